@@ -1,5 +1,7 @@
 use serde::Deserialize;
 use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
@@ -9,21 +11,119 @@ pub struct Config {
     pub port: Option<u16>,
     #[serde(rename = "redir-port")]
     pub redir_port: Option<u16>,
+    #[serde(rename = "mixed-port")]
+    pub mixed_port: Option<u16>,
     #[serde(rename = "tproxy-port")]
     pub tproxy_port: Option<u16>,
 
     #[serde(rename = "allow-lan")]
     pub allow_lan: Option<bool>,
+    #[serde(rename = "bind-address")]
+    pub bind_address: Option<String>,
+    #[serde(rename = "lan-allowed-ips", default)]
+    pub lan_allowed_ips: Vec<String>,
+    #[serde(rename = "lan-disallowed-ips", default)]
+    pub lan_disallowed_ips: Vec<String>,
     pub mode: Option<String>,
-    #[serde(rename = "log-level")]
-    pub log_level: Option<String>,
     #[serde(rename = "external-controller")]
     pub external_controller: Option<String>,
+    #[serde(default)]
+    pub authentication: Vec<String>,
 
     pub proxies: Vec<Proxy>,
     #[serde(rename = "proxy-groups")]
     pub proxy_groups: Vec<ProxyGroup>,
     pub rules: Vec<String>,
+    #[serde(rename = "rule-providers", default)]
+    pub rule_providers: HashMap<String, RuleProviderConfig>,
+    // 订阅的代理节点，代理组通过 use 引用
+    #[serde(rename = "proxy-providers", default)]
+    pub proxy_providers: HashMap<String, ProxyProviderConfig>,
+    // GEOIP 规则使用的 MaxMind 国家数据库
+    #[serde(rename = "geoip-database")]
+    pub geoip_database: Option<String>,
+    // GEOSITE 规则使用的 geosite.dat，或按分类存放的文本域名列表目录
+    #[serde(rename = "geosite-database")]
+    pub geosite_database: Option<String>,
+
+    pub dns: Option<DnsConfig>,
+    // 域名 -> 一个或多个 IP，优先于任何 nameserver
+    #[serde(default)]
+    pub hosts: serde_yaml::Mapping,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct DnsConfig {
+    #[serde(default)]
+    pub enable: bool,
+    #[serde(default)]
+    pub ipv6: bool,
+    #[serde(default)]
+    pub nameserver: Vec<String>,
+    #[serde(default)]
+    pub fallback: Vec<String>,
+    // 只用来解析写成域名的加密上游，必须是 IP 形式的 udp/tcp 服务器
+    #[serde(rename = "default-nameserver", default)]
+    pub default_nameserver: Vec<String>,
+    #[serde(rename = "fallback-filter", default)]
+    pub fallback_filter: FallbackFilter,
+    // 域名 -> 一个或多个 nameserver，保持配置中的顺序
+    #[serde(rename = "nameserver-policy", default)]
+    pub nameserver_policy: serde_yaml::Mapping,
+
+    // 本地 DNS 服务监听地址，如 0.0.0.0:1053
+    pub listen: Option<String>,
+    // normal / fake-ip / redir-host
+    #[serde(rename = "enhanced-mode")]
+    pub enhanced_mode: Option<String>,
+    #[serde(rename = "fake-ip-range")]
+    pub fake_ip_range: Option<String>,
+    // 这些域名返回真实地址，写法同 nameserver-policy
+    #[serde(rename = "fake-ip-filter", default)]
+    pub fake_ip_filter: Vec<String>,
+    // 把 fake-ip 映射保存到文件，重启后继续使用
+    #[serde(rename = "store-fake-ip", default)]
+    pub store_fake_ip: bool,
+    // 映射文件位置，默认在配置文件旁边；相对路径按配置文件所在目录解析
+    #[serde(rename = "fake-ip-store")]
+    pub fake_ip_store: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct FallbackFilter {
+    // 主 nameserver 返回这些网段内的地址时视为被污染，改用 fallback 的结果
+    #[serde(default)]
+    pub ipcidr: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RuleProviderConfig {
+    // file / http
+    #[serde(rename = "type")]
+    pub provider_type: String,
+    // domain / ipcidr / classical
+    pub behavior: String,
+    // yaml（默认）/ text
+    pub format: Option<String>,
+    // http 类型时是下载内容的缓存文件
+    pub path: String,
+    pub url: Option<String>,
+    // 下载时经过的代理或代理组，默认 DIRECT
+    pub proxy: Option<String>,
+    // 秒，定时重新读取或下载；file 类型文件有改动时也会重新读取
+    pub interval: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ProxyProviderConfig {
+    // file / http，内容是带 proxies 列表的 yaml
+    #[serde(rename = "type")]
+    pub provider_type: String,
+    pub path: String,
+    pub url: Option<String>,
+    pub proxy: Option<String>,
+    // 秒
+    pub interval: Option<u64>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -49,7 +149,9 @@ pub enum Proxy {
         #[serde(default)]
         alter_id: Option<u32>,
 
+        // 只实现了 aes-128-gcm
         cipher: Option<String>,
+        // 未设置时允许 UDP
         udp: Option<bool>,
         network: Option<String>,
         #[serde(rename = "ws-path")]
@@ -66,14 +168,100 @@ pub struct ProxyGroup {
     pub name: String,
     #[serde(rename = "type")]
     pub group_type: String,
+    #[serde(default)]
     pub proxies: Vec<String>,
+    // 引用的 proxy-providers，其中的节点排在 proxies 之后
+    #[serde(rename = "use", default)]
+    pub uses: Vec<String>,
+
+    pub url: Option<String>,
+    // 秒
+    pub interval: Option<u64>,
+    // 毫秒
+    pub timeout: Option<u64>,
+    // 毫秒
+    pub tolerance: Option<u64>,
+    pub strategy: Option<String>,
+}
+
+impl Proxy {
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            Proxy::Trojan { name, .. } | Proxy::VMess { name, .. } => Some(name),
+            Proxy::Unknown => None,
+        }
+    }
 }
 
 use std::fs;
 
+const FAKE_IP_STORE: &str = "fakeip.cache";
+
 impl Config {
     pub fn load(path: &str) -> Self {
         let content = fs::read_to_string(path).expect("Failed to read config file");
-        serde_yaml::from_str(&content).expect("Failed to parse config file")
+        let mut config: Config = serde_yaml::from_str(&content).expect("Failed to parse config file");
+        if let Some(dns) = config.dns.as_mut() {
+            let dir = Path::new(path).parent().unwrap_or(Path::new(""));
+            let store = dns.fake_ip_store.take().unwrap_or_else(|| PathBuf::from(FAKE_IP_STORE));
+            dns.fake_ip_store = Some(dir.join(store));
+        }
+        config
+    }
+
+    // allow-lan 关闭时只监听回环；开启时监听 bind-address，"*" 或未设置表示全部地址
+    pub fn listen_ip(&self) -> Result<IpAddr, String> {
+        if !self.allow_lan.unwrap_or(false) {
+            return Ok(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        }
+        self.bind_ip()
+    }
+
+    // redir / tproxy 收的是防火墙转过来的流量，PREROUTING 的 REDIRECT 会把目标改成入口网卡地址，
+    // 只听回环就收不到，所以不受 allow-lan 限制
+    pub fn transparent_listen_ip(&self) -> Result<IpAddr, String> {
+        self.bind_ip()
+    }
+
+    fn bind_ip(&self) -> Result<IpAddr, String> {
+        match self.bind_address.as_deref().map(str::trim) {
+            None | Some("*") => Ok(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            Some(addr) => addr
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .parse()
+                .map_err(|_| format!("invalid bind-address: {}", addr)),
+        }
+    }
+
+    // ":9090" 这样不带地址的写法和代理端口一样监听在 listen_ip 上
+    pub fn controller_addr(&self) -> Result<SocketAddr, String> {
+        let addr = self.external_controller.as_deref().unwrap_or("127.0.0.1:8080");
+        if let Some(port) = addr.strip_prefix(':') {
+            let port = port.parse().map_err(|_| format!("invalid external-controller: {}", addr))?;
+            return Ok(SocketAddr::new(self.listen_ip()?, port));
+        }
+        addr.parse().map_err(|_| format!("invalid external-controller: {}", addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(extra: &str) -> Config {
+        serde_yaml::from_str(&format!("{}proxies: []\nproxy-groups: []\nrules: []\n", extra)).unwrap()
+    }
+
+    #[test]
+    fn transparent_listeners_ignore_allow_lan() {
+        let local = config("allow-lan: false\n");
+        assert_eq!(local.listen_ip().unwrap(), IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert_eq!(local.transparent_listen_ip().unwrap(), IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+
+        let bound = config("allow-lan: true\nbind-address: 192.168.1.1\n");
+        assert_eq!(bound.listen_ip().unwrap(), "192.168.1.1".parse::<IpAddr>().unwrap());
+        assert_eq!(bound.transparent_listen_ip().unwrap(), "192.168.1.1".parse::<IpAddr>().unwrap());
+        assert!(config("bind-address: nope\n").transparent_listen_ip().is_err());
     }
 }
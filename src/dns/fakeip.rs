@@ -0,0 +1,263 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use lru::LruCache;
+
+use crate::rule::cidr::Cidr;
+use crate::rule::trie::DomainTrie;
+
+pub const DEFAULT_RANGE: &str = "198.18.0.1/16";
+const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// fake-ip 地址池：域名和地址一一对应，池满时回收最久未使用的映射。
+pub struct FakeIpPool {
+    // 第一个可分配地址及可分配数量
+    first: u32,
+    size: u32,
+    filter: DomainTrie,
+    store: Option<PathBuf>,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    // 偏移 -> 域名，按使用顺序排列
+    by_ip: LruCache<u32, String>,
+    by_domain: HashMap<String, u32>,
+    // 尚未用过的下一个偏移
+    next: u32,
+    dirty: bool,
+}
+
+impl FakeIpPool {
+    // store 为映射文件路径，None 表示不保存
+    pub fn new(range: &str, filter: &[String], store: Option<PathBuf>) -> Result<Self, String> {
+        let cidr: Cidr = range.parse()?;
+        let IpAddr::V4(addr) = cidr.addr() else {
+            return Err(format!("fake-ip-range must be IPv4: {}", range));
+        };
+        if cidr.prefix() > 29 {
+            return Err(format!("fake-ip-range is too small: {}", range));
+        }
+        // 网络地址和第一个地址（网关）保留，广播地址不分配
+        let mask = u32::MAX << (32 - cidr.prefix());
+        let network = u32::from(addr) & mask;
+        let first = network + 2;
+        let size = (!mask) - 2;
+
+        // 写法和 hosts 相同："+." 含自身，"*." 只匹配子域名
+        let mut trie = DomainTrie::new();
+        for pattern in filter {
+            trie.insert_pattern(pattern, 0);
+        }
+
+        let pool = Self {
+            first,
+            size,
+            filter: trie,
+            store,
+            inner: Mutex::new(Inner {
+                by_ip: LruCache::new(NonZeroUsize::new(size as usize).unwrap()),
+                by_domain: HashMap::new(),
+                next: 0,
+                dirty: false,
+            }),
+        };
+        pool.load();
+        Ok(pool)
+    }
+
+    // fake-ip-filter 命中的域名照常解析
+    pub fn skips(&self, domain: &str) -> bool {
+        self.filter.lookup(domain).is_some()
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.offset_of(ip).is_some()
+    }
+
+    pub fn lookup_ip(&self, domain: &str) -> IpAddr {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(&offset) = inner.by_domain.get(&domain) {
+            inner.by_ip.promote(&offset);
+            return self.ip_at(offset);
+        }
+
+        let offset = if inner.next < self.size {
+            inner.next += 1;
+            inner.next - 1
+        } else {
+            let (offset, old) = inner.by_ip.pop_lru().expect("fake-ip pool is empty");
+            inner.by_domain.remove(&old);
+            offset
+        };
+        inner.by_ip.put(offset, domain.clone());
+        inner.by_domain.insert(domain, offset);
+        inner.dirty = true;
+        self.ip_at(offset)
+    }
+
+    pub fn lookup_domain(&self, ip: &IpAddr) -> Option<String> {
+        let offset = self.offset_of(ip)?;
+        self.inner.lock().unwrap().by_ip.get(&offset).cloned()
+    }
+
+    fn ip_at(&self, offset: u32) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::from(self.first + offset))
+    }
+
+    fn offset_of(&self, ip: &IpAddr) -> Option<u32> {
+        let IpAddr::V4(ip) = ip.to_canonical() else {
+            return None;
+        };
+        let offset = u32::from(ip).checked_sub(self.first)?;
+        (offset < self.size).then_some(offset)
+    }
+
+    // 定期把有变化的映射写回文件
+    pub fn start_persist(self: &Arc<Self>) {
+        if self.store.is_none() {
+            return;
+        }
+        let pool = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(SAVE_INTERVAL);
+            loop {
+                interval.tick().await;
+                pool.save();
+            }
+        });
+    }
+
+    pub fn save(&self) {
+        let Some(path) = &self.store else { return };
+        let content = {
+            let mut inner = self.inner.lock().unwrap();
+            if !inner.dirty {
+                return;
+            }
+            inner.dirty = false;
+            // 从最久未用的开始写，载入时按顺序 put 即可还原 LRU 顺序
+            let mut content = String::new();
+            for (offset, domain) in inner.by_ip.iter().rev() {
+                content.push_str(&format!("{} {}\n", self.ip_at(*offset), domain));
+            }
+            content
+        };
+
+        let tmp = path.with_extension("tmp");
+        if let Err(e) = fs::write(&tmp, content).and_then(|_| fs::rename(&tmp, path)) {
+            eprintln!("[DNS] Failed to save fake-ip mapping to {}: {}", path.display(), e);
+        }
+    }
+
+    fn load(&self) {
+        let Some(path) = &self.store else { return };
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return,
+            Err(e) => {
+                eprintln!("[DNS] Failed to load fake-ip mapping from {}: {}", path.display(), e);
+                return;
+            }
+        };
+
+        let mut inner = self.inner.lock().unwrap();
+        for line in content.lines() {
+            let Some((ip, domain)) = line.split_once(' ') else { continue };
+            // 地址池改过之后，不在新范围内的旧映射直接丢弃
+            let Some(offset) = ip.parse().ok().and_then(|ip| self.offset_of(&ip)) else {
+                continue;
+            };
+            if self.skips(domain) || inner.by_domain.contains_key(domain) {
+                continue;
+            }
+            if let Some((_, old)) = inner.by_ip.push(offset, domain.to_string()) {
+                inner.by_domain.remove(&old);
+            }
+            inner.by_domain.insert(domain.to_string(), offset);
+            inner.next = inner.next.max(offset + 1);
+        }
+        println!("[DNS] Loaded {} fake-ip mappings from {}", inner.by_ip.len(), path.display());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_uses_hosts_pattern_syntax() {
+        let filter = ["*.lan.test".to_string(), "+.home.test".to_string(), "nas.test".to_string()];
+        let pool = FakeIpPool::new(DEFAULT_RANGE, &filter, None).unwrap();
+        assert!(pool.skips("a.lan.test"));
+        assert!(!pool.skips("lan.test"));
+        assert!(pool.skips("home.test"));
+        assert!(pool.skips("a.home.test"));
+        assert!(pool.skips("nas.test"));
+        assert!(!pool.skips("a.nas.test"));
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn allocates_sequentially_and_reuses_mappings() {
+        let pool = FakeIpPool::new("198.18.0.1/16", &[], None).unwrap();
+        assert_eq!(pool.lookup_ip("a.test"), ip("198.18.0.2"));
+        assert_eq!(pool.lookup_ip("b.test"), ip("198.18.0.3"));
+        assert_eq!(pool.lookup_ip("A.test."), ip("198.18.0.2"));
+        assert_eq!(pool.lookup_domain(&ip("198.18.0.3")).as_deref(), Some("b.test"));
+        assert_eq!(pool.lookup_domain(&ip("198.18.0.4")), None);
+        assert!(pool.contains(&ip("198.18.255.254")));
+        assert!(!pool.contains(&ip("198.18.255.255")));
+        assert!(!pool.contains(&ip("198.18.0.1")));
+    }
+
+    #[test]
+    fn full_pool_recycles_least_recently_used() {
+        // /29 可分配 .2 ~ .6 共 5 个地址
+        let pool = FakeIpPool::new("10.0.0.0/29", &[], None).unwrap();
+        for n in 0..5 {
+            pool.lookup_ip(&format!("d{}.test", n));
+        }
+        // 访问 d0 之后最久未用的是 d1
+        pool.lookup_ip("d0.test");
+        assert_eq!(pool.lookup_ip("new.test"), ip("10.0.0.3"));
+        assert_eq!(pool.lookup_domain(&ip("10.0.0.3")).as_deref(), Some("new.test"));
+        assert_eq!(pool.lookup_ip("d0.test"), ip("10.0.0.2"));
+        // d1 被回收后重新分配，拿走下一个最久未用的 d2 的地址
+        assert_eq!(pool.lookup_ip("d1.test"), ip("10.0.0.4"));
+    }
+
+    #[test]
+    fn persist_and_reload_round_trip() {
+        let dir = std::env::temp_dir().join(format!("clash-rs-fakeip-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("fakeip.cache");
+        let _ = fs::remove_file(&path);
+
+        let pool = FakeIpPool::new("10.0.0.0/29", &[], Some(path.clone())).unwrap();
+        for n in 0..5 {
+            pool.lookup_ip(&format!("d{}.test", n));
+        }
+        pool.lookup_ip("d0.test");
+        pool.save();
+
+        let reloaded = FakeIpPool::new("10.0.0.0/29", &[], Some(path.clone())).unwrap();
+        for n in 0..5 {
+            assert_eq!(reloaded.lookup_domain(&pool.lookup_ip(&format!("d{}.test", n))), Some(format!("d{}.test", n)));
+        }
+        // LRU 顺序也要还原：载入后最久未用的仍是 d1
+        let reloaded = FakeIpPool::new("10.0.0.0/29", &[], Some(path.clone())).unwrap();
+        assert_eq!(reloaded.lookup_ip("new.test"), ip("10.0.0.3"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,56 @@
+use std::net::IpAddr;
+
+use crate::rule::trie::DomainTrie;
+
+/// 配置中的 hosts：精确域名优先，其次是更具体的通配；"+." 匹配自身及子域名，"*." 只匹配子域名。
+#[derive(Default)]
+pub struct Hosts {
+    trie: DomainTrie,
+    ips: Vec<Vec<IpAddr>>,
+}
+
+impl Hosts {
+    pub fn from_config(hosts: &serde_yaml::Mapping) -> Result<Self, String> {
+        let mut exact = Vec::new();
+        let mut wildcard = Vec::new();
+        for (domain, value) in hosts {
+            let domain = domain
+                .as_str()
+                .ok_or_else(|| format!("hosts key must be a string: {:?}", domain))?;
+            let ips = super::string_list(value)
+                .ok_or_else(|| format!("invalid hosts entry for {}", domain))?
+                .iter()
+                .map(|ip| ip.parse::<IpAddr>().map_err(|_| format!("invalid IP for host {}: {}", domain, ip)))
+                .collect::<Result<Vec<_>, _>>()?;
+            if ips.is_empty() {
+                return Err(format!("hosts entry for {} is empty", domain));
+            }
+
+            match domain.strip_prefix("+.").or_else(|| domain.strip_prefix("*.")) {
+                Some(parent) => wildcard.push((domain, parent.split('.').count(), ips)),
+                None => exact.push((domain, ips)),
+            }
+        }
+
+        // 查找时取最小序号，所以按 精确 -> 标签多的通配 -> 标签少的通配 的顺序编号
+        wildcard.sort_by_key(|(_, labels, _)| std::cmp::Reverse(*labels));
+        let mut result = Self::default();
+        for (domain, ips) in exact {
+            result.trie.insert_exact(domain, result.ips.len());
+            result.ips.push(ips);
+        }
+        for (pattern, _, ips) in wildcard {
+            result.trie.insert_pattern(pattern, result.ips.len());
+            result.ips.push(ips);
+        }
+        Ok(result)
+    }
+
+    pub fn lookup(&self, domain: &str) -> Option<&[IpAddr]> {
+        self.trie.lookup(domain).map(|index| self.ips[index].as_slice())
+    }
+
+    pub fn len(&self) -> usize {
+        self.ips.len()
+    }
+}
@@ -0,0 +1,208 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_SOA: u16 = 6;
+pub const TYPE_AAAA: u16 = 28;
+pub const CLASS_IN: u16 = 1;
+
+pub const RCODE_SERVFAIL: u8 = 2;
+pub const RCODE_NXDOMAIN: u8 = 3;
+
+// 一次应答里需要的部分：A/AAAA 记录、最小 TTL 和 RCODE
+#[derive(Debug, Default)]
+pub struct Answer {
+    pub ips: Vec<IpAddr>,
+    pub ttl: u32,
+    pub rcode: u8,
+    // 授权段带 SOA 时否定应答可缓存的时间：SOA 记录 TTL 与 MINIMUM 取小（RFC 2308）
+    pub negative_ttl: Option<u32>,
+}
+
+// 标准递归查询：一个问题，RD=1
+pub fn build_query(id: u16, name: &str, qtype: u16) -> Vec<u8> {
+    let mut out = Vec::with_capacity(name.len() + 18);
+    out.extend_from_slice(&id.to_be_bytes());
+    out.extend_from_slice(&[0x01, 0x00]);
+    out.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    encode_name(name, &mut out);
+    out.extend_from_slice(&qtype.to_be_bytes());
+    out.extend_from_slice(&CLASS_IN.to_be_bytes());
+    out
+}
+
+pub fn parse_response(buf: &[u8], id: u16) -> io::Result<Answer> {
+    if buf.len() < 12 {
+        return Err(invalid("DNS response too short"));
+    }
+    if u16::from_be_bytes([buf[0], buf[1]]) != id {
+        return Err(invalid("DNS response id mismatch"));
+    }
+    if buf[2] & 0x80 == 0 {
+        return Err(invalid("DNS message is not a response"));
+    }
+
+    let mut answer = Answer {
+        rcode: buf[3] & 0x0F,
+        ttl: u32::MAX,
+        ..Default::default()
+    };
+    let qdcount = u16::from_be_bytes([buf[4], buf[5]]);
+    let ancount = u16::from_be_bytes([buf[6], buf[7]]);
+    let nscount = u16::from_be_bytes([buf[8], buf[9]]);
+
+    let mut pos = 12;
+    for _ in 0..qdcount {
+        pos = skip_name(buf, pos)? + 4;
+    }
+
+    for _ in 0..ancount {
+        let (rtype, ttl, rdata, next) = read_record(buf, pos)?;
+        pos = next;
+
+        // CNAME 链由递归服务器展开，这里只收集最终的地址记录
+        let ip = match (rtype, rdata.len()) {
+            (TYPE_A, 4) => IpAddr::V4(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3])),
+            (TYPE_AAAA, 16) => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(rdata).unwrap())),
+            _ => continue,
+        };
+        answer.ips.push(ip);
+        answer.ttl = answer.ttl.min(ttl);
+    }
+
+    // 授权段只用来取 SOA，解析不了就当没有
+    for _ in 0..nscount {
+        let Ok((rtype, ttl, rdata, next)) = read_record(buf, pos) else {
+            break;
+        };
+        pos = next;
+        // MNAME、RNAME 之后是 SERIAL REFRESH RETRY EXPIRE MINIMUM，MINIMUM 在最后 4 字节
+        if rtype == TYPE_SOA && rdata.len() >= 22 {
+            let minimum = u32::from_be_bytes(rdata[rdata.len() - 4..].try_into().unwrap());
+            answer.negative_ttl = Some(ttl.min(minimum));
+            break;
+        }
+    }
+
+    if answer.ips.is_empty() {
+        answer.ttl = 0;
+    }
+    Ok(answer)
+}
+
+// 收到的查询里的第一个问题
+#[derive(Debug)]
+pub struct Question {
+    pub id: u16,
+    pub name: String,
+    pub qtype: u16,
+    // 问题段结束的位置，应答时原样拷贝 [12, end)
+    end: usize,
+}
+
+pub fn parse_query(buf: &[u8]) -> io::Result<Question> {
+    if buf.len() < 12 {
+        return Err(invalid("DNS query too short"));
+    }
+    if buf[2] & 0x80 != 0 || u16::from_be_bytes([buf[4], buf[5]]) == 0 {
+        return Err(invalid("DNS message is not a query"));
+    }
+
+    let mut labels = Vec::new();
+    let mut pos = 12;
+    loop {
+        let len = *buf.get(pos).ok_or_else(|| invalid("truncated DNS name"))? as usize;
+        pos += 1;
+        if len == 0 {
+            break;
+        }
+        if len & 0xC0 != 0 {
+            return Err(invalid("compressed name in DNS question"));
+        }
+        let label = buf.get(pos..pos + len).ok_or_else(|| invalid("truncated DNS name"))?;
+        labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+        pos += len;
+    }
+    let tail = buf.get(pos..pos + 4).ok_or_else(|| invalid("truncated DNS question"))?;
+
+    Ok(Question {
+        id: u16::from_be_bytes([buf[0], buf[1]]),
+        name: labels.join("."),
+        qtype: u16::from_be_bytes([tail[0], tail[1]]),
+        end: pos + 4,
+    })
+}
+
+// 针对 query 的应答：拷贝问题段，ips 中与问题类型一致的地址作为回答
+pub fn build_response(query: &[u8], question: &Question, ips: &[IpAddr], ttl: u32, rcode: u8) -> Vec<u8> {
+    let records: Vec<Vec<u8>> = ips
+        .iter()
+        .filter_map(|ip| match (ip, question.qtype) {
+            (IpAddr::V4(v4), TYPE_A) => Some(v4.octets().to_vec()),
+            (IpAddr::V6(v6), TYPE_AAAA) => Some(v6.octets().to_vec()),
+            _ => None,
+        })
+        .collect();
+
+    let mut out = Vec::with_capacity(question.end + records.len() * 28);
+    out.extend_from_slice(&question.id.to_be_bytes());
+    // QR=1，保留 OPCODE 和 RD；RA=1
+    out.push(0x80 | (query[2] & 0x79));
+    out.push(0x80 | (rcode & 0x0F));
+    out.extend_from_slice(&[0, 1]);
+    out.extend_from_slice(&(records.len() as u16).to_be_bytes());
+    out.extend_from_slice(&[0, 0, 0, 0]);
+    out.extend_from_slice(&query[12..question.end]);
+    for rdata in records {
+        // 名字用指向问题段的压缩指针
+        out.extend_from_slice(&[0xC0, 0x0C]);
+        out.extend_from_slice(&question.qtype.to_be_bytes());
+        out.extend_from_slice(&CLASS_IN.to_be_bytes());
+        out.extend_from_slice(&ttl.to_be_bytes());
+        out.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        out.extend_from_slice(&rdata);
+    }
+    out
+}
+
+pub fn is_truncated(buf: &[u8]) -> bool {
+    buf.len() > 2 && buf[2] & 0x02 != 0
+}
+
+pub fn encode_name(name: &str, out: &mut Vec<u8>) {
+    for label in name.trim_end_matches('.').split('.').filter(|l| !l.is_empty()) {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        out.push(label.len() as u8);
+        out.extend_from_slice(label);
+    }
+    out.push(0);
+}
+
+// 跳过一个（可能带压缩指针的）域名，返回其后的位置
+// 返回 (类型, TTL, RDATA, 下一条记录的位置)
+fn read_record(buf: &[u8], pos: usize) -> io::Result<(u16, u32, &[u8], usize)> {
+    let pos = skip_name(buf, pos)?;
+    let header = buf.get(pos..pos + 10).ok_or_else(|| invalid("truncated DNS record"))?;
+    let rtype = u16::from_be_bytes([header[0], header[1]]);
+    let ttl = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+    let rdlen = u16::from_be_bytes([header[8], header[9]]) as usize;
+    let rdata = buf
+        .get(pos + 10..pos + 10 + rdlen)
+        .ok_or_else(|| invalid("truncated DNS record"))?;
+    Ok((rtype, ttl, rdata, pos + 10 + rdlen))
+}
+
+fn skip_name(buf: &[u8], mut pos: usize) -> io::Result<usize> {
+    loop {
+        let len = *buf.get(pos).ok_or_else(|| invalid("truncated DNS name"))?;
+        match len {
+            0 => return Ok(pos + 1),
+            l if l & 0xC0 == 0xC0 => return Ok(pos + 2),
+            l => pos += 1 + l as usize,
+        }
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
@@ -0,0 +1,514 @@
+pub mod fakeip;
+pub mod hosts;
+pub mod message;
+pub mod server;
+pub mod upstream;
+
+use std::io;
+use std::net::IpAddr;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::future::select_ok;
+use lru::LruCache;
+use tokio::time::Instant;
+
+use crate::config::DnsConfig;
+use crate::proxy::proxy_manager::ProxyManager;
+use crate::rule::cidr::{Cidr, CidrTable};
+use crate::rule::trie::DomainTrie;
+use fakeip::FakeIpPool;
+use hosts::Hosts;
+use message::{Answer, RCODE_NXDOMAIN, TYPE_A, TYPE_AAAA};
+use upstream::{Nameserver, ProxyDialer, Transport};
+
+// 上游给出 0 或极短 TTL 时也至少缓存这么久，避免同一时刻的重复查询
+const MIN_TTL: Duration = Duration::from_secs(1);
+// NXDOMAIN 按 SOA 缓存的上限，防止 SOA MINIMUM 写得过大时长时间解析不到新加的域名
+const MAX_NEGATIVE_TTL: Duration = Duration::from_secs(3600);
+// 解析缓存最多保留的 (域名, 类型) 条目数，超出时淘汰最久未用的
+const CACHE_SIZE: usize = 4096;
+// redir-host 反查表的大小；应用常常在 TTL 过期后还在用旧地址，所以不按 TTL 淘汰
+const REVERSE_CACHE_SIZE: usize = 4096;
+
+/// DIRECT 拨号和 IP 规则匹配共用的解析器；未启用 dns 时退回系统解析。
+pub struct Resolver {
+    enabled: bool,
+    hosts: Hosts,
+    ipv6: bool,
+    nameservers: Vec<Nameserver>,
+    fallback: Vec<Nameserver>,
+    fallback_filter: CidrTable,
+    policy: DomainTrie,
+    policy_servers: Vec<Vec<Nameserver>>,
+    cache: Mutex<LruCache<(String, u16), CacheEntry>>,
+    dialer: ProxyDialer,
+    fake_ip: Option<Arc<FakeIpPool>>,
+    // redir-host：解析结果 IP -> 域名
+    reverse: Option<Mutex<LruCache<IpAddr, String>>>,
+}
+
+// (地址, 过期时间)，地址为 None 表示缓存的 NXDOMAIN
+type CacheEntry = (Option<Vec<IpAddr>>, Instant);
+
+impl Resolver {
+    pub fn new(config: Option<&DnsConfig>, hosts: &serde_yaml::Mapping) -> Result<Self, String> {
+        let default = DnsConfig::default();
+        let config = config.unwrap_or(&default);
+
+        let dialer = ProxyDialer::default();
+        let no_bootstrap = Arc::new(Vec::new());
+        let mut bootstrap = Vec::new();
+        for s in &config.default_nameserver {
+            let server = Nameserver::parse(s, &dialer, &no_bootstrap)?;
+            if !matches!(server.transport(), Transport::Udp | Transport::Tcp) {
+                return Err(format!("default-nameserver must be a plain udp/tcp server: {}", s));
+            }
+            bootstrap.push(server);
+        }
+        let bootstrap = Arc::new(bootstrap);
+        let parse_all = |list: &[String]| -> Result<Vec<Nameserver>, String> {
+            list.iter().map(|s| Nameserver::parse(s, &dialer, &bootstrap)).collect()
+        };
+
+        let mut fallback_filter = CidrTable::new();
+        for (index, cidr) in config.fallback_filter.ipcidr.iter().enumerate() {
+            fallback_filter.insert(&cidr.parse::<Cidr>()?, index);
+        }
+
+        // 写法和 hosts 相同："+.example.com" 匹配自身及子域名，"*.example.com" 只匹配子域名
+        let mut policy = DomainTrie::new();
+        let mut policy_servers = Vec::new();
+        for (pattern, servers) in &config.nameserver_policy {
+            let pattern = pattern
+                .as_str()
+                .ok_or_else(|| format!("nameserver-policy key must be a string: {:?}", pattern))?;
+            let servers = string_list(servers).ok_or_else(|| format!("invalid nameserver-policy for {}", pattern))?;
+
+            policy.insert_pattern(pattern, policy_servers.len());
+            policy_servers.push(parse_all(&servers)?);
+        }
+
+        let nameservers = parse_all(&config.nameserver)?;
+        let fallback = parse_all(&config.fallback)?;
+        if config.enable && nameservers.is_empty() {
+            return Err("dns.nameserver must not be empty when dns is enabled".to_string());
+        }
+
+        let hosts = Hosts::from_config(hosts).map_err(|e| format!("invalid hosts: {}", e))?;
+        if hosts.len() > 0 {
+            println!("[DNS] Loaded {} hosts entries", hosts.len());
+        }
+
+        let mut reverse = None;
+        let fake_ip = match config.enhanced_mode.as_deref().unwrap_or("normal") {
+            "normal" => None,
+            "redir-host" if config.enable => {
+                println!("[DNS] redir-host mode");
+                reverse = Some(Mutex::new(LruCache::new(NonZeroUsize::new(REVERSE_CACHE_SIZE).unwrap())));
+                None
+            }
+            "fake-ip" if config.enable => {
+                let range = config.fake_ip_range.as_deref().unwrap_or(fakeip::DEFAULT_RANGE);
+                let store = config.store_fake_ip.then(|| config.fake_ip_store.clone().unwrap_or_default());
+                let pool = FakeIpPool::new(range, &config.fake_ip_filter, store)?;
+                println!("[DNS] fake-ip range {}, {} filter patterns", range, config.fake_ip_filter.len());
+                Some(Arc::new(pool))
+            }
+            mode @ ("fake-ip" | "redir-host") => return Err(format!("enhanced-mode {} requires dns.enable", mode)),
+            mode => return Err(format!("unsupported enhanced-mode: {}", mode)),
+        };
+
+        if config.enable {
+            println!(
+                "[DNS] nameservers: {}, fallback: {}, {} policies",
+                join(&nameservers),
+                join(&fallback),
+                policy_servers.len()
+            );
+        }
+
+        Ok(Self {
+            enabled: config.enable,
+            hosts,
+            ipv6: config.ipv6,
+            nameservers,
+            fallback,
+            fallback_filter,
+            policy,
+            policy_servers,
+            cache: Mutex::new(LruCache::new(NonZeroUsize::new(CACHE_SIZE).unwrap())),
+            dialer,
+            fake_ip,
+            reverse,
+        })
+    }
+
+    // 上游写了 #代理名 时经由 ProxyManager 拨号
+    pub fn set_proxies(&self, manager: &Arc<ProxyManager>) {
+        let _ = self.dialer.set(Arc::downgrade(manager));
+    }
+
+    pub fn fake_ip(&self) -> Option<&Arc<FakeIpPool>> {
+        self.fake_ip.as_ref()
+    }
+
+    // fake-ip 地址对应的域名；不在地址池内时返回 None
+    pub fn fake_domain(&self, ip: &IpAddr) -> Option<String> {
+        self.fake_ip.as_ref()?.lookup_domain(ip)
+    }
+
+    pub fn hosts(&self) -> &Hosts {
+        &self.hosts
+    }
+
+    // redir-host 模式下某个地址最近一次由哪个域名解析得到
+    pub fn sniff_domain(&self, ip: &IpAddr) -> Option<String> {
+        self.reverse.as_ref()?.lock().unwrap().get(&ip.to_canonical()).cloned()
+    }
+
+    fn remember(&self, domain: &str, ips: &[IpAddr]) {
+        if let Some(reverse) = &self.reverse {
+            let mut reverse = reverse.lock().unwrap();
+            for ip in ips {
+                reverse.put(*ip, domain.to_string());
+            }
+        }
+    }
+
+    // 返回全部地址，IPv4 在前
+    pub async fn resolve(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            // 直接拿 fake-ip 去连接时换回真实地址
+            if let Some(pool) = &self.fake_ip
+                && pool.contains(&ip)
+            {
+                let domain = pool.lookup_domain(&ip).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotFound, format!("fake-ip {} has no mapping", ip))
+                })?;
+                return Box::pin(self.resolve(&domain)).await;
+            }
+            return Ok(vec![ip]);
+        }
+        if let Some(ips) = self.hosts.lookup(host) {
+            return Ok(ips.to_vec());
+        }
+
+        let ips = if self.enabled {
+            if self.ipv6 {
+                let (v4, v6) = tokio::join!(self.query(host, TYPE_A), self.query(host, TYPE_AAAA));
+                match (v4, v6) {
+                    (Err(e), Err(_)) => return Err(e),
+                    (v4, v6) => v4.unwrap_or_default().into_iter().chain(v6.unwrap_or_default()).collect(),
+                }
+            } else {
+                self.query(host, TYPE_A).await?
+            }
+        } else {
+            tokio::net::lookup_host((host, 0)).await?.map(|addr| addr.ip()).collect()
+        };
+
+        if ips.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("no address for {}", host)));
+        }
+        Ok(ips)
+    }
+
+    pub async fn resolve_ip(&self, host: &str) -> io::Result<IpAddr> {
+        Ok(self.resolve(host).await?[0])
+    }
+
+    async fn query(&self, domain: &str, qtype: u16) -> io::Result<Vec<IpAddr>> {
+        self.lookup(domain, qtype).await.map(|(ips, _)| ips)
+    }
+
+    // 带剩余 TTL（秒）的查询，DNS 服务应答时使用
+    pub async fn lookup(&self, domain: &str, qtype: u16) -> io::Result<(Vec<IpAddr>, u32)> {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        let key = (domain.clone(), qtype);
+        let cached = {
+            let mut cache = self.cache.lock().unwrap();
+            match cache.get(&key) {
+                Some((ips, expires)) => match expires.checked_duration_since(Instant::now()) {
+                    Some(left) => Some((ips.clone(), left.as_secs() as u32)),
+                    // 过期的条目直接删掉
+                    None => {
+                        cache.pop(&key);
+                        None
+                    }
+                },
+                None => None,
+            }
+        };
+        match cached {
+            Some((Some(ips), left)) => {
+                self.remember(&domain, &ips);
+                return Ok((ips, left));
+            }
+            Some((None, _)) => return Err(not_exist(&domain)),
+            None => {}
+        }
+
+        let answer = match self.policy.lookup(&domain) {
+            Some(index) => exchange(&self.policy_servers[index], &domain, qtype).await?,
+            None => self.query_default(&domain, qtype).await?,
+        };
+        if answer.rcode == RCODE_NXDOMAIN {
+            // 没有 SOA 的 NXDOMAIN 不缓存
+            if let Some(ttl) = answer.negative_ttl {
+                let ttl = Duration::from_secs(ttl as u64).clamp(MIN_TTL, MAX_NEGATIVE_TTL);
+                self.cache.lock().unwrap().put(key, (None, Instant::now() + ttl));
+            }
+            return Err(not_exist(&domain));
+        }
+
+        println!("[DNS] {} -> {:?} (ttl {}s)", domain, answer.ips, answer.ttl);
+        self.remember(&domain, &answer.ips);
+        let ttl = Duration::from_secs(answer.ttl as u64).max(MIN_TTL);
+        self.cache
+            .lock()
+            .unwrap()
+            .put(key, (Some(answer.ips.clone()), Instant::now() + ttl));
+        Ok((answer.ips, answer.ttl))
+    }
+
+    // A / AAAA 以外的查询原样转发给上游，按域名选 nameserver-policy
+    pub async fn forward(&self, domain: &str, query: &[u8]) -> io::Result<Vec<u8>> {
+        let servers = match self.policy.lookup(domain) {
+            Some(index) => &self.policy_servers[index],
+            None => &self.nameservers,
+        };
+        if servers.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "no nameserver configured"));
+        }
+        let queries = servers.iter().map(|server| Box::pin(server.exchange(query)));
+        select_ok(queries).await.map(|(response, _)| response)
+    }
+
+    // 主 nameserver 失败，或结果落在 fallback-filter 网段内时，改用 fallback
+    async fn query_default(&self, domain: &str, qtype: u16) -> io::Result<Answer> {
+        let primary = exchange(&self.nameservers, domain, qtype).await;
+        if self.fallback.is_empty() {
+            return primary;
+        }
+
+        match primary {
+            Ok(answer) if !answer.ips.iter().any(|ip| self.fallback_filter.lookup(ip).is_some()) => Ok(answer),
+            Ok(_) => {
+                println!("[DNS] {} answer filtered, using fallback", domain);
+                exchange(&self.fallback, domain, qtype).await
+            }
+            Err(e) => {
+                println!("[DNS] {} failed ({}), using fallback", domain, e);
+                exchange(&self.fallback, domain, qtype).await
+            }
+        }
+    }
+}
+
+// 同时向一组 nameserver 发起查询，取最先成功的应答
+async fn exchange(servers: &[Nameserver], domain: &str, qtype: u16) -> io::Result<Answer> {
+    if servers.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "no nameserver configured"));
+    }
+    let queries = servers.iter().map(|server| Box::pin(server.lookup(domain, qtype)));
+    select_ok(queries).await.map(|(answer, _)| answer)
+}
+
+fn not_exist(domain: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{} does not exist", domain))
+}
+
+// 配置里可以写单个字符串或字符串列表
+fn string_list(value: &serde_yaml::Value) -> Option<Vec<String>> {
+    match value {
+        serde_yaml::Value::String(s) => Some(vec![s.clone()]),
+        serde_yaml::Value::Sequence(list) => list.iter().map(|v| v.as_str().map(str::to_string)).collect(),
+        _ => None,
+    }
+}
+
+fn join(servers: &[Nameserver]) -> String {
+    servers.iter().map(|s| s.to_string()).collect::<Vec<_>>().join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tokio::net::UdpSocket;
+
+    use super::*;
+    use message::{CLASS_IN, RCODE_SERVFAIL, TYPE_SOA};
+
+    // 本地 UDP DNS 桩：按域名返回 (rcode, 地址, ttl)，并统计收到的查询数。
+    // NXDOMAIN 且 ttl 非 0 时在授权段带上 SOA
+    pub(super) async fn stub(answer: fn(&str) -> (u8, Vec<IpAddr>, u32)) -> (String, Arc<AtomicUsize>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = format!("udp://{}", socket.local_addr().unwrap());
+        let queries = Arc::new(AtomicUsize::new(0));
+        let counter = queries.clone();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            loop {
+                let (n, peer) = socket.recv_from(&mut buf).await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                let query = &buf[..n];
+                let question = message::parse_query(query).unwrap();
+                let (rcode, ips, ttl) = answer(&question.name);
+                let mut response = message::build_response(query, &question, &ips, ttl, rcode);
+                if rcode == RCODE_NXDOMAIN && ttl > 0 {
+                    append_soa(&mut response, ttl);
+                }
+                socket.send_to(&response, peer).await.unwrap();
+            }
+        });
+        (addr, queries)
+    }
+
+    // 根域的 SOA，记录 TTL 是 ttl，MINIMUM 是 ttl 的两倍，生效的应是较小的那个
+    fn append_soa(response: &mut Vec<u8>, ttl: u32) {
+        response[9] = 1;
+        response.push(0);
+        response.extend_from_slice(&TYPE_SOA.to_be_bytes());
+        response.extend_from_slice(&CLASS_IN.to_be_bytes());
+        response.extend_from_slice(&ttl.to_be_bytes());
+        response.extend_from_slice(&22u16.to_be_bytes());
+        response.extend_from_slice(&[0, 0]);
+        for value in [1, 1800, 900, 604800, ttl * 2] {
+            response.extend_from_slice(&u32::to_be_bytes(value));
+        }
+    }
+
+    fn ip(a: u8, b: u8, c: u8, d: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(a, b, c, d))
+    }
+
+    fn resolver(yaml: &str) -> Resolver {
+        let config: DnsConfig = serde_yaml::from_str(yaml).unwrap();
+        Resolver::new(Some(&config), &serde_yaml::Mapping::new()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn nameserver_policy_selects_server() {
+        let (primary, primary_queries) = stub(|_| (0, vec![ip(1, 1, 1, 1)], 60)).await;
+        let (policy, policy_queries) = stub(|_| (0, vec![ip(2, 2, 2, 2)], 60)).await;
+        let resolver = resolver(&format!(
+            "enable: true\nnameserver: [\"{}\"]\nnameserver-policy:\n  \"+.corp.test\": \"{}\"\n",
+            primary, policy
+        ));
+
+        assert_eq!(resolver.resolve("a.corp.test").await.unwrap(), vec![ip(2, 2, 2, 2)]);
+        assert_eq!(resolver.resolve("corp.test").await.unwrap(), vec![ip(2, 2, 2, 2)]);
+        assert_eq!(resolver.resolve("other.test").await.unwrap(), vec![ip(1, 1, 1, 1)]);
+        assert_eq!(policy_queries.load(Ordering::SeqCst), 2);
+        assert_eq!(primary_queries.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn nameserver_policy_star_skips_parent() {
+        let (primary, _) = stub(|_| (0, vec![ip(1, 1, 1, 1)], 60)).await;
+        let (policy, _) = stub(|_| (0, vec![ip(2, 2, 2, 2)], 60)).await;
+        let resolver = resolver(&format!(
+            "enable: true\nnameserver: [\"{}\"]\nnameserver-policy:\n  \"*.corp.test\": \"{}\"\n",
+            primary, policy
+        ));
+
+        assert_eq!(resolver.resolve("a.corp.test").await.unwrap(), vec![ip(2, 2, 2, 2)]);
+        assert_eq!(resolver.resolve("corp.test").await.unwrap(), vec![ip(1, 1, 1, 1)]);
+    }
+
+    #[tokio::test]
+    async fn fallback_when_primary_fails() {
+        let (primary, _) = stub(|_| (RCODE_SERVFAIL, vec![], 0)).await;
+        let (fallback, fallback_queries) = stub(|_| (0, vec![ip(3, 3, 3, 3)], 60)).await;
+        let resolver = resolver(&format!(
+            "enable: true\nnameserver: [\"{}\"]\nfallback: [\"{}\"]\n",
+            primary, fallback
+        ));
+
+        assert_eq!(resolver.resolve("example.test").await.unwrap(), vec![ip(3, 3, 3, 3)]);
+        assert_eq!(fallback_queries.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn fallback_filter_replaces_polluted_answer() {
+        let (primary, _) = stub(|name| match name {
+            "polluted.test" => (0, vec![ip(10, 0, 0, 1)], 60),
+            _ => (0, vec![ip(1, 1, 1, 1)], 60),
+        })
+        .await;
+        let (fallback, fallback_queries) = stub(|_| (0, vec![ip(3, 3, 3, 3)], 60)).await;
+        let resolver = resolver(&format!(
+            "enable: true\nnameserver: [\"{}\"]\nfallback: [\"{}\"]\nfallback-filter:\n  ipcidr: [10.0.0.0/8]\n",
+            primary, fallback
+        ));
+
+        assert_eq!(resolver.resolve("polluted.test").await.unwrap(), vec![ip(3, 3, 3, 3)]);
+        assert_eq!(resolver.resolve("clean.test").await.unwrap(), vec![ip(1, 1, 1, 1)]);
+        assert_eq!(fallback_queries.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn nxdomain_maps_to_not_found() {
+        let (primary, _) = stub(|_| (RCODE_NXDOMAIN, vec![], 0)).await;
+        let resolver = resolver(&format!("enable: true\nnameserver: [\"{}\"]\n", primary));
+
+        let err = resolver.lookup("missing.test", TYPE_A).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        let err = resolver.resolve("missing.test").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    // 只在推进时钟时暂停，查询本身仍走真实的 UDP 和超时
+    async fn advance(duration: Duration) {
+        tokio::time::pause();
+        tokio::time::advance(duration).await;
+        tokio::time::resume();
+    }
+
+    #[tokio::test]
+    async fn cache_expires_after_ttl() {
+        let (primary, queries) = stub(|_| (0, vec![ip(1, 1, 1, 1)], 60)).await;
+        let resolver = resolver(&format!("enable: true\nnameserver: [\"{}\"]\n", primary));
+
+        resolver.lookup("ttl.test", TYPE_A).await.unwrap();
+        advance(Duration::from_secs(59)).await;
+        resolver.lookup("ttl.test", TYPE_A).await.unwrap();
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+
+        advance(Duration::from_secs(2)).await;
+        resolver.lookup("ttl.test", TYPE_A).await.unwrap();
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn nxdomain_cached_for_soa_ttl() {
+        let (primary, queries) = stub(|_| (RCODE_NXDOMAIN, vec![], 30)).await;
+        let resolver = resolver(&format!("enable: true\nnameserver: [\"{}\"]\n", primary));
+
+        for _ in 0..2 {
+            let err = resolver.lookup("missing.test", TYPE_A).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        }
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+
+        advance(Duration::from_secs(31)).await;
+        let err = resolver.lookup("missing.test", TYPE_A).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn nxdomain_without_soa_is_not_cached() {
+        let (primary, queries) = stub(|_| (RCODE_NXDOMAIN, vec![], 0)).await;
+        let resolver = resolver(&format!("enable: true\nnameserver: [\"{}\"]\n", primary));
+
+        resolver.lookup("missing.test", TYPE_A).await.unwrap_err();
+        resolver.lookup("missing.test", TYPE_A).await.unwrap_err();
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,138 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+use super::Resolver;
+use crate::proxy::lan::LanAccess;
+use super::message::{self, RCODE_NXDOMAIN, RCODE_SERVFAIL, TYPE_A, TYPE_AAAA};
+
+// fake-ip 应答的 TTL，客户端尽快回来查询才能及时续上映射
+const FAKE_IP_TTL: u32 = 1;
+const HOSTS_TTL: u32 = 10;
+
+// dns.listen 上的 DNS 服务，UDP 和 TCP 同端口，来源地址和代理入站一样受 lan 限制
+pub async fn start_dns_server(addr: &str, resolver: Arc<Resolver>, lan: Arc<LanAccess>) -> io::Result<()> {
+    let addr: SocketAddr = addr.parse().map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let socket = Arc::new(UdpSocket::bind(addr).await?);
+    let listener = TcpListener::bind(addr).await?;
+    println!("[DNS] Server listening on {} (udp+tcp)", addr);
+
+    tokio::spawn(serve_tcp(listener, resolver.clone(), lan.clone()));
+
+    let mut buf = vec![0u8; 65535];
+    loop {
+        let (n, peer) = match socket.recv_from(&mut buf).await {
+            Ok(packet) => packet,
+            Err(e) => {
+                eprintln!("[DNS] UDP receive failed: {}", e);
+                continue;
+            }
+        };
+        if !lan.permits(peer.ip()) {
+            println!("[DNS] Rejected query from {}", peer);
+            continue;
+        }
+        let query = buf[..n].to_vec();
+        let socket = socket.clone();
+        let resolver = resolver.clone();
+        tokio::spawn(async move {
+            match handle(&resolver, &query).await {
+                Ok(response) => {
+                    let _ = socket.send_to(&response, peer).await;
+                }
+                Err(e) => eprintln!("[DNS] Bad query from {}: {}", peer, e),
+            }
+        });
+    }
+}
+
+async fn serve_tcp(listener: TcpListener, resolver: Arc<Resolver>, lan: Arc<LanAccess>) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                eprintln!("[DNS] TCP accept failed: {}", e);
+                continue;
+            }
+        };
+        if !lan.permits(peer.ip()) {
+            println!("[DNS] Rejected connection from {}", peer);
+            continue;
+        }
+        let resolver = resolver.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_tcp(stream, &resolver).await {
+                eprintln!("[DNS] TCP query from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+// 一条连接上可以连续发多个查询，每个前面带 2 字节长度
+async fn handle_tcp(mut stream: TcpStream, resolver: &Resolver) -> io::Result<()> {
+    loop {
+        let len = match stream.read_u16().await {
+            Ok(len) => len as usize,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let mut query = vec![0u8; len];
+        stream.read_exact(&mut query).await?;
+
+        let response = handle(resolver, &query).await?;
+        let mut packet = Vec::with_capacity(response.len() + 2);
+        packet.extend_from_slice(&(response.len() as u16).to_be_bytes());
+        packet.extend_from_slice(&response);
+        stream.write_all(&packet).await?;
+    }
+}
+
+async fn handle(resolver: &Resolver, query: &[u8]) -> io::Result<Vec<u8>> {
+    let question = message::parse_query(query)?;
+    let name = question.name.as_str();
+
+    // hosts 优先于 fake-ip 和上游
+    if matches!(question.qtype, TYPE_A | TYPE_AAAA)
+        && let Some(ips) = resolver.hosts().lookup(name)
+    {
+        return Ok(message::build_response(query, &question, ips, HOSTS_TTL, 0));
+    }
+
+    if let Some(pool) = resolver.fake_ip()
+        && matches!(question.qtype, TYPE_A | TYPE_AAAA)
+        && !pool.skips(name)
+    {
+        // 只分配 IPv4 fake-ip，AAAA 返回空应答让客户端走 A 记录
+        if question.qtype == TYPE_AAAA {
+            return Ok(message::build_response(query, &question, &[], 0, 0));
+        }
+        let ip = pool.lookup_ip(name);
+        println!("[DNS] {} -> {} (fake-ip)", name, ip);
+        return Ok(message::build_response(query, &question, &[ip], FAKE_IP_TTL, 0));
+    }
+
+    if !matches!(question.qtype, TYPE_A | TYPE_AAAA) {
+        return match resolver.forward(name, query).await {
+            Ok(response) => Ok(response),
+            Err(e) => {
+                eprintln!("[DNS] Forwarding {} (type {}) failed: {}", name, question.qtype, e);
+                Ok(message::build_response(query, &question, &[], 0, RCODE_SERVFAIL))
+            }
+        };
+    }
+
+    let response = match resolver.lookup(name, question.qtype).await {
+        Ok((ips, ttl)) => message::build_response(query, &question, &ips, ttl, 0),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            message::build_response(query, &question, &[], 0, RCODE_NXDOMAIN)
+        }
+        Err(e) => {
+            eprintln!("[DNS] Lookup {} failed: {}", name, e);
+            message::build_response(query, &question, &[], 0, RCODE_SERVFAIL)
+        }
+    };
+    Ok(response)
+}
@@ -0,0 +1,616 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, OnceLock, Weak};
+use std::time::Duration;
+
+use hyper::client::conn::SendRequest;
+use hyper::{Body, Request};
+use rand::Rng;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::{Mutex, mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+use super::message::{self, Answer};
+use crate::proxy::outbound::AnyStream;
+use crate::proxy::proxy_manager::ProxyManager;
+use crate::proxy::tls;
+use crate::rule::Metadata;
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+// 每个 DoH 上游最多保留的空闲连接
+const MAX_IDLE_HTTPS: usize = 4;
+
+// ProxyManager 依赖解析器（DIRECT 拨号），所以创建之后再注入
+pub type ProxyDialer = Arc<OnceLock<Weak<ProxyManager>>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Udp,
+    Tcp,
+    Tls,
+    Https,
+}
+
+// 上游 nameserver，写法：
+//   1.1.1.1、udp://1.1.1.1:53、tcp://[2606:4700::1111]
+//   tls://dns.google:853、https://cloudflare-dns.com/dns-query
+// 末尾的 #代理名 表示经该代理或代理组连接上游（UDP 除外）
+pub struct Nameserver {
+    transport: Transport,
+    host: String,
+    port: u16,
+    path: String,
+    proxy: Option<String>,
+    dialer: ProxyDialer,
+    // 加密上游写成域名时用来解析它的 default-nameserver
+    bootstrap: Arc<Vec<Nameserver>>,
+    // DoT 共用一条连接；只在建立连接时加锁
+    tls: Mutex<Option<Arc<TlsConn>>>,
+    // DoH 的 HTTP/1.1 连接一次只能跑一个请求，空闲的放回这里
+    https: std::sync::Mutex<Vec<SendRequest<Body>>>,
+}
+
+impl fmt::Display for Nameserver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scheme = scheme_of(self.transport);
+        let host = match self.host.parse::<IpAddr>() {
+            Ok(IpAddr::V6(ip)) => format!("[{}]", ip),
+            _ => self.host.clone(),
+        };
+        write!(f, "{}://{}:{}", scheme, host, self.port)?;
+        if self.transport == Transport::Https {
+            f.write_str(&self.path)?;
+        }
+        if let Some(proxy) = &self.proxy {
+            write!(f, "#{}", proxy)?;
+        }
+        Ok(())
+    }
+}
+
+impl Nameserver {
+    pub fn parse(s: &str, dialer: &ProxyDialer, bootstrap: &Arc<Vec<Nameserver>>) -> Result<Self, String> {
+        let s = s.trim();
+        let (s, proxy) = match s.split_once('#') {
+            Some((s, proxy)) if !proxy.is_empty() => (s, Some(proxy.to_string())),
+            _ => (s, None),
+        };
+        let (transport, rest) = match s.split_once("://") {
+            Some(("udp", rest)) => (Transport::Udp, rest),
+            Some(("tcp", rest)) => (Transport::Tcp, rest),
+            Some(("tls", rest)) => (Transport::Tls, rest),
+            Some(("https", rest)) => (Transport::Https, rest),
+            Some((scheme, _)) => return Err(format!("unsupported nameserver scheme {}: {}", scheme, s)),
+            None => (Transport::Udp, s),
+        };
+        if transport == Transport::Udp && proxy.is_some() {
+            return Err(format!("udp nameserver cannot be dialed through a proxy: {}", s));
+        }
+
+        let url = url::Url::parse(&format!("{}://{}", scheme_of(transport), rest))
+            .map_err(|e| format!("invalid nameserver {}: {}", s, e))?;
+        let host = match url.host() {
+            Some(url::Host::Domain(domain)) => domain.to_string(),
+            Some(url::Host::Ipv4(ip)) => ip.to_string(),
+            Some(url::Host::Ipv6(ip)) => ip.to_string(),
+            None => return Err(format!("invalid nameserver: {}", s)),
+        };
+        let port = url.port().unwrap_or(match transport {
+            Transport::Udp | Transport::Tcp => 53,
+            Transport::Tls => 853,
+            Transport::Https => 443,
+        });
+        let path = match url.path() {
+            "" | "/" => "/dns-query",
+            path => path,
+        };
+        // 明文 UDP/TCP 需要 IP，加密上游的域名由 default-nameserver 引导
+        if matches!(transport, Transport::Udp | Transport::Tcp) && host.parse::<IpAddr>().is_err() {
+            return Err(format!("nameserver must be an IP address: {}", s));
+        }
+
+        Ok(Self {
+            transport,
+            host,
+            port,
+            path: path.to_string(),
+            proxy,
+            dialer: dialer.clone(),
+            bootstrap: bootstrap.clone(),
+            tls: Mutex::new(None),
+            https: std::sync::Mutex::new(Vec::new()),
+        })
+    }
+
+    pub async fn lookup(&self, domain: &str, qtype: u16) -> io::Result<Answer> {
+        let id = rand::thread_rng().r#gen::<u16>();
+        let query = message::build_query(id, domain, qtype);
+        let answer = message::parse_response(&self.exchange(&query).await?, id)?;
+        // SERVFAIL / REFUSED 等当作失败，交给其他 nameserver 或 fallback
+        if answer.rcode != 0 && answer.rcode != message::RCODE_NXDOMAIN {
+            return Err(io::Error::other(format!("{} returned rcode {}", self, answer.rcode)));
+        }
+        Ok(answer)
+    }
+
+    // 发送一个完整的 DNS 报文，返回原始应答
+    pub async fn exchange(&self, query: &[u8]) -> io::Result<Vec<u8>> {
+        let exchange = async {
+            let response = match self.transport {
+                Transport::Udp => self.exchange_udp(query).await?,
+                Transport::Tcp => self.exchange_tcp(query).await?,
+                Transport::Tls => self.exchange_tls(query).await?,
+                Transport::Https => self.exchange_https(query).await?,
+            };
+            // UDP 应答被截断时改用 TCP 重查
+            if message::is_truncated(&response) && self.transport == Transport::Udp {
+                return self.exchange_tcp(query).await;
+            }
+            Ok(response)
+        };
+
+        tokio::time::timeout(QUERY_TIMEOUT, exchange)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, format!("{} timed out", self)))?
+    }
+
+    async fn exchange_udp(&self, query: &[u8]) -> io::Result<Vec<u8>> {
+        let addr = SocketAddr::new(self.host.parse().map_err(io::Error::other)?, self.port);
+        let bind = if addr.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" };
+        let socket = UdpSocket::bind(bind).await?;
+        socket.connect(addr).await?;
+        socket.send(query).await?;
+
+        let mut buf = vec![0u8; 4096];
+        let n = socket.recv(&mut buf).await?;
+        buf.truncate(n);
+        Ok(buf)
+    }
+
+    async fn exchange_tcp(&self, query: &[u8]) -> io::Result<Vec<u8>> {
+        let mut stream = self.dial().await?;
+        exchange_stream(&mut stream, query).await
+    }
+
+    // DoT 复用同一条 TLS 连接；复用的连接可能已被服务器关闭，失败时换新连接重试一次
+    async fn exchange_tls(&self, query: &[u8]) -> io::Result<Vec<u8>> {
+        let (conn, fresh) = self.tls_conn().await?;
+        match conn.exchange(query).await {
+            Err(_) if !fresh => self.tls_conn().await?.0.exchange(query).await,
+            result => result,
+        }
+    }
+
+    // 返回 (连接, 是否新建)
+    async fn tls_conn(&self) -> io::Result<(Arc<TlsConn>, bool)> {
+        let mut conn = self.tls.lock().await;
+        if let Some(conn) = conn.as_ref().filter(|conn| !conn.is_closed()) {
+            return Ok((conn.clone(), false));
+        }
+        let stream = tls::connect(self.dial().await?, &self.host).await?;
+        let new = Arc::new(TlsConn::new(Box::new(stream)));
+        *conn = Some(new.clone());
+        Ok((new, true))
+    }
+
+    // DoH（RFC 8484 POST），HTTP/1.1 keep-alive 连接池
+    async fn exchange_https(&self, query: &[u8]) -> io::Result<Vec<u8>> {
+        let idle = self.https.lock().unwrap().pop();
+        if let Some(mut sender) = idle {
+            // 空闲连接可能已被服务器关闭，失败时换新连接
+            if let Ok(response) = self.post(&mut sender, query).await {
+                self.release(sender);
+                return Ok(response);
+            }
+        }
+
+        let stream = tls::connect(self.dial().await?, &self.host).await?;
+        let (mut sender, connection) = hyper::client::conn::handshake(stream)
+            .await
+            .map_err(io::Error::other)?;
+        tokio::spawn(async move {
+            let _ = connection.await;
+        });
+        let response = self.post(&mut sender, query).await?;
+        self.release(sender);
+        Ok(response)
+    }
+
+    fn release(&self, sender: SendRequest<Body>) {
+        let mut idle = self.https.lock().unwrap();
+        if idle.len() < MAX_IDLE_HTTPS {
+            idle.push(sender);
+        }
+    }
+
+    async fn post(&self, sender: &mut SendRequest<Body>, query: &[u8]) -> io::Result<Vec<u8>> {
+        futures::future::poll_fn(|cx| sender.poll_ready(cx))
+            .await
+            .map_err(io::Error::other)?;
+
+        let request = Request::post(&self.path)
+            .header(hyper::header::HOST, &self.host)
+            .header(hyper::header::CONTENT_TYPE, "application/dns-message")
+            .header(hyper::header::ACCEPT, "application/dns-message")
+            .body(Body::from(query.to_vec()))
+            .map_err(io::Error::other)?;
+        let response = sender.send_request(request).await.map_err(io::Error::other)?;
+        if !response.status().is_success() {
+            return Err(io::Error::other(format!("{} returned HTTP {}", self, response.status())));
+        }
+        let body = hyper::body::to_bytes(response.into_body())
+            .await
+            .map_err(io::Error::other)?;
+        Ok(body.to_vec())
+    }
+
+    pub fn transport(&self) -> Transport {
+        self.transport
+    }
+
+    async fn dial(&self) -> io::Result<AnyStream> {
+        let ip = self.server_ip().await?;
+        let Some(proxy) = &self.proxy else {
+            return Ok(Box::new(TcpStream::connect((ip, self.port)).await?));
+        };
+        let manager = self
+            .dialer
+            .get()
+            .and_then(Weak::upgrade)
+            .ok_or_else(|| io::Error::other("proxies are not ready yet"))?;
+        manager.connect(proxy, &Metadata::new(&ip.to_string(), self.port, None)).await
+    }
+
+    // 上游自身的地址不能交给 Resolver 解析（DIRECT 拨号会再查到这个上游），
+    // 用 default-nameserver，未配置时用系统解析器
+    async fn server_ip(&self) -> io::Result<IpAddr> {
+        if let Ok(ip) = self.host.parse() {
+            return Ok(ip);
+        }
+        let not_found = || io::Error::new(io::ErrorKind::NotFound, format!("cannot resolve {}", self.host));
+        if self.bootstrap.is_empty() {
+            return tokio::net::lookup_host((self.host.as_str(), self.port))
+                .await?
+                .next()
+                .map(|addr| addr.ip())
+                .ok_or_else(not_found);
+        }
+
+        let mut last_err = None;
+        for server in self.bootstrap.iter() {
+            match Box::pin(server.lookup(&self.host, message::TYPE_A)).await {
+                Ok(answer) if !answer.ips.is_empty() => return Ok(answer.ips[0]),
+                Ok(_) => last_err = Some(not_found()),
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err.unwrap_or_else(not_found))
+    }
+}
+
+fn scheme_of(transport: Transport) -> &'static str {
+    match transport {
+        Transport::Udp => "udp",
+        Transport::Tcp => "tcp",
+        Transport::Tls => "tls",
+        Transport::Https => "https",
+    }
+}
+
+// TCP / DoT 报文前有 2 字节长度
+async fn exchange_stream(stream: &mut AnyStream, query: &[u8]) -> io::Result<Vec<u8>> {
+    stream.write_all(&frame(query)).await?;
+    stream.flush().await?;
+    read_frame(stream).await
+}
+
+fn frame(query: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(query.len() + 2);
+    packet.extend_from_slice(&(query.len() as u16).to_be_bytes());
+    packet.extend_from_slice(query);
+    packet
+}
+
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let len = reader.read_u16().await? as usize;
+    let mut buf = vec![0u8; len];
+    reader.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+// DoT 连接上同时进行多个查询（RFC 7766 pipelining）。每个查询换成连接内唯一的 id，
+// 读任务按 id 把应答交给对应的等待者。报文由写任务整体发送，调用方取消不会写出半个报文
+struct TlsConn {
+    queue: mpsc::UnboundedSender<Vec<u8>>,
+    pending: Arc<std::sync::Mutex<Pending>>,
+    tasks: [JoinHandle<()>; 2],
+}
+
+#[derive(Default)]
+struct Pending {
+    waiters: HashMap<u16, oneshot::Sender<Vec<u8>>>,
+    next_id: u16,
+    closed: bool,
+}
+
+impl Pending {
+    fn close(&mut self) {
+        self.closed = true;
+        self.waiters.clear();
+    }
+}
+
+// 查询被取消（超时、select_ok 里输掉）时只移除自己的等待项，连接和其他查询不受影响；
+// 迟到的应答找不到等待项，由读任务丢弃
+struct Waiter<'a> {
+    pending: &'a std::sync::Mutex<Pending>,
+    id: u16,
+}
+
+impl Drop for Waiter<'_> {
+    fn drop(&mut self) {
+        self.pending.lock().unwrap().waiters.remove(&self.id);
+    }
+}
+
+impl TlsConn {
+    fn new(stream: AnyStream) -> Self {
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        let (queue, mut packets) = mpsc::unbounded_channel::<Vec<u8>>();
+        let pending = Arc::new(std::sync::Mutex::new(Pending::default()));
+
+        let writing = {
+            let pending = pending.clone();
+            tokio::spawn(async move {
+                while let Some(packet) = packets.recv().await {
+                    if writer.write_all(&packet).await.is_err() || writer.flush().await.is_err() {
+                        break;
+                    }
+                }
+                pending.lock().unwrap().close();
+            })
+        };
+        let reading = {
+            let pending = pending.clone();
+            tokio::spawn(async move {
+                while let Ok(response) = read_frame(&mut reader).await {
+                    if response.len() < 2 {
+                        break;
+                    }
+                    let id = u16::from_be_bytes([response[0], response[1]]);
+                    if let Some(waiter) = pending.lock().unwrap().waiters.remove(&id) {
+                        let _ = waiter.send(response);
+                    }
+                }
+                pending.lock().unwrap().close();
+            })
+        };
+
+        Self {
+            queue,
+            pending,
+            tasks: [writing, reading],
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.pending.lock().unwrap().closed
+    }
+
+    async fn exchange(&self, query: &[u8]) -> io::Result<Vec<u8>> {
+        if query.len() < 12 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "DNS query too short"));
+        }
+        let (tx, rx) = oneshot::channel();
+        let id = {
+            let mut pending = self.pending.lock().unwrap();
+            if pending.closed {
+                return Err(connection_closed());
+            }
+            if pending.waiters.len() >= u16::MAX as usize / 2 {
+                return Err(io::Error::other("too many DNS queries in flight"));
+            }
+            let mut id = pending.next_id;
+            while pending.waiters.contains_key(&id) {
+                id = id.wrapping_add(1);
+            }
+            pending.next_id = id.wrapping_add(1);
+            pending.waiters.insert(id, tx);
+            id
+        };
+        let _waiter = Waiter {
+            pending: &self.pending,
+            id,
+        };
+
+        let mut packet = frame(query);
+        packet[2..4].copy_from_slice(&id.to_be_bytes());
+        self.queue.send(packet).map_err(|_| connection_closed())?;
+        let mut response = rx.await.map_err(|_| connection_closed())?;
+        response[..2].copy_from_slice(&query[..2]);
+        Ok(response)
+    }
+}
+
+impl Drop for TlsConn {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+fn connection_closed() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionAborted, "DoT connection closed")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use hyper::service::service_fn;
+    use hyper::{Response, StatusCode};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    // hN.test 应答 10.0.0.N，N 越小回得越晚，用来打乱流水线上的应答顺序；slow.test 300ms 后才应答
+    async fn answer(query: Vec<u8>) -> Vec<u8> {
+        let question = message::parse_query(&query).unwrap();
+        let index = question
+            .name
+            .strip_prefix('h')
+            .and_then(|rest| rest.strip_suffix(".test"))
+            .and_then(|n| n.parse::<u8>().ok());
+        let ip = match index {
+            Some(n) => {
+                tokio::time::sleep(Duration::from_millis(100u64.saturating_sub(n as u64 * 5))).await;
+                Ipv4Addr::new(10, 0, 0, n)
+            }
+            None => {
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                Ipv4Addr::new(9, 9, 9, 9)
+            }
+        };
+        message::build_response(&query, &question, &[IpAddr::V4(ip)], 60, 0)
+    }
+
+    // 本地 DoT 桩：每个连接上并发处理查询，返回 (端口, 已接受的连接数)
+    async fn tls_stub() -> (u16, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let conns = Arc::new(AtomicUsize::new(0));
+        let counter = conns.clone();
+        tokio::spawn(async move {
+            loop {
+                let (tcp, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                let Ok(stream) = tls::test_acceptor().accept(tcp).await else {
+                    continue;
+                };
+                let (mut reader, mut writer) = tokio::io::split(stream);
+                let (tx, mut rx) = mpsc::unbounded_channel::<Vec<u8>>();
+                tokio::spawn(async move {
+                    while let Some(packet) = rx.recv().await {
+                        if writer.write_all(&packet).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+                tokio::spawn(async move {
+                    while let Ok(query) = read_frame(&mut reader).await {
+                        let tx = tx.clone();
+                        tokio::spawn(async move {
+                            let _ = tx.send(frame(&answer(query).await));
+                        });
+                    }
+                });
+            }
+        });
+        (port, conns)
+    }
+
+    // 本地 DoH 桩 (HTTP/1.1 over TLS)，返回 (端口, 已接受的连接数)
+    async fn https_stub() -> (u16, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let conns = Arc::new(AtomicUsize::new(0));
+        let counter = conns.clone();
+        tokio::spawn(async move {
+            loop {
+                let (tcp, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let Ok(stream) = tls::test_acceptor().accept(tcp).await else {
+                        return;
+                    };
+                    let service = service_fn(|request: Request<Body>| async move {
+                        if request.uri().path() != "/dns-query" {
+                            return Ok::<_, hyper::Error>(
+                                Response::builder().status(StatusCode::NOT_FOUND).body(Body::empty()).unwrap(),
+                            );
+                        }
+                        let query = hyper::body::to_bytes(request.into_body()).await?;
+                        Ok(Response::new(Body::from(answer(query.to_vec()).await)))
+                    });
+                    let _ = hyper::server::conn::Http::new().serve_connection(stream, service).await;
+                });
+            }
+        });
+        (port, conns)
+    }
+
+    fn nameserver(s: &str) -> Nameserver {
+        Nameserver::parse(s, &ProxyDialer::default(), &Arc::new(Vec::new())).unwrap()
+    }
+
+    fn v4(answer: &Answer, n: u8) -> bool {
+        answer.ips == vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, n))]
+    }
+
+    #[tokio::test]
+    async fn dot_pipelines_queries_on_one_connection() {
+        let (port, conns) = tls_stub().await;
+        let server = nameserver(&format!("tls://127.0.0.1:{}", port));
+
+        let lookups = (1..=16).map(|n| {
+            let server = &server;
+            async move { (n, server.lookup(&format!("h{}.test", n), message::TYPE_A).await.unwrap()) }
+        });
+        for (n, answer) in futures::future::join_all(lookups).await {
+            assert!(v4(&answer, n), "h{}.test answered {:?}", n, answer.ips);
+        }
+        assert!(v4(&server.lookup("h3.test", message::TYPE_A).await.unwrap(), 3));
+        assert_eq!(conns.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn dot_cancelled_query_keeps_connection() {
+        let (port, conns) = tls_stub().await;
+        let server = nameserver(&format!("tls://127.0.0.1:{}", port));
+
+        let cancelled = tokio::time::timeout(
+            Duration::from_millis(50),
+            server.lookup("slow.test", message::TYPE_A),
+        )
+        .await;
+        assert!(cancelled.is_err());
+        // 迟到的 slow.test 应答在这期间到达，不能被当成别的查询的应答
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        assert!(v4(&server.lookup("h7.test", message::TYPE_A).await.unwrap(), 7));
+        assert!(v4(&server.lookup("h8.test", message::TYPE_A).await.unwrap(), 8));
+        assert_eq!(conns.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn doh_reuses_idle_connection() {
+        let (port, conns) = https_stub().await;
+        let server = nameserver(&format!("https://127.0.0.1:{}/dns-query", port));
+
+        for n in 1..=3 {
+            assert!(v4(&server.lookup(&format!("h{}.test", n), message::TYPE_A).await.unwrap(), n));
+        }
+        assert_eq!(conns.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn encrypted_upstream_host_resolved_by_bootstrap() {
+        let (bootstrap, queries) = super::super::tests::stub(|name| match name {
+            "dns.test" => (0, vec![IpAddr::V4(Ipv4Addr::LOCALHOST)], 60),
+            _ => (message::RCODE_NXDOMAIN, vec![], 0),
+        })
+        .await;
+        let (port, conns) = tls_stub().await;
+        let dialer = ProxyDialer::default();
+        let bootstrap = Arc::new(vec![Nameserver::parse(&bootstrap, &dialer, &Arc::new(Vec::new())).unwrap()]);
+        let server = Nameserver::parse(&format!("tls://dns.test:{}", port), &dialer, &bootstrap).unwrap();
+
+        assert!(v4(&server.lookup("h5.test", message::TYPE_A).await.unwrap(), 5));
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+        assert_eq!(conns.load(Ordering::SeqCst), 1);
+    }
+}
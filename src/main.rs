@@ -1,49 +1,205 @@
 mod config;
+mod dns;
 mod proxy;
+mod rule;
 
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 use config::Config;
+use dns::Resolver;
+use proxy::auth::Authenticator;
+use proxy::dispatcher::Dispatcher;
+use proxy::lan::LanAccess;
+use proxy::provider::ProxyProvider;
 use proxy::proxy_manager::ProxyManager;
-use proxy::runtime::ProxyRuntime;
+use proxy::runtime::{Mode, ProxyGroup, ProxyRuntime};
+use rule::{RuleData, RuleEngine};
+use rule::provider::RuleProvider;
+use rule::geoip::{self, GeoIp};
+use rule::geosite::{self, GeoSite};
 use proxy::socks5::start_socks5_server;
 use proxy::http::start_http_server;
+use proxy::http_proxy::start_http_proxy_server;
+use proxy::mixed::start_mixed_server;
+use proxy::health::start_health_checks;
 
 #[tokio::main]
 async fn main() {
     let config = Config::load("config.yaml");
-    let manager = Arc::new(ProxyManager::new(&config));
-    let runtime = Arc::new(ProxyRuntime::new());
+    let mode = config
+        .mode
+        .as_deref()
+        .unwrap_or("rule")
+        .parse::<Mode>()
+        .unwrap_or_else(|e| panic!("Invalid mode in config: {}", e));
+    let runtime = Arc::new(ProxyRuntime::new(mode));
+    println!("[Init] Mode: {}", mode);
 
-    // 注册 proxy-group[1] 
-    let (group_name, proxy_candidates) = if let Some(group) = config.proxy_groups.get(1) {
-        let default_proxy = group
-            .proxies
-            .get(0)
-            .expect("No proxies in the first proxy-group");
-        runtime.register_group(&group.name, default_proxy);
+    for group in &config.proxy_groups {
+        let proxy_group = ProxyGroup::from_config(group)
+            .unwrap_or_else(|e| panic!("Invalid proxy-group {}: {}", group.name, e));
+        runtime.register_group(proxy_group);
         println!(
-            "[Init] Registered proxy group: {} -> default: {}",
-            group.name, default_proxy
+            "[Init] Registered proxy group: {} ({}) -> default: {}",
+            group.name,
+            group.group_type,
+            group.proxies.first().map(String::as_str).unwrap_or("-")
         );
+    }
+
+    // global 模式使用的 GLOBAL 选择组，未在配置中定义时默认包含全部节点和代理组
+    if runtime.get_group("GLOBAL").is_none() {
+        let members: Vec<String> = config
+            .proxies
+            .iter()
+            .filter_map(|p| p.name())
+            .chain(config.proxy_groups.iter().map(|g| g.name.as_str()))
+            .chain(["DIRECT", "REJECT"])
+            .map(String::from)
+            .collect();
+        let mut providers: Vec<String> = config.proxy_providers.keys().cloned().collect();
+        providers.sort();
+        runtime.register_group(ProxyGroup::new("GLOBAL", "select", members).with_providers(providers));
+    }
+
+    let resolver = Arc::new(
+        Resolver::new(config.dns.as_ref(), &config.hosts).unwrap_or_else(|e| panic!("Invalid dns config: {}", e)),
+    );
+    let manager = Arc::new(ProxyManager::new(&config, runtime.clone(), resolver.clone()));
+    resolver.set_proxies(&manager);
+    if let Some(pool) = resolver.fake_ip() {
+        pool.start_persist();
+        // 退出前保存 fake-ip 映射
+        let pool = pool.clone();
+        tokio::spawn(async move {
+            shutdown_signal().await;
+            pool.save();
+            std::process::exit(0);
+        });
+    }
+    let lan = Arc::new(
+        LanAccess::from_config(&config.lan_allowed_ips, &config.lan_disallowed_ips)
+            .unwrap_or_else(|e| panic!("Invalid lan-allowed-ips / lan-disallowed-ips: {}", e)),
+    );
+    if let Some(dns) = config.dns.as_ref().filter(|dns| dns.enable)
+        && let Some(addr) = dns.listen.clone()
+    {
+        let (resolver, lan) = (resolver.clone(), lan.clone());
+        tokio::spawn(async move {
+            if let Err(e) = dns::server::start_dns_server(&addr, resolver, lan).await {
+                eprintln!("[DNS] Failed to start server: {}", e);
+            }
+        });
+    }
+    // 各 provider 同时加载，没有缓存需要下载的不会互相等待
+    let proxy_providers = config.proxy_providers.iter().map(|(name, provider)| {
+        let (manager, runtime) = (&manager, &runtime);
+        async move {
+            let provider = ProxyProvider::from_config(name, provider, manager, runtime)
+                .await
+                .unwrap_or_else(|e| panic!("Invalid proxy-provider {}: {}", name, e));
+            Arc::new(provider).start_refresh();
+        }
+    });
+    futures::future::join_all(proxy_providers).await;
+    runtime
+        .check_references(|name| manager.contains(name))
+        .unwrap_or_else(|e| panic!("Invalid proxy-groups: {}", e));
 
-        // ✅ 在这里构造 tuple 并返回
-        (group.name.clone(), group.proxies.clone())
-    } else {
-        panic!("No proxy-group defined in config.");
+    start_health_checks(runtime.clone(), manager.clone());
+    let controller = config
+        .controller_addr()
+        .unwrap_or_else(|e| panic!("{}", e));
+    tokio::spawn(start_http_server(controller, runtime.clone()));
+
+    let mut rule_data = RuleData {
+        geoip: Arc::new(GeoIp::new(config.geoip_database.as_deref().unwrap_or(geoip::DEFAULT_PATH))),
+        geosite: Arc::new(GeoSite::new(config.geosite_database.as_deref().unwrap_or(geosite::DEFAULT_PATH))),
+        providers: HashMap::new(),
     };
+    let rule_providers = config.rule_providers.iter().map(|(name, provider)| {
+        let (rule_data, manager) = (&rule_data, &manager);
+        async move {
+            let provider = RuleProvider::from_config(name, provider, rule_data, manager)
+                .await
+                .unwrap_or_else(|e| panic!("Invalid rule-provider {}: {}", name, e));
+            (name.clone(), Arc::new(provider))
+        }
+    });
+    for (name, provider) in futures::future::join_all(rule_providers).await {
+        provider.start_refresh();
+        rule_data.providers.insert(name, provider);
+    }
+    let rules = RuleEngine::new(&config.rules, &rule_data)
+        .unwrap_or_else(|e| panic!("Failed to compile rules: {}", e));
+    rule_data.geoip.start().await;
+    for policy in rules.policies() {
+        if !manager.contains(policy) && runtime.get_group(policy).is_none() {
+            panic!("Rule references unknown proxy or group: {}", policy);
+        }
+    }
+    let auth = Authenticator::from_config(&config.authentication)
+        .unwrap_or_else(|e| panic!("Invalid authentication: {}", e));
+    let dispatcher = Arc::new(Dispatcher::new(manager.clone(), runtime.clone(), rules, auth, lan, resolver));
+
+    let listen_ip = config.listen_ip().unwrap_or_else(|e| panic!("{}", e));
+    let listen = |port: u16| SocketAddr::new(listen_ip, port).to_string();
+    println!("[Init] Listening on {} (allow-lan: {})", listen_ip, config.allow_lan.unwrap_or(false));
+
+    if let Some(port) = config.port {
+        let addr = listen(port);
+        let dispatcher = dispatcher.clone();
+        tokio::spawn(async move {
+            if let Err(e) = start_http_proxy_server(&addr, dispatcher).await {
+                eprintln!("[HTTP Proxy] Failed to start: {}", e);
+            }
+        });
+    }
 
-    // ✅ 然后传入 HTTP 控制器
-    tokio::spawn(start_http_server(
-        runtime.clone(),
-        group_name.clone(),
-        proxy_candidates.clone(),
-    ));
+    if let Some(port) = config.mixed_port {
+        let addr = listen(port);
+        let dispatcher = dispatcher.clone();
+        tokio::spawn(async move {
+            if let Err(e) = start_mixed_server(&addr, dispatcher).await {
+                eprintln!("[Mixed] Failed to start: {}", e);
+            }
+        });
+    }
+
+    // 监听全部地址时改用双栈，IPv4 / IPv6 的 REDIRECT 和 TPROXY 都能收到
+    let transparent_ip = config.transparent_listen_ip().unwrap_or_else(|e| panic!("{}", e));
+    let transparent_listen = |port: u16| match transparent_ip {
+        IpAddr::V4(ip) if ip.is_unspecified() => format!("[::]:{}", port),
+        ip => SocketAddr::new(ip, port).to_string(),
+    };
+
+    #[cfg(target_os = "linux")]
+    if let Some(port) = config.redir_port {
+        let addr = transparent_listen(port);
+        let dispatcher = dispatcher.clone();
+        tokio::spawn(async move {
+            if let Err(e) = proxy::redir::start_redir_server(&addr, dispatcher).await {
+                eprintln!("[Redir] Failed to start: {}", e);
+            }
+        });
+    }
+
+    #[cfg(target_os = "linux")]
+    if let Some(port) = config.tproxy_port {
+        let addr = transparent_listen(port);
+        let dispatcher = dispatcher.clone();
+        tokio::spawn(async move {
+            if let Err(e) = proxy::tproxy::start_tproxy_server(&addr, dispatcher).await {
+                eprintln!("[TProxy] Failed to start: {}", e);
+            }
+        });
+    }
 
     let port = config.socks_port.unwrap_or(7891);
     start_socks5_server(
-        &format!("0.0.0.0:{}", port),
-        manager.clone(),
-        runtime.clone(),
+        &listen(port),
+        dispatcher.clone(),
     )
     .await
     .unwrap();
@@ -52,3 +208,18 @@ async fn main() {
 
 
 
+
+// Ctrl-C 或 SIGTERM（systemd、docker stop）
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        let mut terminate = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
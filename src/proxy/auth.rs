@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+
+// 入站用户表，配置格式为 "user:pass"
+pub struct Authenticator {
+    users: HashMap<String, String>,
+}
+
+impl Authenticator {
+    pub fn from_config(entries: &[String]) -> Result<Self, String> {
+        let mut users = HashMap::new();
+        for entry in entries {
+            let (user, pass) = entry
+                .split_once(':')
+                .ok_or_else(|| format!("expected user:pass, got {}", entry))?;
+            if user.is_empty() {
+                return Err(format!("empty username in {}", entry));
+            }
+            users.insert(user.to_string(), pass.to_string());
+        }
+        Ok(Self { users })
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.users.is_empty()
+    }
+
+    pub fn verify(&self, user: &str, pass: &str) -> bool {
+        self.users
+            .get(user)
+            .is_some_and(|expected| constant_time_eq(expected.as_bytes(), pass.as_bytes()))
+    }
+}
+
+// 比较耗时只取决于长度，不随第一个不同字节的位置变化
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let mut diff = a.len() ^ b.len();
+    for i in 0..a.len().max(b.len()) {
+        let x = a.get(i).copied().unwrap_or(0);
+        let y = b.get(i).copied().unwrap_or(0);
+        diff |= (x ^ y) as usize;
+    }
+    diff == 0
+}
+
+// HTTP 代理的 Proxy-Authorization: Basic base64(user:pass)
+pub fn parse_basic(value: &str) -> Option<(String, String)> {
+    let (scheme, encoded) = value.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("Basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(decode_base64(encoded.trim())?).ok()?;
+    let (user, pass) = decoded.split_once(':')?;
+    Some((user.to_string(), pass.to_string()))
+}
+
+fn decode_base64(input: &str) -> Option<Vec<u8>> {
+    let data = input.trim_end_matches('=');
+    let padding = input.len() - data.len();
+    // 带填充时总长必须是 4 的倍数；余 1 个字符凑不出一个字节
+    if padding > 2 || (padding > 0 && !input.len().is_multiple_of(4)) || data.len() % 4 == 1 {
+        return None;
+    }
+    let input = data;
+    let mut out = Vec::with_capacity(input.len() * 3 / 4);
+    let mut acc = 0u32;
+    let mut bits = 0;
+    for c in input.bytes() {
+        let v = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        acc = (acc << 6) | v as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_padded_and_unpadded_credentials() {
+        let expected = Some(("user".to_string(), "pass".to_string()));
+        // "user:pass" 长度 9，不需要填充
+        assert_eq!(parse_basic("Basic dXNlcjpwYXNz"), expected);
+        assert_eq!(parse_basic("  basic   dXNlcjpwYXNz "), expected);
+        // "user:pa" 和 "user:pas" 分别带两个和一个 '='
+        assert_eq!(parse_basic("Basic dXNlcjpwYQ=="), Some(("user".into(), "pa".into())));
+        assert_eq!(parse_basic("Basic dXNlcjpwYXM="), Some(("user".into(), "pas".into())));
+        assert_eq!(parse_basic("Basic dXNlcjpwYXM"), Some(("user".into(), "pas".into())));
+        // 密码里可以有冒号
+        assert_eq!(parse_basic("Basic dTpwOnE="), Some(("u".into(), "p:q".into())));
+    }
+
+    #[test]
+    fn rejects_malformed_credentials() {
+        assert_eq!(parse_basic("Bearer dXNlcjpwYXNz"), None);
+        assert_eq!(parse_basic("Basic"), None);
+        assert_eq!(parse_basic("Basic dXNlcjpwYXNz!"), None);
+        assert_eq!(parse_basic("Basic dXNl=cjpwYXNz"), None);
+        assert_eq!(parse_basic("Basic dXNlcjpwYQ==="), None);
+        assert_eq!(parse_basic("Basic dXNlcjpwYQ="), None);
+        assert_eq!(parse_basic("Basic dXNlcjpwYXNzx"), None);
+        // "userpass" 没有冒号
+        assert_eq!(parse_basic("Basic dXNlcnBhc3M="), None);
+        // 解码结果不是 UTF-8
+        assert_eq!(parse_basic("Basic //79"), None);
+    }
+
+    #[test]
+    fn verifies_exact_password() {
+        let auth = Authenticator::from_config(&["user:pass".to_string()]).unwrap();
+        assert!(auth.verify("user", "pass"));
+        assert!(!auth.verify("user", "pas"));
+        assert!(!auth.verify("user", "passs"));
+        assert!(!auth.verify("user", ""));
+        assert!(!auth.verify("other", "pass"));
+        assert!(constant_time_eq(b"", b""));
+        assert!(!constant_time_eq(b"a", b""));
+    }
+}
@@ -1,19 +1,74 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
 use async_trait::async_trait;
-use tokio::net::TcpStream;
-use crate::proxy::outbound::{OutboundHandler, AnyStream};
+use tokio::net::{TcpStream, UdpSocket};
+use crate::dns::Resolver;
+use crate::proxy::outbound::{self, OutboundHandler, OutboundDatagram, AnyDatagram, AnyStream};
 
-pub struct DirectProxy;
+pub struct DirectProxy {
+    resolver: Arc<Resolver>,
+}
+
+impl DirectProxy {
+    pub fn new(resolver: Arc<Resolver>) -> Self {
+        Self { resolver }
+    }
+}
 
 #[async_trait]
 impl OutboundHandler for DirectProxy {
     async fn connect(&self, address: &str, port: u16) -> std::io::Result<AnyStream> {
-        let target = format!("{}:{}", address, port);
-        println!("[DirectProxy] Connecting to {}", target);
-        let stream = TcpStream::connect(target).await?;
-        Ok(Box::new(stream))
+        println!("[DirectProxy] Connecting to {}:{}", address, port);
+        let mut last_err = None;
+        for ip in self.resolver.resolve(address).await.map_err(outbound::destination_error)? {
+            match TcpStream::connect(SocketAddr::new(ip, port)).await {
+                Ok(stream) => return Ok(Box::new(stream)),
+                Err(e) => last_err = Some(e),
+            }
+        }
+        let e = last_err.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no address for {}", address)));
+        Err(outbound::destination_error(e))
+    }
+
+    async fn connect_over(&self, stream: AnyStream, address: &str, port: u16) -> std::io::Result<AnyStream> {
+        println!("[DirectProxy] Passing through to {}:{}", address, port);
+        Ok(stream)
+    }
+
+    async fn bind_udp(&self) -> io::Result<AnyDatagram> {
+        // 优先使用双栈套接字，IPv4 目标通过映射地址发送
+        let socket = match UdpSocket::bind("[::]:0").await {
+            Ok(socket) => socket,
+            Err(_) => UdpSocket::bind("0.0.0.0:0").await?,
+        };
+        println!("[DirectProxy] UDP bound on {}", socket.local_addr()?);
+        Ok(Arc::new(DirectDatagram {
+            socket,
+            resolver: self.resolver.clone(),
+        }))
+    }
+}
+
+pub struct DirectDatagram {
+    socket: UdpSocket,
+    resolver: Arc<Resolver>,
+}
+
+#[async_trait]
+impl OutboundDatagram for DirectDatagram {
+    async fn send_to(&self, data: &[u8], address: &str, port: u16) -> io::Result<()> {
+        let target = SocketAddr::new(self.resolver.resolve_ip(address).await?, port);
+        let target = match (target, self.socket.local_addr()?) {
+            (SocketAddr::V4(v4), SocketAddr::V6(_)) => SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port()),
+            (target, _) => target,
+        };
+        self.socket.send_to(data, target).await?;
+        Ok(())
     }
 
-    fn as_any(&self) -> &dyn std::any::Any {
-        self
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, String, u16)> {
+        let (n, from) = self.socket.recv_from(buf).await?;
+        Ok((n, from.ip().to_canonical().to_string(), from.port()))
     }
 }
@@ -0,0 +1,152 @@
+use std::io;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use crate::dns::Resolver;
+use crate::proxy::auth::Authenticator;
+use crate::proxy::lan::LanAccess;
+use crate::proxy::outbound::{AnyDatagram, AnyStream};
+use crate::proxy::proxy_manager::ProxyManager;
+use crate::proxy::runtime::{Mode, ProxyRuntime};
+use crate::rule::{Metadata, RuleEngine};
+
+pub struct UdpRoute {
+    mode: Mode,
+    policy: String,
+    meta: Metadata,
+}
+
+pub struct Dispatcher {
+    manager: Arc<ProxyManager>,
+    runtime: Arc<ProxyRuntime>,
+    rules: RuleEngine,
+    auth: Authenticator,
+    lan: Arc<LanAccess>,
+    resolver: Arc<Resolver>,
+}
+
+impl Dispatcher {
+    pub fn new(
+        manager: Arc<ProxyManager>,
+        runtime: Arc<ProxyRuntime>,
+        rules: RuleEngine,
+        auth: Authenticator,
+        lan: Arc<LanAccess>,
+        resolver: Arc<Resolver>,
+    ) -> Self {
+        Self {
+            manager,
+            runtime,
+            rules,
+            auth,
+            lan,
+            resolver,
+        }
+    }
+
+    // 所有入站在 accept 时检查来源地址
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        self.lan.permits(ip)
+    }
+
+    pub fn authenticator(&self) -> &Authenticator {
+        &self.auth
+    }
+
+    pub async fn connect(&self, meta: &Metadata) -> io::Result<AnyStream> {
+        let meta = self.prepare(meta).await;
+        let policy = self.policy(&meta);
+        self.manager.connect(policy, &meta).await
+    }
+
+    // UDP 目标的规则匹配结果，会话按目标缓存
+    pub async fn udp_route(&self, meta: &Metadata) -> UdpRoute {
+        let meta = self.prepare(meta).await;
+        let policy = self.policy(&meta).to_string();
+        UdpRoute {
+            mode: self.runtime.mode(),
+            policy,
+            meta,
+        }
+    }
+
+    // 模式切换后缓存的规则结果作废
+    pub fn is_current(&self, route: &UdpRoute) -> bool {
+        route.mode == self.runtime.mode()
+    }
+
+    // 每个包都沿代理组重新解析最终节点，组切换和 provider 更新立即生效
+    pub fn udp_proxy(&self, route: &UdpRoute) -> io::Result<String> {
+        self.manager.resolve_proxy(&route.policy, &route.meta)
+    }
+
+    // 规则匹配后最终落到的节点；relay 组没有单个节点，返回错误
+    pub async fn resolve_proxy(&self, meta: &Metadata) -> io::Result<String> {
+        let meta = self.prepare(meta).await;
+        let policy = self.policy(&meta);
+        self.manager.resolve_proxy(policy, &meta)
+    }
+
+    // 规则最终是否落到 DIRECT（BIND 只能在本机监听）
+    pub async fn routes_direct(&self, meta: &Metadata) -> bool {
+        matches!(self.resolve_proxy(meta).await.as_deref(), Ok("DIRECT"))
+    }
+
+    // fake-ip 目标换回域名，其余原样返回
+    pub fn real_host(&self, host: &str) -> String {
+        match host.parse::<IpAddr>().ok().and_then(|ip| self.resolver.fake_domain(&ip)) {
+            Some(domain) => domain,
+            None => host.to_string(),
+        }
+    }
+
+    // 域名目标在命中域名规则之前会先遇到 IP 规则时，解析出 IP 供规则匹配
+    async fn prepare(&self, meta: &Metadata) -> Metadata {
+        let mut meta = meta.clone();
+        if let Some(ip) = meta.dst_ip
+            && let Some(domain) = self.resolver.fake_domain(&ip)
+        {
+            meta.host = domain;
+            meta.dst_ip = None;
+        } else if meta.domain().is_none()
+            && let Some(ip) = meta.dst_ip
+            && let Some(domain) = self.resolver.sniff_domain(&ip)
+        {
+            // redir-host：只有 IP 的连接按解析记录还原域名，IP 规则仍按真实目标匹配
+            println!("[Rule] {} was resolved from {}", ip, domain);
+            meta.host = domain;
+            meta.sniffed = true;
+        }
+        if self.runtime.mode() == Mode::Rule
+            && self.rules.needs_resolve(&meta)
+            && let Some(domain) = meta.domain()
+        {
+            match self.resolver.resolve_ip(domain).await {
+                Ok(ip) => meta.dst_ip = Some(ip),
+                Err(e) => println!("[Rule] Cannot resolve {} for IP rules: {}", domain, e),
+            }
+        }
+        meta
+    }
+
+    pub async fn bind_udp(&self, proxy: &str) -> io::Result<AnyDatagram> {
+        self.manager.bind_udp(proxy).await
+    }
+
+    pub fn policy(&self, meta: &Metadata) -> &str {
+        match self.runtime.mode() {
+            Mode::Direct => "DIRECT",
+            Mode::Global => "GLOBAL",
+            Mode::Rule => match self.rules.match_rule(meta) {
+                Some(entry) => {
+                    println!("[Rule] {}:{} matched {} -> {}", meta.host, meta.dst_port, entry.raw, entry.policy);
+                    entry.policy.as_str()
+                }
+                None => {
+                    println!("[Rule] {}:{} matched nothing -> DIRECT", meta.host, meta.dst_port);
+                    "DIRECT"
+                }
+            },
+        }
+    }
+}
@@ -0,0 +1,257 @@
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use url::Url;
+
+use crate::config;
+use crate::proxy::outbound::AnyStream;
+use crate::proxy::proxy_manager::ProxyManager;
+use crate::proxy::runtime::{ProxyGroup, ProxyRuntime};
+use crate::proxy::tls;
+use crate::rule::Metadata;
+
+const DEFAULT_TEST_URL: &str = "http://www.gstatic.com/generate_204";
+
+#[derive(Debug, Clone)]
+pub struct HealthCheck {
+    pub url: String,
+    pub interval: Duration,
+    pub timeout: Duration,
+    pub tolerance: Duration,
+}
+
+impl HealthCheck {
+    pub fn from_config(group: &config::ProxyGroup) -> Self {
+        Self {
+            url: group.url.clone().unwrap_or_else(|| DEFAULT_TEST_URL.to_string()),
+            interval: Duration::from_secs(group.interval.unwrap_or(300).max(1)),
+            timeout: Duration::from_millis(group.timeout.unwrap_or(5000)),
+            tolerance: Duration::from_millis(group.tolerance.unwrap_or(0)),
+        }
+    }
+}
+
+pub fn start_health_checks(runtime: Arc<ProxyRuntime>, manager: Arc<ProxyManager>) {
+    for group in runtime.groups() {
+        let Some(health_check) = group.health_check().cloned() else {
+            continue;
+        };
+        let runtime = runtime.clone();
+        let manager = manager.clone();
+
+        println!(
+            "[HealthCheck] {} -> {} every {:?}",
+            group.name(),
+            health_check.url,
+            health_check.interval
+        );
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(health_check.interval);
+            loop {
+                ticker.tick().await;
+                check_group(&group, &health_check, &runtime, &manager).await;
+            }
+        });
+    }
+}
+
+pub async fn check_group(
+    group: &ProxyGroup,
+    health_check: &HealthCheck,
+    runtime: &ProxyRuntime,
+    manager: &ProxyManager,
+) {
+    let members = group.proxies();
+    let probes = members.iter().map(|member| async move {
+        let delay = match url_test(manager, member, &health_check.url, health_check.timeout).await {
+            Ok(delay) => Some(delay),
+            Err(e) => {
+                println!("[HealthCheck] {} / {} failed: {}", group.name(), member, e);
+                None
+            }
+        };
+        (member, delay)
+    });
+
+    for (member, delay) in futures::future::join_all(probes).await {
+        if let Some(delay) = delay {
+            println!("[HealthCheck] {} / {}: {} ms", group.name(), member, delay.as_millis());
+        }
+        runtime.record_delay(group.name(), member, delay);
+    }
+
+    let before = group.get();
+    group.auto_select(|member| runtime.delay(group.name(), member), health_check.tolerance);
+    let after = group.get();
+    if before != after {
+        println!("[HealthCheck] {} switched {} -> {}", group.name(), before, after);
+    }
+}
+
+// 经 name（节点、代理组或 relay 链路）发起一次 HTTP GET，返回收到响应首行所花的时间。
+// 走 probe 而不是 connect，测试失败不会让成员里的 fallback 组切换节点
+pub async fn url_test(manager: &ProxyManager, name: &str, url: &str, timeout: Duration) -> io::Result<Duration> {
+    let url = Url::parse(url).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let host = url
+        .host_str()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "test url has no host"))?
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_string();
+    let port = url.port_or_known_default().unwrap_or(80);
+    let path = &url[url::Position::BeforePath..url::Position::AfterQuery];
+
+    let start = Instant::now();
+    let probe = async {
+        let stream = manager.probe(name, &Metadata::new(&host, port, None)).await?;
+        let mut stream: AnyStream = if url.scheme() == "https" {
+            Box::new(tls::connect(stream, &host).await?)
+        } else {
+            stream
+        };
+
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: clash-rs\r\nConnection: close\r\n\r\n",
+            path,
+            url.host_str().unwrap_or(&host)
+        );
+        stream.write_all(request.as_bytes()).await?;
+        stream.flush().await?;
+
+        let mut status = [0u8; 12];
+        stream.read_exact(&mut status).await?;
+        if !status.starts_with(b"HTTP/") {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid HTTP response"));
+        }
+        Ok(start.elapsed())
+    };
+
+    tokio::time::timeout(timeout, probe)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "url-test timed out"))?
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::dns::Resolver;
+    use crate::proxy::runtime::Mode;
+
+    // 本地测试地址：等待 delay 后回 204，None 表示一直不回
+    async fn serve(delay: Option<Duration>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 1024];
+                    let _ = stream.read(&mut buf).await;
+                    match delay {
+                        Some(delay) => {
+                            tokio::time::sleep(delay).await;
+                            let _ = stream.write_all(b"HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n").await;
+                        }
+                        None => tokio::time::sleep(Duration::from_secs(60)).await,
+                    }
+                });
+            }
+        });
+        format!("http://{}/generate_204", addr)
+    }
+
+    // 按 proxy-groups 的 yaml 建出 runtime 和 manager
+    fn setup(groups: &str) -> (Arc<ProxyRuntime>, ProxyManager) {
+        let yaml = format!("proxies: []\nproxy-groups: {}\nrules: []\n", groups);
+        let config: Config = serde_yaml::from_str(&yaml).unwrap();
+        let runtime = Arc::new(ProxyRuntime::new(Mode::Rule));
+        for group in &config.proxy_groups {
+            runtime.register_group(ProxyGroup::from_config(group).unwrap());
+        }
+        let resolver = Arc::new(Resolver::new(None, &serde_yaml::Mapping::new()).unwrap());
+        let manager = ProxyManager::new(&config, runtime.clone(), resolver);
+        (runtime, manager)
+    }
+
+    async fn check_all(runtime: &ProxyRuntime, manager: &ProxyManager) {
+        for group in runtime.groups() {
+            if let Some(health_check) = group.health_check() {
+                check_group(&group, health_check, runtime, manager).await;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn url_test_measures_delay() {
+        let url = serve(Some(Duration::from_millis(100))).await;
+        let (_, manager) = setup("[]");
+        let delay = url_test(&manager, "DIRECT", &url, Duration::from_secs(2)).await.unwrap();
+        assert!(delay >= Duration::from_millis(100), "{:?}", delay);
+        assert!(delay < Duration::from_secs(2), "{:?}", delay);
+    }
+
+    #[tokio::test]
+    async fn url_test_times_out() {
+        let url = serve(None).await;
+        let (_, manager) = setup("[]");
+        let err = url_test(&manager, "DIRECT", &url, Duration::from_millis(200)).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn delays_are_recorded_per_group() {
+        let fast = serve(Some(Duration::ZERO)).await;
+        let slow = serve(None).await;
+        // 同一个成员在两个组里，测试地址不同
+        let (runtime, manager) = setup(&format!(
+            r#"
+  - {{ name: fast, type: url-test, proxies: [DIRECT], url: "{}" }}
+  - {{ name: slow, type: fallback, proxies: [DIRECT], url: "{}", timeout: 200 }}"#,
+            fast, slow
+        ));
+
+        check_all(&runtime, &manager).await;
+        assert!(runtime.delay("fast", "DIRECT").is_some());
+        assert_eq!(runtime.delay("slow", "DIRECT"), None);
+    }
+
+    #[tokio::test]
+    async fn relay_members_are_tested_end_to_end() {
+        let url = serve(Some(Duration::ZERO)).await;
+        // broken 的第一跳 DIRECT 可用，但整条链路不通
+        let (runtime, manager) = setup(&format!(
+            r#"
+  - {{ name: working, type: relay, proxies: [DIRECT] }}
+  - {{ name: broken, type: relay, proxies: [DIRECT, REJECT] }}
+  - {{ name: auto, type: url-test, proxies: [broken, working], url: "{}" }}"#,
+            url
+        ));
+
+        check_all(&runtime, &manager).await;
+        assert!(runtime.delay("auto", "working").is_some());
+        assert_eq!(runtime.delay("auto", "broken"), None);
+        assert_eq!(runtime.get_group("auto").unwrap().get(), "working");
+    }
+
+    #[tokio::test]
+    async fn probing_a_fallback_member_does_not_fail_it_over() {
+        let url = serve(Some(Duration::ZERO)).await;
+        let (runtime, manager) = setup(&format!(
+            r#"
+  - {{ name: fb, type: fallback, proxies: [REJECT, DIRECT], url: "{}" }}
+  - {{ name: auto, type: url-test, proxies: [fb], url: "{}" }}"#,
+            url, url
+        ));
+
+        // fb 当前选中 REJECT，测的就是它；DIRECT 可用也不能被顺手切过去
+        assert!(url_test(&manager, "fb", &url, Duration::from_secs(2)).await.is_err());
+        assert_eq!(runtime.get_group("fb").unwrap().get(), "REJECT");
+        assert_eq!(runtime.delay("fb", "REJECT"), None);
+        assert_eq!(runtime.delay("fb", "DIRECT"), None);
+    }
+}
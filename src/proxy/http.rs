@@ -5,33 +5,16 @@ use std::sync::Arc;
 use hyper::{Body, Request, Response, Server, Method, StatusCode};
 use hyper::service::{make_service_fn, service_fn};
 
-use crate::proxy::runtime::ProxyRuntime;
+use crate::proxy::runtime::{Mode, ProxyRuntime};
 
-pub async fn start_http_server(
-    runtime: Arc<ProxyRuntime>,
-    group_name: String,
-    candidates: Vec<String>,
-) {
-    use hyper::{Body, Request, Response, Server, Method, StatusCode};
-    use hyper::service::{make_service_fn, service_fn};
-    use std::convert::Infallible;
-    use std::net::SocketAddr;
-
-    let addr = SocketAddr::from(([127, 0, 0, 1], 8080));
+pub async fn start_http_server(addr: SocketAddr, runtime: Arc<ProxyRuntime>) {
 
     let make_svc = make_service_fn(move |_| {
         let runtime = runtime.clone();
-        let group_name = group_name.clone();
-        let candidates = candidates.clone();
 
         async move {
             Ok::<_, Infallible>(service_fn(move |req| {
-                handle(
-                    req,
-                    runtime.clone(),
-                    group_name.clone(),
-                    candidates.clone(),
-                )
+                handle(req, runtime.clone())
             }))
         }
     });
@@ -43,89 +26,132 @@ pub async fn start_http_server(
 async fn handle(
     req: Request<Body>,
     runtime: Arc<ProxyRuntime>,
-    group_name: String,
-    candidates: Vec<String>,
 ) -> Result<Response<Body>, Infallible> {
-    let group = runtime.get_group(&group_name);
     match (req.method(), req.uri().path()) {
         (&Method::GET, "/proxies") => {
-            if let Some(group) = runtime.get_group(&group_name) {
+            let mut response = String::new();
+            for group in runtime.groups() {
                 let current = group.get();
-                let mut response = format!("Current: {}\n\nAvailable:\n", current);
+                response.push_str(&format!("[{}] ({})\nCurrent: {}\nAvailable:\n", group.name(), group.group_type(), current));
 
-                for p in &candidates {
+                for p in group.proxies().iter() {
+                    let delay = runtime
+                        .delay(group.name(), p)
+                        .map(|d| format!(" ({} ms)", d.as_millis()))
+                        .unwrap_or_default();
                     if *p == current {
-                        response.push_str(&format!("- ✅ {}\n", p));
+                        response.push_str(&format!("- ✅ {}{}\n", p, delay));
                     } else {
-                        response.push_str(&format!("- {}\n", p));
+                        response.push_str(&format!("- {}{}\n", p, delay));
                     }
                 }
+                response.push('\n');
+            }
+
+            Ok(utf8_response(response))
+        }
 
-                return Ok(utf8_response(response));
-            } else {
-                return Ok(Response::new(Body::from("No such group\n")));
+        // 改状态的接口只接受 POST，浏览器里的链接、图片等跨站 GET 不能切换节点或模式
+        (&Method::POST, "/proxy") => {
+            if !same_origin(&req) {
+                return Ok(error_response(StatusCode::FORBIDDEN, "Cross-origin request rejected\n"));
             }
+            let Some(group_name) = query_param(&req, "group") else {
+                return Ok(error_response(StatusCode::BAD_REQUEST, "Missing ?group=xxx\n"));
+            };
+            let Some(group) = runtime.get_group(&group_name) else {
+                return Ok(error_response(StatusCode::NOT_FOUND, "No such group\n"));
+            };
+            let Some(value) = query_param(&req, "to") else {
+                return Ok(error_response(StatusCode::BAD_REQUEST, "Missing ?to=xxx\n"));
+            };
+
+            println!("[HTTP] Switching {} to {}", group_name, value);
+            if let Err(e) = group.set(&value) {
+                return Ok(error_response(StatusCode::BAD_REQUEST, format!("{}\n", e)));
+            }
+
+            // ✅ 如果是 /ui 里的表单，跳转回 /ui
+            if wants_html(&req) {
+                return Ok(redirect_to_ui());
+            }
+
+            // curl POST 响应
+            Ok(Response::new(Body::from(format!("Switched {} to: {}\n", group_name, value))))
         }
 
-        (&Method::GET, "/proxy") | (&Method::POST, "/proxy") => {
-            if let Some(group) = runtime.get_group(&group_name) {
-                if let Some(query) = req.uri().query() {
-                    let params = url::form_urlencoded::parse(query.as_bytes()).collect::<Vec<_>>();
-                    for (key, value) in params {
-                        if key == "to" {
-                            println!("[HTTP] Switching Proxy to {}", value);
-                            group.set(&value);
-
-                            // ✅ 如果是浏览器点击，跳转回 /ui
-                            if req.method() == Method::GET {
-                                let mut resp = Response::new(Body::empty());
-                                *resp.status_mut() = StatusCode::FOUND;
-                                resp.headers_mut().insert(
-                                    hyper::header::LOCATION,
-                                    "/ui".parse().unwrap(),
-                                );
-                                return Ok(resp);
-                            }
-
-                            // curl POST 响应
-                            return Ok(Response::new(Body::from(format!("Switched to: {}\n", value))));
-                        }
+        (&Method::GET, "/mode") => Ok(utf8_response(format!("Mode: {}\n", runtime.mode()))),
+
+        (&Method::POST, "/mode") => {
+            if !same_origin(&req) {
+                return Ok(error_response(StatusCode::FORBIDDEN, "Cross-origin request rejected\n"));
+            }
+            let Some(value) = query_param(&req, "to") else {
+                return Ok(error_response(StatusCode::BAD_REQUEST, "Missing ?to=xxx\n"));
+            };
+            match value.parse::<Mode>() {
+                Ok(mode) => {
+                    println!("[HTTP] Switching mode to {}", mode);
+                    runtime.set_mode(mode);
+
+                    if wants_html(&req) {
+                        return Ok(redirect_to_ui());
                     }
+
+                    Ok(utf8_response(format!("Switched mode to: {}\n", mode)))
                 }
-                Ok(Response::new(Body::from("Missing ?to=xxx\n")))
-            } else {
-                Ok(Response::new(Body::from("No such group\n")))
+                Err(e) => Ok(error_response(StatusCode::BAD_REQUEST, format!("{}\n", e))),
             }
         }
 
+        (&Method::GET, "/proxy") => Ok(error_response(StatusCode::METHOD_NOT_ALLOWED, "Use POST\n")),
+
         (&Method::GET, "/ui") => {
-            let group = runtime.get_group(&group_name);
-            if let Some(group) = group {
-                let current = group.get();
-                let mut html = String::new();
-                html.push_str("<html><head><meta charset='utf-8'><title>Proxy Switcher</title></head><body>");
-                html.push_str("<h2>🚀 当前代理节点</h2>");
-                html.push_str(&format!("<p><b>{}</b></p>", current));
+            let mut html = String::new();
+            html.push_str("<html><head><meta charset='utf-8'><title>Proxy Switcher</title></head><body>");
+            let mode = runtime.mode();
+            html.push_str("<h2>🧩 运行模式</h2><p>");
+            for m in [Mode::Rule, Mode::Global, Mode::Direct] {
+                if m == mode {
+                    html.push_str(&format!("<b>✅ {}</b> ", m));
+                } else {
+                    html.push_str(&format!(
+                        "<form method='post' action='/mode?to={}' style='display:inline'><button>{}</button></form> ",
+                        m, m
+                    ));
+                }
+            }
+            html.push_str("</p>");
 
-                html.push_str("<h3>🧭 可选节点：</h3><ul>");
-                for p in &candidates {
+            // 组名、节点名来自配置和订阅，原样拼进页面会被注入脚本
+            for group in runtime.groups() {
+                let current = group.get();
+                html.push_str(&format!(
+                    "<h2>🚀 {} <small>({})</small></h2>",
+                    escape_html(group.name()),
+                    escape_html(group.group_type())
+                ));
+                html.push_str(&format!("<p>当前节点：<b>{}</b></p>", escape_html(&current)));
+
+                html.push_str("<ul>");
+                for p in group.proxies().iter() {
                     html.push_str(&format!(
-                        "<li><a href='/proxy?to={}'>{}</a></li>",
+                        "<li><form method='post' action='/proxy?group={}&amp;to={}' style='display:inline'><button>{}</button></form></li>",
+                        urlencoding::encode(group.name()),
                         urlencoding::encode(p),  // URL 编码中文
-                        if *p == current { format!("✅ {}", p) } else { p.to_string() }
+                        if *p == current { format!("✅ {}", escape_html(p)) } else { escape_html(p) }
                     ));
                 }
-                html.push_str("</ul></body></html>");
-
-                let mut resp = Response::new(Body::from(html));
-                resp.headers_mut().insert(
-                    hyper::header::CONTENT_TYPE,
-                    "text/html; charset=utf-8".parse().unwrap(),
-                );
-                return Ok(resp);
-            } else {
-                return Ok(Response::new(Body::from("No proxy group")));
+                html.push_str("</ul>");
             }
+            html.push_str("</body></html>");
+
+            let mut resp = Response::new(Body::from(html));
+            resp.headers_mut().insert(
+                hyper::header::CONTENT_TYPE,
+                "text/html; charset=utf-8".parse().unwrap(),
+            );
+            Ok(resp)
         }
 
         _ => {
@@ -134,6 +160,71 @@ async fn handle(
     }
 }
 
+fn query_param(req: &Request<Body>, key: &str) -> Option<String> {
+    let query = req.uri().query()?;
+    url::form_urlencoded::parse(query.as_bytes())
+        .find(|(k, _)| k == key)
+        .map(|(_, v)| v.into_owned())
+}
+
+// 带 Origin 的请求（浏览器发起）必须来自控制器自己的页面
+fn same_origin(req: &Request<Body>) -> bool {
+    let Some(origin) = req.headers().get(hyper::header::ORIGIN) else {
+        return true;
+    };
+    let host = req.headers().get(hyper::header::HOST).and_then(|h| h.to_str().ok());
+    let origin = origin.to_str().ok().and_then(|o| url::Url::parse(o).ok());
+    match (origin, host) {
+        (Some(origin), Some(host)) => {
+            let authority = match origin.port() {
+                Some(port) => format!("{}:{}", origin.host_str().unwrap_or_default(), port),
+                None => origin.host_str().unwrap_or_default().to_string(),
+            };
+            authority.eq_ignore_ascii_case(host)
+        }
+        _ => false,
+    }
+}
+
+fn wants_html(req: &Request<Body>) -> bool {
+    req.headers()
+        .get(hyper::header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains("text/html"))
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn redirect_to_ui() -> Response<Body> {
+    let mut resp = Response::new(Body::empty());
+    // POST 之后用 303 让浏览器以 GET 打开 /ui
+    *resp.status_mut() = StatusCode::SEE_OTHER;
+    resp.headers_mut().insert(
+        hyper::header::LOCATION,
+        "/ui".parse().unwrap(),
+    );
+    resp
+}
+
+fn error_response(status: StatusCode, text: impl Into<String>) -> Response<Body> {
+    let mut resp = utf8_response(text);
+    *resp.status_mut() = status;
+    resp
+}
+
 fn utf8_response(text: impl Into<String>) -> Response<Body> {
     let mut resp = Response::new(Body::from(text.into()));
     resp.headers_mut().insert(
@@ -141,4 +232,4 @@ fn utf8_response(text: impl Into<String>) -> Response<Body> {
         "text/plain; charset=utf-8".parse().unwrap(),
     );
     resp
-}
\ No newline at end of file
+}
@@ -0,0 +1,755 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use url::Url;
+
+use crate::proxy::auth;
+use crate::proxy::dispatcher::Dispatcher;
+use crate::proxy::outbound::AnyStream;
+use crate::rule::Metadata;
+
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+const MAX_CHUNK_LINE: usize = 4096;
+// 上游迟迟不回 100 Continue（不认识 Expect 的老服务器）时直接发送请求体
+const EXPECT_CONTINUE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// 没有请求体时，复用的空闲连接被上游关闭可以换新连接重发
+const IDEMPOTENT_METHODS: &[&str] = &["GET", "HEAD", "OPTIONS", "TRACE", "PUT", "DELETE"];
+
+// 转发给上游时需要去掉的逐跳头
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "proxy-authorization",
+    "proxy-authenticate",
+    "te",
+    "trailer",
+    "upgrade",
+];
+
+pub async fn start_http_proxy_server(
+    addr: &str,
+    dispatcher: Arc<Dispatcher>,
+) -> io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    println!("[HTTP Proxy] Listening on {}", addr);
+
+    loop {
+        let (stream, peer_addr) = listener.accept().await?;
+        if !dispatcher.permits(peer_addr.ip()) {
+            println!("[HTTP Proxy] Rejected connection from {}", peer_addr);
+            continue;
+        }
+        let dispatcher = dispatcher.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = handle_client(stream, peer_addr, dispatcher).await {
+                eprintln!("[HTTP Proxy] Error from {}: {:?}", peer_addr, e);
+            }
+        });
+    }
+}
+
+struct Head {
+    // 请求：method / target / version；响应：version / status / reason
+    parts: [String; 3],
+    headers: Vec<(String, String)>,
+}
+
+impl Head {
+    fn parse(raw: &[u8]) -> io::Result<Self> {
+        let text = String::from_utf8_lossy(raw);
+        let mut lines = text.split("\r\n").map(|l| l.trim_end_matches('\n'));
+        let start = lines
+            .next()
+            .ok_or_else(|| invalid_data("empty HTTP head"))?;
+
+        let mut split = start.splitn(3, ' ');
+        let parts = [
+            split.next().unwrap_or_default().to_string(),
+            split.next().unwrap_or_default().to_string(),
+            split.next().unwrap_or_default().to_string(),
+        ];
+        if parts[0].is_empty() || parts[1].is_empty() {
+            return Err(invalid_data(format!("malformed start line: {}", start)));
+        }
+
+        let mut headers = Vec::new();
+        for line in lines.filter(|l| !l.is_empty()) {
+            let (name, value) = line
+                .split_once(':')
+                .ok_or_else(|| invalid_data(format!("malformed header: {}", line)))?;
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+
+        Ok(Self { parts, headers })
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn has_token(&self, name: &str, token: &str) -> bool {
+        self.headers
+            .iter()
+            .filter(|(k, _)| k.eq_ignore_ascii_case(name))
+            .flat_map(|(_, v)| v.split(','))
+            .any(|t| t.trim().eq_ignore_ascii_case(token))
+    }
+
+    fn keep_alive(&self, version: &str) -> bool {
+        let header = if self.header("Proxy-Connection").is_some() { "Proxy-Connection" } else { "Connection" };
+        if self.has_token(header, "close") {
+            return false;
+        }
+        version.eq_ignore_ascii_case("HTTP/1.1") || self.has_token(header, "keep-alive")
+    }
+
+    fn body_length(&self) -> io::Result<BodyLength> {
+        if self.has_token("Transfer-Encoding", "chunked") {
+            return Ok(BodyLength::Chunked);
+        }
+        match self.header("Content-Length") {
+            Some(len) => len
+                .parse()
+                .map(BodyLength::Fixed)
+                .map_err(|_| invalid_data(format!("invalid Content-Length: {}", len))),
+            None => Ok(BodyLength::None),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BodyLength {
+    None,
+    Fixed(u64),
+    Chunked,
+    UntilClose,
+}
+
+struct Upstream {
+    host: String,
+    port: u16,
+    // 建立连接时规则选中的节点
+    proxy: String,
+    stream: BufReader<AnyStream>,
+}
+
+pub async fn handle_client(
+    stream: TcpStream,
+    peer_addr: SocketAddr,
+    dispatcher: Arc<Dispatcher>,
+) -> io::Result<()> {
+    let mut client = BufReader::new(stream);
+    let mut upstream: Option<Upstream> = None;
+
+    loop {
+        let Some(raw) = read_head(&mut client).await? else {
+            return Ok(());
+        };
+        let request = match Head::parse(&raw) {
+            Ok(request) => request,
+            Err(e) => {
+                write_status(client.get_mut(), 400, "Bad Request").await?;
+                return Err(e);
+            }
+        };
+
+        // 配置了认证时每个请求都要带 Proxy-Authorization
+        let user = match authorize(&request, &dispatcher) {
+            Ok(user) => user,
+            Err(e) => {
+                client
+                    .get_mut()
+                    .write_all(
+                        b"HTTP/1.1 407 Proxy Authentication Required\r\n\
+                          Proxy-Authenticate: Basic realm=\"clash-rs\"\r\n\
+                          Content-Length: 0\r\nConnection: close\r\n\r\n",
+                    )
+                    .await?;
+                return Err(e);
+            }
+        };
+
+        if request.parts[0].eq_ignore_ascii_case("CONNECT") {
+            return handle_connect(client, request, peer_addr, user, &dispatcher).await;
+        }
+
+        // 每个请求单独路由，同一目标且走同一节点时复用上游连接
+        if !handle_forward(&mut client, &request, peer_addr, user, &dispatcher, &mut upstream).await? {
+            return Ok(());
+        }
+    }
+}
+
+// 未启用认证时返回 None
+fn authorize(request: &Head, dispatcher: &Dispatcher) -> io::Result<Option<String>> {
+    if !dispatcher.authenticator().enabled() {
+        return Ok(None);
+    }
+    let Some((user, pass)) = request.header("Proxy-Authorization").and_then(auth::parse_basic) else {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "missing proxy credentials"));
+    };
+    if !dispatcher.authenticator().verify(&user, &pass) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("authentication failed for user {}", user),
+        ));
+    }
+    Ok(Some(user))
+}
+
+async fn handle_connect(
+    mut client: BufReader<TcpStream>,
+    request: Head,
+    peer_addr: SocketAddr,
+    user: Option<String>,
+    dispatcher: &Dispatcher,
+) -> io::Result<()> {
+    let Some((host, port)) = split_authority(&request.parts[1]) else {
+        write_status(client.get_mut(), 400, "Bad Request").await?;
+        return Err(invalid_data(format!("invalid CONNECT target: {}", request.parts[1])));
+    };
+
+    println!("[HTTP Proxy] CONNECT {}:{}", host, port);
+    let metadata = Metadata::new(&host, port, Some(peer_addr)).with_user(user);
+    let mut remote = match dispatcher.connect(&metadata).await {
+        Ok(remote) => remote,
+        Err(e) => {
+            write_connect_error(client.get_mut(), &e).await?;
+            return Err(e);
+        }
+    };
+
+    client
+        .get_mut()
+        .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+        .await?;
+
+    // 客户端可能已经把 TLS ClientHello 等数据和请求头一起发过来
+    let buffered = client.buffer().to_vec();
+    if !buffered.is_empty() {
+        remote.write_all(&buffered).await?;
+    }
+    let mut client = client.into_inner();
+
+    let (n1, n2) = tokio::io::copy_bidirectional(&mut client, &mut remote).await?;
+    println!("[HTTP Proxy] Tunnel complete: client → remote = {} bytes, remote → client = {} bytes", n1, n2);
+    Ok(())
+}
+
+// 返回客户端连接是否可以继续使用
+async fn handle_forward(
+    client: &mut BufReader<TcpStream>,
+    request: &Head,
+    peer_addr: SocketAddr,
+    user: Option<String>,
+    dispatcher: &Dispatcher,
+    upstream: &mut Option<Upstream>,
+) -> io::Result<bool> {
+    let [method, target, version] = &request.parts;
+    let url = match Url::parse(target) {
+        Ok(url) if url.scheme() == "http" && url.host_str().is_some() => url,
+        _ => {
+            write_status(client.get_mut(), 400, "Bad Request").await?;
+            return Err(invalid_data(format!("not a proxy request: {} {}", method, target)));
+        }
+    };
+    let raw_host = url.host_str().unwrap_or_default();
+    let host = raw_host.trim_start_matches('[').trim_end_matches(']').to_string();
+    let port = url.port_or_known_default().unwrap_or(80);
+    let path = &url[url::Position::BeforePath..url::Position::AfterQuery];
+    let keep_alive = request.keep_alive(version);
+    let request_body = request.body_length()?;
+    let expect_continue = request_body != BodyLength::None && request.has_token("Expect", "100-continue");
+    let retryable =
+        request_body == BodyLength::None && IDEMPOTENT_METHODS.iter().any(|m| method.eq_ignore_ascii_case(m));
+
+    println!("[HTTP Proxy] {} http://{}:{}{}", method, host, port, path);
+
+    // 规则或组选择变了就不能再用上一个请求的连接；relay 组没有单个节点，不复用
+    let metadata = Metadata::new(&host, port, Some(peer_addr)).with_user(user);
+    let proxy = dispatcher.resolve_proxy(&metadata).await.ok();
+    let reuse = matches!((&*upstream, &proxy), (Some(u), Some(proxy)) if u.host == host && u.port == port && &u.proxy == proxy);
+    if !reuse {
+        connect_upstream(client, dispatcher, &metadata, proxy.clone(), upstream).await?;
+    }
+
+    let mut head = format!("{} {} {}\r\n", method, path, version);
+    if request.header("Host").is_none() {
+        let authority = match url.port() {
+            Some(port) => format!("{}:{}", raw_host, port),
+            None => raw_host.to_string(),
+        };
+        head.push_str(&format!("Host: {}\r\n", authority));
+    }
+    for (name, value) in &request.headers {
+        if !HOP_BY_HOP_HEADERS.contains(&name.to_ascii_lowercase().as_str()) {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+    }
+    head.push_str(if keep_alive { "Connection: keep-alive\r\n\r\n" } else { "Connection: close\r\n\r\n" });
+
+    let remote = &mut upstream.as_mut().unwrap().stream;
+    let mut sent = send_request(client, remote, head.as_bytes(), request_body, expect_continue).await;
+    if reuse && retryable && is_stale(&sent) {
+        println!("[HTTP Proxy] Idle upstream {}:{} closed, retrying on a new connection", host, port);
+        connect_upstream(client, dispatcher, &metadata, proxy, upstream).await?;
+        let remote = &mut upstream.as_mut().unwrap().stream;
+        sent = send_request(client, remote, head.as_bytes(), request_body, expect_continue).await;
+    }
+    let (raw, mut body_sent) = match sent {
+        Ok((Some(raw), body_sent)) => (raw, body_sent),
+        Ok((None, _)) => {
+            *upstream = None;
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "upstream closed before response"));
+        }
+        Err(e) => {
+            *upstream = None;
+            return Err(e);
+        }
+    };
+    let remote = &mut upstream.as_mut().unwrap().stream;
+
+    let mut raw = raw;
+    loop {
+        let response = Head::parse(&raw)?;
+        let status: u16 = response.parts[1]
+            .parse()
+            .map_err(|_| invalid_data(format!("invalid status: {}", response.parts[1])))?;
+        client.get_mut().write_all(&raw).await?;
+
+        if status == 101 {
+            // 协议升级（如 WebSocket）后直接双向转发
+            let buffered = remote.buffer().to_vec();
+            client.get_mut().write_all(&buffered).await?;
+            let mut remote = upstream.take().unwrap().stream.into_inner();
+            tokio::io::copy_bidirectional(client.get_mut(), &mut remote).await?;
+            return Ok(false);
+        }
+        if (100..200).contains(&status) {
+            // 客户端收到 100 Continue 之后才会发请求体
+            if status == 100 && !body_sent {
+                copy_body(client, remote.get_mut(), request_body).await?;
+                remote.get_mut().flush().await?;
+                body_sent = true;
+            }
+            raw = read_head(remote)
+                .await?
+                .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "upstream closed before response"))?;
+            continue;
+        }
+
+        let response_body = if method.eq_ignore_ascii_case("HEAD") || status == 204 || status == 304 {
+            BodyLength::None
+        } else {
+            match response.body_length()? {
+                BodyLength::None => BodyLength::UntilClose,
+                other => other,
+            }
+        };
+        copy_body(remote, client.get_mut(), response_body).await?;
+        client.get_mut().flush().await?;
+
+        // 上游没等请求体就给了最终应答时，请求体还没读走，两边的连接都不能再复用
+        let upstream_alive =
+            body_sent && response_body != BodyLength::UntilClose && response.keep_alive(&response.parts[0]);
+        if !upstream_alive {
+            *upstream = None;
+        }
+        return Ok(keep_alive && body_sent && response_body != BodyLength::UntilClose);
+    }
+}
+
+async fn connect_upstream(
+    client: &mut BufReader<TcpStream>,
+    dispatcher: &Dispatcher,
+    metadata: &Metadata,
+    proxy: Option<String>,
+    upstream: &mut Option<Upstream>,
+) -> io::Result<()> {
+    *upstream = None;
+    let stream = match dispatcher.connect(metadata).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            write_connect_error(client.get_mut(), &e).await?;
+            return Err(e);
+        }
+    };
+    *upstream = Some(Upstream {
+        host: metadata.host.clone(),
+        port: metadata.dst_port,
+        proxy: proxy.unwrap_or_default(),
+        stream: BufReader::new(stream),
+    });
+    Ok(())
+}
+
+// 发出请求并读回第一个应答头；返回 (应答头, 请求体是否已发送)
+async fn send_request(
+    client: &mut BufReader<TcpStream>,
+    remote: &mut BufReader<AnyStream>,
+    head: &[u8],
+    body: BodyLength,
+    expect_continue: bool,
+) -> io::Result<(Option<Vec<u8>>, bool)> {
+    remote.get_mut().write_all(head).await?;
+    let mut body_sent = !expect_continue;
+    if body_sent {
+        copy_body(client, remote.get_mut(), body).await?;
+    }
+    remote.get_mut().flush().await?;
+
+    if !body_sent && tokio::time::timeout(EXPECT_CONTINUE_TIMEOUT, remote.fill_buf()).await.is_err() {
+        copy_body(client, remote.get_mut(), body).await?;
+        remote.get_mut().flush().await?;
+        body_sent = true;
+    }
+    Ok((read_head(remote).await?, body_sent))
+}
+
+// 复用的连接在收到任何应答之前就断了，说明上游已经关闭了空闲连接
+fn is_stale(sent: &io::Result<(Option<Vec<u8>>, bool)>) -> bool {
+    match sent {
+        Ok((None, _)) => true,
+        Ok(_) => false,
+        Err(e) => matches!(
+            e.kind(),
+            io::ErrorKind::BrokenPipe
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::UnexpectedEof
+        ),
+    }
+}
+
+async fn read_head<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut head = Vec::new();
+    loop {
+        let start = head.len();
+        if start >= MAX_HEAD_SIZE {
+            return Err(invalid_data("HTTP head too large"));
+        }
+        let n = read_line(reader, &mut head, MAX_HEAD_SIZE - start, "HTTP head too large").await?;
+        if n == 0 {
+            if head.iter().all(|b| b.is_ascii_whitespace()) {
+                return Ok(None);
+            }
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "incomplete HTTP head"));
+        }
+
+        let line = &head[start..];
+        if line == b"\r\n" || line == b"\n" {
+            // 允许请求之间出现多余的空行
+            if start == 0 {
+                head.clear();
+                continue;
+            }
+            return Ok(Some(head));
+        }
+    }
+}
+
+// 读一行追加到 buf，最多读 limit 字节；超过 limit 还没有换行就报错，返回 0 表示 EOF
+async fn read_line<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    buf: &mut Vec<u8>,
+    limit: usize,
+    too_long: &str,
+) -> io::Result<usize> {
+    let n = (&mut *reader).take(limit as u64).read_until(b'\n', buf).await?;
+    if n == limit && buf.last() != Some(&b'\n') {
+        return Err(invalid_data(too_long));
+    }
+    Ok(n)
+}
+
+async fn copy_body<R, W>(reader: &mut R, writer: &mut W, length: BodyLength) -> io::Result<()>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    match length {
+        BodyLength::None => Ok(()),
+        BodyLength::Fixed(len) => copy_exact(reader, writer, len).await,
+        BodyLength::UntilClose => tokio::io::copy(reader, writer).await.map(|_| ()),
+        BodyLength::Chunked => {
+            let mut line = Vec::new();
+            loop {
+                line.clear();
+                if read_line(reader, &mut line, MAX_CHUNK_LINE, "chunk size line too long").await? == 0 {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "incomplete chunked body"));
+                }
+                writer.write_all(&line).await?;
+
+                let size_text = String::from_utf8_lossy(&line);
+                let size_text = size_text.split(';').next().unwrap_or_default().trim();
+                let size = u64::from_str_radix(size_text, 16)
+                    .map_err(|_| invalid_data(format!("invalid chunk size: {}", size_text)))?;
+
+                if size == 0 {
+                    // trailer 部分以空行结束，总长度和请求头一样受限
+                    let mut trailer = 0;
+                    loop {
+                        line.clear();
+                        if trailer >= MAX_HEAD_SIZE {
+                            return Err(invalid_data("chunked trailer too large"));
+                        }
+                        let n = read_line(reader, &mut line, MAX_HEAD_SIZE - trailer, "chunked trailer too large").await?;
+                        if n == 0 {
+                            return Ok(());
+                        }
+                        trailer += n;
+                        writer.write_all(&line).await?;
+                        if line == b"\r\n" || line == b"\n" {
+                            return Ok(());
+                        }
+                    }
+                }
+                copy_exact(reader, writer, size + 2).await?;
+            }
+        }
+    }
+}
+
+async fn copy_exact<R, W>(reader: &mut R, writer: &mut W, len: u64) -> io::Result<()>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let copied = tokio::io::copy(&mut reader.take(len), writer).await?;
+    if copied != len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "body ended early"));
+    }
+    Ok(())
+}
+
+async fn write_status<W: AsyncWrite + Unpin>(writer: &mut W, code: u16, reason: &str) -> io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        code, reason
+    );
+    writer.write_all(response.as_bytes()).await
+}
+
+// 出站失败按错误类型给出状态码，正文带上原因方便排查
+async fn write_connect_error<W: AsyncWrite + Unpin>(writer: &mut W, e: &io::Error) -> io::Result<()> {
+    let (code, reason) = match e.kind() {
+        io::ErrorKind::PermissionDenied => (403, "Forbidden"),
+        io::ErrorKind::TimedOut => (504, "Gateway Timeout"),
+        _ => (502, "Bad Gateway"),
+    };
+    let body = format!("{}\n", e);
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        code,
+        reason,
+        body.len(),
+        body
+    );
+    writer.write_all(response.as_bytes()).await
+}
+
+fn split_authority(authority: &str) -> Option<(String, u16)> {
+    let (host, port) = authority.rsplit_once(':')?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return None;
+    }
+    Some((host.to_string(), port.parse().ok()?))
+}
+
+fn invalid_data(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::config::Config;
+    use crate::dns::Resolver;
+    use crate::proxy::auth::Authenticator;
+    use crate::proxy::lan::LanAccess;
+    use crate::proxy::proxy_manager::ProxyManager;
+    use crate::proxy::runtime::{Mode, ProxyRuntime};
+    use crate::rule::geoip::GeoIp;
+    use crate::rule::geosite::GeoSite;
+    use crate::rule::{RuleData, RuleEngine};
+
+    // 没有规则，全部走 DIRECT
+    async fn start_proxy() -> SocketAddr {
+        let config: Config = serde_yaml::from_str("proxies: []\nproxy-groups: []\nrules: []\n").unwrap();
+        let runtime = Arc::new(ProxyRuntime::new(Mode::Rule));
+        let resolver = Arc::new(Resolver::new(None, &serde_yaml::Mapping::new()).unwrap());
+        let manager = Arc::new(ProxyManager::new(&config, runtime.clone(), resolver.clone()));
+        let data = RuleData {
+            geoip: Arc::new(GeoIp::new("")),
+            geosite: Arc::new(GeoSite::new("")),
+            providers: HashMap::new(),
+        };
+        let dispatcher = Arc::new(Dispatcher::new(
+            manager,
+            runtime,
+            RuleEngine::new(&[], &data).unwrap(),
+            Authenticator::from_config(&[]).unwrap(),
+            Arc::new(LanAccess::from_config(&[], &[]).unwrap()),
+            resolver,
+        ));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, peer_addr) = listener.accept().await.unwrap();
+                tokio::spawn(handle_client(stream, peer_addr, dispatcher.clone()));
+            }
+        });
+        addr
+    }
+
+    // 上游桩：应答正文为 "路径 请求体长度"；close_after 为 true 时每个应答后关闭连接（模拟空闲超时），
+    // 但不带 Connection: close。返回 (地址, 已接受的连接数)
+    async fn start_upstream(close_after: bool) -> (SocketAddr, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let conns = Arc::new(AtomicUsize::new(0));
+        let counter = conns.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let mut stream = BufReader::new(stream);
+                    while let Ok(Some(raw)) = read_head(&mut stream).await {
+                        let request = Head::parse(&raw).unwrap();
+                        if request.has_token("Expect", "100-continue") {
+                            stream.get_mut().write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await.unwrap();
+                        }
+                        let mut body = Vec::new();
+                        copy_body(&mut stream, &mut body, request.body_length().unwrap()).await.unwrap();
+                        let text = format!("{} {}", request.parts[1], body.len());
+                        let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", text.len(), text);
+                        stream.get_mut().write_all(response.as_bytes()).await.unwrap();
+                        if close_after {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        (addr, conns)
+    }
+
+    // 读一个带 Content-Length 的应答，返回 (状态码, 正文)
+    async fn read_response(reader: &mut BufReader<TcpStream>) -> (u16, String) {
+        let raw = read_head(reader).await.unwrap().unwrap();
+        let response = Head::parse(&raw).unwrap();
+        let mut body = Vec::new();
+        copy_body(reader, &mut body, response.body_length().unwrap()).await.unwrap();
+        (response.parts[1].parse().unwrap(), String::from_utf8(body).unwrap())
+    }
+
+    async fn get(client: &mut BufReader<TcpStream>, upstream: SocketAddr, path: &str) -> (u16, String) {
+        let request = format!("GET http://{}{} HTTP/1.1\r\nHost: {}\r\n\r\n", upstream, path, upstream);
+        client.get_mut().write_all(request.as_bytes()).await.unwrap();
+        read_response(client).await
+    }
+
+    #[tokio::test]
+    async fn keep_alive_reuses_upstream() {
+        let proxy = start_proxy().await;
+        let (upstream, conns) = start_upstream(false).await;
+        let mut client = BufReader::new(TcpStream::connect(proxy).await.unwrap());
+
+        assert_eq!(get(&mut client, upstream, "/a").await, (200, "/a 0".to_string()));
+        assert_eq!(get(&mut client, upstream, "/b").await, (200, "/b 0".to_string()));
+        assert_eq!(conns.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn closed_idle_upstream_is_retried() {
+        let proxy = start_proxy().await;
+        let (upstream, conns) = start_upstream(true).await;
+        let mut client = BufReader::new(TcpStream::connect(proxy).await.unwrap());
+
+        assert_eq!(get(&mut client, upstream, "/a").await, (200, "/a 0".to_string()));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(get(&mut client, upstream, "/b").await, (200, "/b 0".to_string()));
+        assert_eq!(conns.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn chunked_request_body_is_forwarded() {
+        let proxy = start_proxy().await;
+        let (upstream, _) = start_upstream(false).await;
+        let mut client = BufReader::new(TcpStream::connect(proxy).await.unwrap());
+
+        let chunked = "5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\nX-Trailer: 1\r\n\r\n";
+        let request = format!(
+            "POST http://{}/upload HTTP/1.1\r\nHost: {}\r\nTransfer-Encoding: chunked\r\n\r\n{}",
+            upstream, upstream, chunked
+        );
+        client.get_mut().write_all(request.as_bytes()).await.unwrap();
+        assert_eq!(read_response(&mut client).await, (200, format!("/upload {}", chunked.len())));
+        assert_eq!(get(&mut client, upstream, "/next").await, (200, "/next 0".to_string()));
+    }
+
+    #[tokio::test]
+    async fn expect_continue_is_forwarded_before_body() {
+        let proxy = start_proxy().await;
+        let (upstream, _) = start_upstream(false).await;
+        let mut client = BufReader::new(TcpStream::connect(proxy).await.unwrap());
+
+        let request = format!(
+            "POST http://{}/upload HTTP/1.1\r\nHost: {}\r\nContent-Length: 4\r\nExpect: 100-continue\r\n\r\n",
+            upstream, upstream
+        );
+        client.get_mut().write_all(request.as_bytes()).await.unwrap();
+        // 比 EXPECT_CONTINUE_TIMEOUT 短，100 必须来自上游而不是超时后盲发
+        let raw = tokio::time::timeout(Duration::from_millis(500), read_head(&mut client))
+            .await
+            .expect("no 100 Continue")
+            .unwrap()
+            .unwrap();
+        assert!(raw.starts_with(b"HTTP/1.1 100"));
+        client.get_mut().write_all(b"data").await.unwrap();
+        assert_eq!(read_response(&mut client).await, (200, "/upload 4".to_string()));
+    }
+
+    #[tokio::test]
+    async fn oversize_head_is_rejected() {
+        let mut input = b"GET / HTTP/1.1\r\n".to_vec();
+        input.extend(std::iter::repeat_n(b'a', MAX_HEAD_SIZE));
+        let err = read_head(&mut input.as_slice()).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // 很多短行加起来超限也一样
+        let mut input = b"GET / HTTP/1.1\r\n".to_vec();
+        while input.len() <= MAX_HEAD_SIZE {
+            input.extend_from_slice(b"X-A: b\r\n");
+        }
+        input.extend_from_slice(b"\r\n");
+        let err = read_head(&mut input.as_slice()).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn oversize_chunk_line_is_rejected() {
+        let mut input = vec![b'0'; MAX_CHUNK_LINE + 1];
+        input.extend_from_slice(b"\r\n\r\n");
+        let err = copy_body(&mut input.as_slice(), &mut Vec::new(), BodyLength::Chunked)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
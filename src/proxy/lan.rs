@@ -0,0 +1,40 @@
+use std::net::IpAddr;
+
+use crate::rule::cidr::{Cidr, CidrTable};
+
+// 入站来源过滤：先查拒绝列表，再查允许列表（未配置时放行全部），本机回环始终放行
+pub struct LanAccess {
+    allowed: Option<CidrTable>,
+    denied: CidrTable,
+}
+
+impl LanAccess {
+    pub fn from_config(allowed: &[String], denied: &[String]) -> Result<Self, String> {
+        let table = |list: &[String]| -> Result<CidrTable, String> {
+            let mut table = CidrTable::new();
+            for (index, cidr) in list.iter().enumerate() {
+                table.insert(&cidr.parse::<Cidr>()?, index);
+            }
+            Ok(table)
+        };
+
+        Ok(Self {
+            allowed: if allowed.is_empty() { None } else { Some(table(allowed)?) },
+            denied: table(denied)?,
+        })
+    }
+
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        if ip.is_loopback() {
+            return true;
+        }
+        if self.denied.lookup(&ip).is_some() {
+            return false;
+        }
+        match &self.allowed {
+            Some(allowed) => allowed.lookup(&ip).is_some(),
+            None => true,
+        }
+    }
+}
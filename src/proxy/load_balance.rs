@@ -0,0 +1,136 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::rule::Metadata;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    ConsistentHashing,
+    RoundRobin,
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "consistent-hashing" => Ok(Strategy::ConsistentHashing),
+            "round-robin" => Ok(Strategy::RoundRobin),
+            other => Err(format!("unknown load-balance strategy: {}", other)),
+        }
+    }
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Strategy::ConsistentHashing => "consistent-hashing",
+            Strategy::RoundRobin => "round-robin",
+        };
+        f.write_str(name)
+    }
+}
+
+// 同一站点（eTLD+1）总是落到同一出口；目标是 IP 时按来源 IP 粘滞
+pub fn sticky_key(meta: &Metadata) -> String {
+    match meta.domain() {
+        Some(domain) => etld_plus_one(domain),
+        None => match meta.src_addr {
+            Some(src) => src.ip().to_string(),
+            None => meta.host.clone(),
+        },
+    }
+}
+
+// 最高随机权重（rendezvous）哈希：成员增减只影响落在该成员上的 key
+pub fn consistent_hash<'a>(key: &str, members: &[&'a String]) -> Option<&'a String> {
+    let key_hash = fnv1a(key.as_bytes());
+    members
+        .iter()
+        .max_by_key(|member| mix(key_hash ^ fnv1a(member.as_bytes())))
+        .copied()
+}
+
+// 按公共后缀列表（PSL）取可注册域名，如 a.b.example.co.uk -> example.co.uk；
+// 本身就是公共后缀或无法识别时原样返回
+pub fn etld_plus_one(domain: &str) -> String {
+    let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+    match psl::domain_str(&domain) {
+        Some(registrable) => registrable.to_string(),
+        None => domain,
+    }
+}
+
+fn fnv1a(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+fn mix(mut x: u64) -> u64 {
+    x ^= x >> 30;
+    x = x.wrapping_mul(0xbf58476d1ce4e5b9);
+    x ^= x >> 27;
+    x = x.wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(n: usize) -> Vec<String> {
+        (0..n).map(|i| format!("node-{}", i)).collect()
+    }
+
+    #[test]
+    fn same_site_maps_to_same_member() {
+        let members = names(5);
+        let members: Vec<&String> = members.iter().collect();
+        let pick = |host: &str| consistent_hash(&sticky_key(&Metadata::new(host, 443, None)), &members).unwrap();
+        assert_eq!(pick("www.example.com"), pick("img.cdn.example.com"));
+        assert_eq!(pick("example.com"), pick("EXAMPLE.com."));
+        assert_eq!(pick("a.bbc.co.uk"), pick("b.bbc.co.uk"));
+    }
+
+    #[test]
+    fn removing_member_only_moves_its_keys() {
+        let all = names(5);
+        let before: Vec<&String> = all.iter().collect();
+        let after: Vec<&String> = all.iter().filter(|m| *m != "node-2").collect();
+        let mut moved = 0;
+        for i in 0..1000 {
+            let key = format!("site-{}.com", i);
+            let old = consistent_hash(&key, &before).unwrap();
+            let new = consistent_hash(&key, &after).unwrap();
+            if old == "node-2" {
+                moved += 1;
+            } else {
+                assert_eq!(old, new, "{} moved off a surviving member", key);
+            }
+        }
+        // 大致五分之一的 key 落在被移除的成员上
+        assert!((100..300).contains(&moved), "moved {}", moved);
+    }
+
+    #[test]
+    fn public_suffix_is_respected() {
+        assert_eq!(etld_plus_one("a.b.example.co.uk"), "example.co.uk");
+        assert_eq!(etld_plus_one("www.bbc.co.uk"), "bbc.co.uk");
+        assert_eq!(etld_plus_one("x.y.example.com"), "example.com");
+        // 本身是公共后缀时原样返回
+        assert_eq!(etld_plus_one("co.uk"), "co.uk");
+        assert_ne!(etld_plus_one("a.co.uk"), etld_plus_one("b.co.uk"));
+    }
+
+    #[test]
+    fn ip_targets_stick_to_source() {
+        let src = Some("192.168.1.10:5000".parse().unwrap());
+        assert_eq!(sticky_key(&Metadata::new("1.1.1.1", 443, src)), "192.168.1.10");
+        assert_eq!(sticky_key(&Metadata::new("8.8.8.8", 53, src)), "192.168.1.10");
+        assert_eq!(sticky_key(&Metadata::new("1.1.1.1", 443, None)), "1.1.1.1");
+    }
+}
@@ -0,0 +1,46 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use crate::proxy::dispatcher::Dispatcher;
+use crate::proxy::{http_proxy, socks4, socks5};
+
+pub async fn start_mixed_server(
+    addr: &str,
+    dispatcher: Arc<Dispatcher>,
+) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    println!("[Mixed] Listening on {}", addr);
+
+    loop {
+        let (stream, peer_addr) = listener.accept().await?;
+        if !dispatcher.permits(peer_addr.ip()) {
+            println!("[Mixed] Rejected connection from {}", peer_addr);
+            continue;
+        }
+        let dispatcher = dispatcher.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = handle_client(stream, peer_addr, dispatcher).await {
+                eprintln!("[Mixed] Error from {}: {:?}", peer_addr, e);
+            }
+        });
+    }
+}
+
+// 根据首字节判断协议：0x05 SOCKS5，0x04 SOCKS4/4a，其余按 HTTP 处理
+async fn handle_client(
+    stream: TcpStream,
+    peer_addr: SocketAddr,
+    dispatcher: Arc<Dispatcher>,
+) -> std::io::Result<()> {
+    let mut first = [0u8; 1];
+    if stream.peek(&mut first).await? == 0 {
+        return Ok(());
+    }
+
+    match first[0] {
+        0x05 => socks5::handle_client(stream, peer_addr, dispatcher).await,
+        0x04 => socks4::handle_client(stream, peer_addr, dispatcher).await,
+        _ => http_proxy::handle_client(stream, peer_addr, dispatcher).await,
+    }
+}
@@ -1,4 +1,3 @@
-pub mod fake;
 pub mod direct;
 pub mod outbound;
 pub mod socks5;
@@ -8,3 +7,20 @@ pub mod http;
 pub mod vmess;
 pub mod ws_wrapper;
 pub mod trojan;
+pub mod reject;
+pub mod dispatcher;
+pub mod tls;
+pub mod health;
+pub mod load_balance;
+pub mod provider;
+pub mod vehicle;
+pub mod http_proxy;
+pub mod socks4;
+pub mod mixed;
+pub mod socks_addr;
+pub mod auth;
+#[cfg(target_os = "linux")]
+pub mod redir;
+#[cfg(target_os = "linux")]
+pub mod tproxy;
+pub mod lan;
@@ -1,6 +1,7 @@
 
-use std::any::Any;
+use std::fmt;
 use std::io;
+use std::sync::Arc;
 use async_trait::async_trait;
 use tokio::io::{AsyncRead, AsyncWrite};
 
@@ -8,8 +9,61 @@ pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send + Sync {}
 impl<T: AsyncRead + AsyncWrite + Unpin + Send + Sync> AsyncStream for T {}
 pub type AnyStream = Box<dyn AsyncStream>;
 
+// 目标本身连不上（直连被拒绝、解析不到等），换别的代理也没用，fallback 不据此切换节点
+#[derive(Debug)]
+struct DestinationError(io::Error);
+
+impl fmt::Display for DestinationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for DestinationError {}
+
+pub fn destination_error(e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), DestinationError(e))
+}
+
+pub fn is_destination_error(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|inner| inner.is::<DestinationError>())
+}
+
+// 去掉目标错误标记：relay 里直连的是下一跳服务器，连不上属于代理链路的问题
+pub fn proxy_error(e: io::Error) -> io::Error {
+    match e.get_ref().and_then(|inner| inner.downcast_ref::<DestinationError>()) {
+        Some(_) => io::Error::new(e.kind(), e.to_string()),
+        None => e,
+    }
+}
+
+#[async_trait]
+pub trait OutboundDatagram: Send + Sync {
+    async fn send_to(&self, data: &[u8], address: &str, port: u16) -> io::Result<()>;
+    // 返回 (长度, 来源地址, 来源端口)
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, String, u16)>;
+}
+pub type AnyDatagram = Arc<dyn OutboundDatagram>;
+
 #[async_trait]
 pub trait OutboundHandler: Send + Sync {
     async fn connect(&self, address: &str, port: u16) -> io::Result<AnyStream>;
-    fn as_any(&self) -> &dyn Any;
+
+    // 在已有的传输流上完成本节点的握手（relay 链路中的后续跳）
+    async fn connect_over(&self, stream: AnyStream, address: &str, port: u16) -> io::Result<AnyStream> {
+        let _ = stream;
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("outbound cannot relay to {}:{}", address, port),
+        ))
+    }
+
+    async fn bind_udp(&self) -> io::Result<AnyDatagram> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "outbound does not support UDP"))
+    }
+
+    // 本节点服务器地址，relay 链路中上一跳需要连到这里
+    fn server_addr(&self) -> Option<(String, u16)> {
+        None
+    }
 }
@@ -0,0 +1,126 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::config::{Proxy, ProxyProviderConfig};
+use crate::proxy::proxy_manager::ProxyManager;
+use crate::proxy::runtime::ProxyRuntime;
+use crate::proxy::vehicle::Vehicle;
+
+// 检查文件是否改动的间隔
+const FILE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Deserialize)]
+struct ProxyList {
+    #[serde(default)]
+    proxies: Vec<Proxy>,
+}
+
+/// 代理组 use 引用的节点列表（订阅）。更新时替换 ProxyManager 里的节点和引用它的各组成员。
+pub struct ProxyProvider {
+    name: String,
+    vehicle: Vehicle,
+    interval: Option<Duration>,
+    manager: Arc<ProxyManager>,
+    runtime: Arc<ProxyRuntime>,
+    // None 表示启动时用了缓存或还没加载成功，需要尽快更新
+    last_update: Mutex<Option<Instant>>,
+    loaded: AtomicBool,
+}
+
+impl ProxyProvider {
+    pub async fn from_config(
+        name: &str,
+        config: &ProxyProviderConfig,
+        manager: &Arc<ProxyManager>,
+        runtime: &Arc<ProxyRuntime>,
+    ) -> Result<Self, String> {
+        let vehicle = Vehicle::new(
+            &config.provider_type,
+            &config.path,
+            config.url.as_deref(),
+            config.proxy.as_deref(),
+            manager,
+        )?;
+        // 首次下载失败又没有缓存时先以空列表启动，后台每次检查都重试，不让整个程序起不来
+        let (proxies, stale) = match vehicle.load(|content| parse(name, content)).await {
+            Ok((proxies, stale)) => (Some(proxies), stale),
+            Err(e) => {
+                eprintln!("[ProxyProvider] {}: {}, starting empty and retrying in background", name, e);
+                (None, true)
+            }
+        };
+
+        let provider = Self {
+            name: name.to_string(),
+            vehicle,
+            interval: config.interval.filter(|&secs| secs > 0).map(Duration::from_secs),
+            manager: manager.clone(),
+            runtime: runtime.clone(),
+            last_update: Mutex::new((!stale).then(Instant::now)),
+            loaded: AtomicBool::new(false),
+        };
+        provider.apply(proxies.as_deref().unwrap_or_default());
+        provider.loaded.store(proxies.is_some(), Ordering::Relaxed);
+        Ok(provider)
+    }
+
+    pub fn start_refresh(self: &Arc<Self>) {
+        let provider = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(FILE_CHECK_INTERVAL);
+            loop {
+                ticker.tick().await;
+                provider.refresh().await;
+            }
+        });
+    }
+
+    async fn refresh(&self) {
+        let due = match *self.last_update.lock().unwrap() {
+            Some(last) => self.interval.is_some_and(|interval| last.elapsed() >= interval),
+            None => true,
+        };
+        // HTTP 只按 interval 重新下载，文件每次都检查修改时间
+        if !due && !self.vehicle.is_file() {
+            return;
+        }
+        if due {
+            *self.last_update.lock().unwrap() = Some(Instant::now());
+        }
+
+        match self.vehicle.update(due, |content| parse(&self.name, content)).await {
+            Ok(None) => {}
+            Ok(Some(proxies)) => {
+                self.apply(&proxies);
+                self.loaded.store(true, Ordering::Relaxed);
+            }
+            Err(e) => {
+                eprintln!("[ProxyProvider] {}: update failed, keeping previous proxies: {}", self.name, e);
+                // 还没加载成功过就在下次检查时继续重试，不等 interval
+                if !self.loaded.load(Ordering::Relaxed) {
+                    *self.last_update.lock().unwrap() = None;
+                }
+            }
+        }
+    }
+
+    fn apply(&self, proxies: &[Proxy]) {
+        let names = self.manager.replace_provider(&self.name, proxies);
+        println!("[ProxyProvider] {}: loaded {} proxies", self.name, names.len());
+        self.runtime.set_provider_proxies(&self.name, names);
+    }
+}
+
+// 没有可用节点的内容视为无效，避免一次错误的订阅清空所有节点
+fn parse(name: &str, content: &str) -> Result<Vec<Proxy>, String> {
+    let list: ProxyList =
+        serde_yaml::from_str(content).map_err(|e| format!("invalid proxy provider {}: {}", name, e))?;
+    let proxies: Vec<Proxy> = list.proxies.into_iter().filter(|p| p.name().is_some()).collect();
+    if proxies.is_empty() {
+        return Err(format!("proxy provider {} has no supported proxies", name));
+    }
+    Ok(proxies)
+}
@@ -1,71 +1,330 @@
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::io;
+use std::sync::{Arc, RwLock};
 
-use crate::proxy::fake::FakeProxy;
-use crate::proxy::outbound::{AnyStream, OutboundHandler};
+use crate::proxy::outbound::{self, AnyDatagram, AnyStream, OutboundHandler};
 use crate::config::{Config, Proxy};
+use crate::dns::Resolver;
 use crate::proxy::direct::DirectProxy;
+use crate::proxy::reject::RejectProxy;
+use crate::proxy::runtime::{ProxyGroup, ProxyRuntime};
 use crate::proxy::vmess::VmessProxy;
 use crate::proxy::trojan::TrojanProxy;
+use crate::rule::Metadata;
 use uuid::Uuid;
 
 pub struct ProxyManager {
-    handlers: HashMap<String, Arc<dyn OutboundHandler>>,
+    // proxy-providers 更新时会替换其中的节点
+    handlers: RwLock<HashMap<String, Arc<dyn OutboundHandler>>>,
+    // provider 名 -> 它提供的节点名
+    provided: RwLock<HashMap<String, Vec<String>>>,
+    runtime: Arc<ProxyRuntime>,
 }
 
 impl ProxyManager {
-    pub fn new(config: &Config) -> Self {
+    pub fn new(config: &Config, runtime: Arc<ProxyRuntime>, resolver: Arc<Resolver>) -> Self {
         let mut handlers: HashMap<String, Arc<dyn OutboundHandler>> = HashMap::new();
 
         for proxy in &config.proxies {
-            match proxy {
-                Proxy::Trojan { name, server, port, password, sni } => {
-                        let handler = TrojanProxy::new(name.clone(), server.clone(), *port, password.clone(), sni.clone());
-                        handlers.insert(name.clone(), Arc::new(handler));
-                    }
+            let Some(name) = proxy.name() else { continue };
+            let handler = build_handler(proxy).unwrap_or_else(|e| panic!("Invalid proxy {}: {}", name, e));
+            handlers.insert(name.to_string(), handler);
+        }
+
+        if !handlers.contains_key("DIRECT") {
+            handlers.insert("DIRECT".into(), Arc::new(DirectProxy::new(resolver)));
+        }
+        if !handlers.contains_key("REJECT") {
+            handlers.insert("REJECT".into(), Arc::new(RejectProxy));
+        }
+
+        Self {
+            handlers: RwLock::new(handlers),
+            provided: RwLock::new(HashMap::new()),
+            runtime,
+        }
+    }
+
+    // 用 provider 的新节点列表替换它之前提供的节点，返回实际加入的节点名
+    pub fn replace_provider(&self, provider: &str, proxies: &[Proxy]) -> Vec<String> {
+        let mut handlers = self.handlers.write().unwrap();
+        let mut provided = self.provided.write().unwrap();
+        for old in provided.remove(provider).unwrap_or_default() {
+            handlers.remove(&old);
+        }
 
-                Proxy::VMess {
-                    name,
-                    server,
-                    port,
-                    uuid,
-                    alter_id,
-                    network,
-                    ws_path,
-                    ws_headers,
-                    ..
-                } => {
-                    let uuid = Uuid::parse_str(uuid).unwrap();
-                    let proxy = VmessProxy {
-                        name: name.clone(),
-                        server: server.clone(),
-                        port: *port,
-                        uuid,
-                        alter_id: alter_id.unwrap_or(0),
-                        network: network.clone(),
-                        ws_path: ws_path.clone(),
-                        ws_host: ws_headers.as_ref().and_then(|h| h.get("Host").cloned()),
-                        
-                    };
-                    handlers.insert(name.clone(), Arc::new(proxy));
+        let mut names = Vec::new();
+        for proxy in proxies {
+            let Some(name) = proxy.name() else { continue };
+            if handlers.contains_key(name) || self.runtime.get_group(name).is_some() {
+                eprintln!("[ProxyProvider] {}: skipping {}, the name is already in use", provider, name);
+                continue;
+            }
+            match build_handler(proxy) {
+                Ok(handler) => {
+                    handlers.insert(name.to_string(), handler);
+                    names.push(name.to_string());
                 }
+                Err(e) => eprintln!("[ProxyProvider] {}: skipping {}: {}", provider, name, e),
+            }
+        }
+        provided.insert(provider.to_string(), names.clone());
+        names
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.handlers.read().unwrap().contains_key(name)
+    }
+
+    // 按策略名建立连接，代理组按各自类型选择成员
+    pub async fn connect(&self, name: &str, meta: &Metadata) -> io::Result<AnyStream> {
+        let Some(group) = self.runtime.get_group(name) else {
+            let handler = self.handler(name)?;
+            return handler.connect(&meta.host, meta.dst_port).await;
+        };
 
-                Proxy::Unknown => {}
+        match group.group_type() {
+            "fallback" => self.connect_fallback(&group, meta).await,
+            "relay" => self.connect_relay(&group, meta).await,
+            "load-balance" => {
+                let member = group
+                    .pick_balanced(meta, |member| self.runtime.delay(group.name(), member))
+                    .ok_or_else(|| io::Error::other(format!("group {} has no proxies", name)))?;
+                println!("[ProxyManager] {} ({}) -> {}", name, group.strategy(), member);
+                Box::pin(self.connect(&member, meta)).await
+            }
+            _ => {
+                let member = group.get();
+                println!("[ProxyManager] {} -> {}", name, member);
+                Box::pin(self.connect(&member, meta)).await
             }
         }
+    }
 
-        if !handlers.contains_key("DIRECT") {
-            handlers.insert("DIRECT".into(), Arc::new(DirectProxy));
+    // 只沿代理组解析出最终节点名，不建立连接（UDP 会话按节点复用）
+    pub fn resolve_proxy(&self, name: &str, meta: &Metadata) -> io::Result<String> {
+        let Some(group) = self.runtime.get_group(name) else {
+            if !self.contains(name) {
+                return Err(io::Error::new(io::ErrorKind::NotFound, format!("No proxy or group named {}", name)));
+            }
+            return Ok(name.to_string());
+        };
+        if group.group_type() == "relay" {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("relay group {} has no single proxy", name),
+            ));
+        }
+        let member = self.pick_member(&group, meta)?;
+        self.resolve_proxy(&member, meta)
+    }
+
+    // 健康检查用：沿各组当前的选择连到最终节点，relay 组测整条链路；
+    // 不像 connect 那样让 fallback 组切换节点或记录延迟，测量结果只由调用方记录
+    pub async fn probe(&self, name: &str, meta: &Metadata) -> io::Result<AnyStream> {
+        let Some(group) = self.runtime.get_group(name) else {
+            return self.handler(name)?.connect(&meta.host, meta.dst_port).await;
+        };
+        if group.group_type() == "relay" {
+            return self.connect_relay(&group, meta).await;
+        }
+        let member = self.pick_member(&group, meta)?;
+        Box::pin(self.probe(&member, meta)).await
+    }
+
+    // 非 relay 组本次使用的成员
+    fn pick_member(&self, group: &ProxyGroup, meta: &Metadata) -> io::Result<String> {
+        match group.group_type() {
+            "load-balance" => group
+                .pick_balanced(meta, |member| self.runtime.delay(group.name(), member))
+                .ok_or_else(|| io::Error::other(format!("group {} has no proxies", group.name()))),
+            _ => Ok(group.get()),
+        }
+    }
+
+    pub async fn bind_udp(&self, name: &str) -> io::Result<AnyDatagram> {
+        self.handler(name)?.bind_udp().await
+    }
+
+    fn handler(&self, name: &str) -> io::Result<Arc<dyn OutboundHandler>> {
+        self.handlers.read().unwrap().get(name).cloned().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("No proxy or group named {}", name))
+        })
+    }
+
+    // 第一跳直接拨号，之后每一跳都在上一跳的流上完成握手，直到最终目标。
+    // 组成员按本次连接做选择（load-balance 按目标分配），不是固定取组的当前节点
+    async fn connect_relay(&self, group: &ProxyGroup, meta: &Metadata) -> io::Result<AnyStream> {
+        let names = group
+            .proxies()
+            .iter()
+            .map(|member| self.resolve_proxy(member, meta))
+            .collect::<io::Result<Vec<_>>>()?;
+        let hops = names
+            .iter()
+            .map(|name| self.handler(name))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        // 每一跳要去的地址：后面第一个有服务器地址的节点，没有则为最终目标
+        let target_of = |index: usize| {
+            hops[index + 1..]
+                .iter()
+                .find_map(|hop| hop.server_addr())
+                .unwrap_or_else(|| (meta.host.clone(), meta.dst_port))
+        };
+
+        println!("[Relay] {} -> {}", group.name(), names.join(" -> "));
+        let (address, port) = target_of(0);
+        let first = hops[0].connect(&address, port).await;
+        // 第一跳连的是下一跳服务器时，连不上是链路的问题而不是目标的问题
+        let mut stream = if address == meta.host && port == meta.dst_port {
+            first?
+        } else {
+            first.map_err(outbound::proxy_error)?
+        };
+        for (index, hop) in hops.iter().enumerate().skip(1) {
+            let (address, port) = target_of(index);
+            stream = hop.connect_over(stream, &address, port).await?;
+        }
+        Ok(stream)
+    }
+
+    // 当前节点连接失败时按顺序尝试其余成员；目标本身连不上时直接返回，不切换也不记为不可用
+    async fn connect_fallback(&self, group: &ProxyGroup, meta: &Metadata) -> io::Result<AnyStream> {
+        let current = group.get();
+        let members = group.proxies();
+        let candidates = std::iter::once(&current).chain(members.iter().filter(|p| **p != current));
+
+        let mut last_err = None;
+        for member in candidates {
+            println!("[ProxyManager] {} -> {}", group.name(), member);
+            match Box::pin(self.connect(member, meta)).await {
+                Ok(stream) => {
+                    if *member != current {
+                        println!("[Fallback] {} failed over {} -> {}", group.name(), current, member);
+                        group.fail_over(member);
+                    }
+                    return Ok(stream);
+                }
+                Err(e) if outbound::is_destination_error(&e) => return Err(e),
+                Err(e) => {
+                    println!("[Fallback] {} / {} failed: {}", group.name(), member, e);
+                    self.runtime.record_delay(group.name(), member, None);
+                    last_err = Some(e);
+                }
+            }
+        }
+
+        Err(last_err.unwrap_or_else(|| io::Error::other(format!("group {} has no proxies", group.name()))))
+    }
+}
+
+fn build_handler(proxy: &Proxy) -> Result<Arc<dyn OutboundHandler>, String> {
+    match proxy {
+        Proxy::Trojan { name, server, port, password, sni } => Ok(Arc::new(TrojanProxy::new(
+            name.clone(),
+            server.clone(),
+            *port,
+            password.clone(),
+            sni.clone(),
+        ))),
+        Proxy::VMess {
+            name,
+            server,
+            port,
+            uuid,
+            alter_id,
+            cipher,
+            udp,
+            network,
+            ws_path,
+            ws_headers,
+        } => {
+            let uuid = Uuid::parse_str(uuid).map_err(|e| format!("invalid uuid: {}", e))?;
+            if let Some(cipher) = cipher.as_deref().filter(|c| !matches!(*c, "auto" | "aes-128-gcm")) {
+                eprintln!("[VMess] {}: cipher {} is not supported, using aes-128-gcm", name, cipher);
+            }
+            Ok(Arc::new(VmessProxy::new(
+                name.clone(),
+                server.clone(),
+                *port,
+                uuid,
+                alter_id.unwrap_or(0),
+                network.clone(),
+                ws_path.clone(),
+                ws_headers.clone(),
+                udp.unwrap_or(true),
+            )))
+        }
+        Proxy::Unknown => Err("unsupported proxy type".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::proxy::runtime::Mode;
+
+    fn setup(groups: &str) -> (Arc<ProxyRuntime>, ProxyManager) {
+        let yaml = format!("proxies: []\nproxy-groups: {}\nrules: []\n", groups);
+        let config: Config = serde_yaml::from_str(&yaml).unwrap();
+        let runtime = Arc::new(ProxyRuntime::new(Mode::Rule));
+        for group in &config.proxy_groups {
+            runtime.register_group(ProxyGroup::from_config(group).unwrap());
         }
+        let resolver = Arc::new(Resolver::new(None, &serde_yaml::Mapping::new()).unwrap());
+        let manager = ProxyManager::new(&config, runtime.clone(), resolver);
+        (runtime, manager)
+    }
+
+    // 刚释放的本地端口，连接会被拒绝
+    async fn closed_port() -> u16 {
+        TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().port()
+    }
+
+    #[tokio::test]
+    async fn fallback_ignores_destination_errors() {
+        let (runtime, manager) = setup("[{ name: fb, type: fallback, proxies: [DIRECT, REJECT] }]");
+        runtime.record_delay("fb", "DIRECT", Some(Duration::from_millis(10)));
 
-        Self { handlers }
+        let meta = Metadata::new("127.0.0.1", closed_port().await, None);
+        let err = manager.connect("fb", &meta).await.err().unwrap();
+        // 没有轮到 REJECT，DIRECT 也没被记为不可用
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        assert_eq!(runtime.get_group("fb").unwrap().get(), "DIRECT");
+        assert!(runtime.delay("fb", "DIRECT").is_some());
     }
 
-    pub fn get(&self, name: &str) -> Option<Arc<dyn OutboundHandler>> {
-        self.handlers.get(name).cloned()
+    #[tokio::test]
+    async fn fallback_fails_over_on_proxy_errors() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // broken 链路的第二跳不能中继，属于代理本身的问题
+        let (runtime, manager) = setup(
+            "[{ name: broken, type: relay, proxies: [DIRECT, REJECT] }, { name: fb, type: fallback, proxies: [broken, DIRECT] }]",
+        );
+
+        let meta = Metadata::new("127.0.0.1", port, None);
+        assert!(manager.connect("fb", &meta).await.is_ok());
+        assert_eq!(runtime.get_group("fb").unwrap().get(), "DIRECT");
+        assert_eq!(runtime.delay("fb", "broken"), None);
     }
 
-    pub fn first(&self) -> Option<Arc<dyn OutboundHandler>> {
-        self.handlers.values().next().cloned()
+    #[tokio::test]
+    async fn relay_hops_go_through_group_selection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // lb 的静态第一个成员是 REJECT，只有 DIRECT 健康，按连接选择应当选到 DIRECT
+        let (runtime, manager) = setup(
+            "[{ name: lb, type: load-balance, proxies: [REJECT, DIRECT] }, { name: chain, type: relay, proxies: [lb] }]",
+        );
+        runtime.record_delay("lb", "DIRECT", Some(Duration::from_millis(10)));
+
+        let meta = Metadata::new("127.0.0.1", port, None);
+        assert!(manager.connect("chain", &meta).await.is_ok());
     }
 }
@@ -0,0 +1,71 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use socket2::SockRef;
+use tokio::net::{TcpListener, TcpStream};
+use crate::proxy::dispatcher::Dispatcher;
+use crate::rule::Metadata;
+
+// iptables -t nat ... -j REDIRECT 过来的连接，原始目标由 SO_ORIGINAL_DST 取回
+pub async fn start_redir_server(
+    addr: &str,
+    dispatcher: Arc<Dispatcher>,
+) -> io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    println!("[Redir] Listening on {}", addr);
+
+    loop {
+        let (stream, peer_addr) = listener.accept().await?;
+        if !dispatcher.permits(peer_addr.ip()) {
+            println!("[Redir] Rejected connection from {}", peer_addr);
+            continue;
+        }
+        let dispatcher = dispatcher.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = handle_client(stream, peer_addr, dispatcher).await {
+                eprintln!("[Redir] Error from {}: {:?}", peer_addr, e);
+            }
+        });
+    }
+}
+
+async fn handle_client(
+    mut client: TcpStream,
+    peer_addr: SocketAddr,
+    dispatcher: Arc<Dispatcher>,
+) -> io::Result<()> {
+    let peer_addr = SocketAddr::new(peer_addr.ip().to_canonical(), peer_addr.port());
+    let target = original_dst(&client)?;
+    // 没经过 REDIRECT 的连接原始目标就是监听地址本身，转发会自环
+    let local = client.local_addr()?;
+    if target.ip().to_canonical() == local.ip().to_canonical() && target.port() == local.port() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "connection was not redirected"));
+    }
+
+    println!("[Redir] {} -> {}", peer_addr, target);
+    let metadata = Metadata::new(&target.ip().to_canonical().to_string(), target.port(), Some(peer_addr));
+    let mut remote = dispatcher.connect(&metadata).await?;
+
+    let (n1, n2) = tokio::io::copy_bidirectional(&mut client, &mut remote).await?;
+    println!("[Redir] Relay complete: client → remote = {} bytes, remote → client = {} bytes", n1, n2);
+
+    Ok(())
+}
+
+fn original_dst(stream: &TcpStream) -> io::Result<SocketAddr> {
+    let socket = SockRef::from(stream);
+    let addr = if stream.local_addr()?.is_ipv6() {
+        // 双栈监听时 IPv4 连接仍走 SOL_IP
+        socket.original_dst_ipv6().or_else(|_| socket.original_dst())
+    } else {
+        socket.original_dst()
+    }
+    .map_err(|e| match e.kind() {
+        // conntrack 里没有这条连接
+        io::ErrorKind::NotFound => io::Error::new(io::ErrorKind::NotFound, "no original destination, connection was not redirected"),
+        _ => e,
+    })?;
+    addr.as_socket()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "original destination is not an IP address"))
+}
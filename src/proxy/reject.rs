@@ -0,0 +1,13 @@
+use async_trait::async_trait;
+use std::io;
+use crate::proxy::outbound::{OutboundHandler, AnyStream};
+
+pub struct RejectProxy;
+
+#[async_trait]
+impl OutboundHandler for RejectProxy {
+    async fn connect(&self, address: &str, port: u16) -> io::Result<AnyStream> {
+        println!("[RejectProxy] Rejected {}:{}", address, port);
+        Err(io::Error::new(io::ErrorKind::PermissionDenied, "rejected by rule"))
+    }
+}
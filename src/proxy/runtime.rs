@@ -1,48 +1,376 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use crate::config;
+use crate::proxy::health::HealthCheck;
+use crate::proxy::load_balance::{self, Strategy};
+use crate::rule::Metadata;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Rule,
+    Global,
+    Direct,
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "rule" => Ok(Mode::Rule),
+            "global" => Ok(Mode::Global),
+            "direct" => Ok(Mode::Direct),
+            other => Err(format!("unknown mode: {}", other)),
+        }
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Mode::Rule => "rule",
+            Mode::Global => "global",
+            Mode::Direct => "direct",
+        };
+        f.write_str(name)
+    }
+}
 
 #[derive(Clone)]
 pub struct ProxyGroup {
+    name: String,
+    group_type: String,
+    // proxy-providers 更新时整体替换
+    proxies: Arc<RwLock<Arc<Vec<String>>>>,
+    // 配置里直接写的成员和 use 引用的 provider
+    static_proxies: Arc<Vec<String>>,
+    providers: Arc<Vec<String>>,
     current: Arc<RwLock<String>>,
+    health_check: Option<HealthCheck>,
+    strategy: Strategy,
+    round_robin: Arc<AtomicUsize>,
 }
 
 impl ProxyGroup {
-    pub fn new(default: &str) -> Self {
+    pub fn new(name: &str, group_type: &str, proxies: Vec<String>) -> Self {
+        // 没有成员（proxy-provider 还没下载成功）时拒绝连接，不能悄悄变成直连
+        let default = proxies.first().cloned().unwrap_or_else(|| "REJECT".to_string());
         Self {
-            current: Arc::new(RwLock::new(default.to_string())),
+            name: name.to_string(),
+            group_type: group_type.to_string(),
+            proxies: Arc::new(RwLock::new(Arc::new(proxies.clone()))),
+            static_proxies: Arc::new(proxies),
+            providers: Arc::new(Vec::new()),
+            current: Arc::new(RwLock::new(default)),
+            health_check: None,
+            strategy: Strategy::ConsistentHashing,
+            round_robin: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn from_config(group: &config::ProxyGroup) -> Result<Self, String> {
+        let mut proxy_group =
+            Self::new(&group.name, &group.group_type, group.proxies.clone()).with_providers(group.uses.clone());
+        if matches!(proxy_group.group_type.as_str(), "url-test" | "fallback" | "load-balance") {
+            proxy_group.health_check = Some(HealthCheck::from_config(group));
+        }
+        if let Some(strategy) = &group.strategy {
+            proxy_group.strategy = strategy.parse()?;
+        }
+        Ok(proxy_group)
+    }
+
+    pub fn with_providers(mut self, providers: Vec<String>) -> Self {
+        self.providers = Arc::new(providers);
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn group_type(&self) -> &str {
+        &self.group_type
+    }
+
+    pub fn proxies(&self) -> Arc<Vec<String>> {
+        self.proxies.read().unwrap().clone()
+    }
+
+    pub fn providers(&self) -> &[String] {
+        &self.providers
+    }
+
+    // 当前节点不在新成员里时改选第一个
+    fn set_proxies(&self, proxies: Vec<String>) {
+        let mut current = self.current.write().unwrap();
+        if !proxies.contains(&current) {
+            *current = proxies.first().cloned().unwrap_or_else(|| "REJECT".to_string());
         }
+        *self.proxies.write().unwrap() = Arc::new(proxies);
+    }
+
+    pub fn health_check(&self) -> Option<&HealthCheck> {
+        self.health_check.as_ref()
+    }
+
+    pub fn strategy(&self) -> Strategy {
+        self.strategy
     }
 
     pub fn get(&self) -> String {
         self.current.read().unwrap().clone()
     }
 
-    pub fn set(&self, name: &str) {
+    pub fn set(&self, name: &str) -> Result<(), String> {
+        if self.group_type != "select" {
+            return Err(format!("group {} is {}, it cannot be switched manually", self.name, self.group_type));
+        }
+        if !self.proxies().iter().any(|p| p == name) {
+            return Err(format!("{} is not a member of group {}", name, self.name));
+        }
+        *self.current.write().unwrap() = name.to_string();
+        Ok(())
+    }
+
+    // load-balance 组按连接选择成员，只在健康的成员之间分配
+    pub fn pick_balanced(&self, meta: &Metadata, delay_of: impl Fn(&str) -> Option<Duration>) -> Option<String> {
+        let proxies = self.proxies();
+        let alive: Vec<&String> = proxies.iter().filter(|p| delay_of(p).is_some()).collect();
+        let candidates = if alive.is_empty() { proxies.iter().collect() } else { alive };
+        if candidates.is_empty() {
+            return None;
+        }
+
+        match self.strategy {
+            Strategy::ConsistentHashing => {
+                load_balance::consistent_hash(&load_balance::sticky_key(meta), &candidates).cloned()
+            }
+            Strategy::RoundRobin => {
+                let index = self.round_robin.fetch_add(1, Ordering::Relaxed) % candidates.len();
+                Some(candidates[index].clone())
+            }
+        }
+    }
+
+    pub fn fail_over(&self, name: &str) {
         *self.current.write().unwrap() = name.to_string();
     }
+
+    // 健康检查结束后根据延迟重新选择节点
+    pub fn auto_select(&self, delay_of: impl Fn(&str) -> Option<Duration>, tolerance: Duration) {
+        match self.group_type.as_str() {
+            "url-test" => self.select_fastest(delay_of, tolerance),
+            "fallback" => self.select_first_alive(delay_of),
+            _ => {}
+        }
+    }
+
+    fn select_first_alive(&self, delay_of: impl Fn(&str) -> Option<Duration>) {
+        if let Some(alive) = self.proxies().iter().find(|p| delay_of(p).is_some()) {
+            *self.current.write().unwrap() = alive.clone();
+        }
+    }
+
+    fn select_fastest(&self, delay_of: impl Fn(&str) -> Option<Duration>, tolerance: Duration) {
+        let proxies = self.proxies();
+        let Some((fastest, fastest_delay)) = proxies
+            .iter()
+            .filter_map(|p| delay_of(p).map(|d| (p, d)))
+            .min_by_key(|(_, d)| *d)
+        else {
+            return;
+        };
+
+        let mut current = self.current.write().unwrap();
+        // 当前节点仍在容差范围内时不切换，避免来回抖动
+        if let Some(current_delay) = delay_of(&current)
+            && current_delay <= fastest_delay + tolerance
+        {
+            return;
+        }
+        *current = fastest.clone();
+    }
 }
 
 #[derive(Clone)]
 pub struct ProxyRuntime {
     groups: Arc<RwLock<HashMap<String, ProxyGroup>>>,
+    order: Arc<RwLock<Vec<String>>>,
+    // (代理组, 成员) -> 该组最近一次测得的延迟，同一节点在不同组的测试地址可能不同
+    delays: Arc<RwLock<HashMap<(String, String), Duration>>>,
+    mode: Arc<RwLock<Mode>>,
+    // proxy-provider 名 -> 当前提供的节点名
+    provided: Arc<RwLock<HashMap<String, Vec<String>>>>,
 }
 
 impl ProxyRuntime {
-    pub fn new() -> Self {
+    pub fn new(mode: Mode) -> Self {
         Self {
             groups: Arc::new(RwLock::new(HashMap::new())),
+            order: Arc::new(RwLock::new(Vec::new())),
+            delays: Arc::new(RwLock::new(HashMap::new())),
+            mode: Arc::new(RwLock::new(mode)),
+            provided: Arc::new(RwLock::new(HashMap::new())),
         }
     }
 
-    pub fn register_group(&self, group_name: &str, default: &str) {
-        self.groups
-            .write()
-            .unwrap()
-            .insert(group_name.to_string(), ProxyGroup::new(default));
+    pub fn mode(&self) -> Mode {
+        *self.mode.read().unwrap()
+    }
+
+    pub fn set_mode(&self, mode: Mode) {
+        *self.mode.write().unwrap() = mode;
+    }
+
+    // None 表示最近一次检查失败
+    pub fn record_delay(&self, group: &str, member: &str, delay: Option<Duration>) {
+        let mut delays = self.delays.write().unwrap();
+        let key = (group.to_string(), member.to_string());
+        match delay {
+            Some(delay) => {
+                delays.insert(key, delay);
+            }
+            None => {
+                delays.remove(&key);
+            }
+        }
+    }
+
+    pub fn delay(&self, group: &str, member: &str) -> Option<Duration> {
+        self.delays.read().unwrap().get(&(group.to_string(), member.to_string())).copied()
+    }
+
+    pub fn register_group(&self, group: ProxyGroup) {
+        let name = group.name().to_string();
+        if self.groups.write().unwrap().insert(name.clone(), group).is_none() {
+            self.order.write().unwrap().push(name);
+        }
     }
 
     pub fn get_group(&self, name: &str) -> Option<ProxyGroup> {
         self.groups.read().unwrap().get(name).cloned()
     }
+
+    // 按注册（配置文件）顺序返回全部代理组
+    pub fn groups(&self) -> Vec<ProxyGroup> {
+        let groups = self.groups.read().unwrap();
+        self.order
+            .read()
+            .unwrap()
+            .iter()
+            .filter_map(|name| groups.get(name).cloned())
+            .collect()
+    }
+
+    // provider 节点列表变化后，重新计算引用它的各组成员
+    pub fn set_provider_proxies(&self, provider: &str, names: Vec<String>) {
+        let mut provided = self.provided.write().unwrap();
+        provided.insert(provider.to_string(), names);
+        for group in self.groups.read().unwrap().values() {
+            if !group.providers.iter().any(|p| p == provider) {
+                continue;
+            }
+            let members = group
+                .static_proxies
+                .iter()
+                .chain(group.providers.iter().filter_map(|p| provided.get(p)).flatten())
+                .cloned()
+                .collect();
+            group.set_proxies(members);
+        }
+    }
+
+    // 启动时检查所有组成员是否存在，以及组之间的引用是否成环
+    pub fn check_references(&self, is_proxy: impl Fn(&str) -> bool) -> Result<(), String> {
+        let groups = self.groups.read().unwrap();
+        let provided = self.provided.read().unwrap();
+
+        for group in groups.values() {
+            if let Some(provider) = group.providers().iter().find(|p| !provided.contains_key(*p)) {
+                return Err(format!("proxy-group {} uses unknown proxy-provider {}", group.name(), provider));
+            }
+            // 只靠 provider 提供成员的组可以暂时为空，provider 在后台重试下载
+            if group.proxies().is_empty() && group.providers().is_empty() {
+                return Err(format!("proxy-group {} has no proxies", group.name()));
+            }
+            for member in group.proxies().iter() {
+                if !groups.contains_key(member) && !is_proxy(member) {
+                    return Err(format!(
+                        "proxy-group {} references unknown proxy or group {}",
+                        group.name(),
+                        member
+                    ));
+                }
+            }
+        }
+
+        let mut done = HashSet::new();
+        for name in groups.keys() {
+            let mut path = Vec::new();
+            visit_group(&groups, name, &mut path, &mut done)?;
+        }
+        Ok(())
+    }
+}
+
+fn visit_group(
+    groups: &HashMap<String, ProxyGroup>,
+    name: &str,
+    path: &mut Vec<String>,
+    done: &mut HashSet<String>,
+) -> Result<(), String> {
+    if done.contains(name) {
+        return Ok(());
+    }
+    if let Some(pos) = path.iter().position(|n| n == name) {
+        let mut cycle = path[pos..].to_vec();
+        cycle.push(name.to_string());
+        return Err(format!("proxy-group cycle: {}", cycle.join(" -> ")));
+    }
+    let Some(group) = groups.get(name) else {
+        return Ok(());
+    };
+
+    path.push(name.to_string());
+    for member in group.proxies().iter() {
+        visit_group(groups, member, path, done)?;
+    }
+    path.pop();
+    done.insert(name.to_string());
+    Ok(())
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn delays(list: &[(&str, u64)]) -> impl Fn(&str) -> Option<Duration> {
+        let map: HashMap<String, Duration> =
+            list.iter().map(|(name, ms)| (name.to_string(), Duration::from_millis(*ms))).collect();
+        move |name| map.get(name).copied()
+    }
+
+    #[test]
+    fn url_test_keeps_current_within_tolerance() {
+        let group = ProxyGroup::new("auto", "url-test", vec!["a".to_string(), "b".to_string()]);
+        assert_eq!(group.get(), "a");
+        group.auto_select(delays(&[("a", 120), ("b", 100)]), Duration::from_millis(50));
+        assert_eq!(group.get(), "a");
+    }
+
+    #[test]
+    fn url_test_switches_outside_tolerance() {
+        let group = ProxyGroup::new("auto", "url-test", vec!["a".to_string(), "b".to_string()]);
+        group.auto_select(delays(&[("a", 120), ("b", 100)]), Duration::from_millis(10));
+        assert_eq!(group.get(), "b");
+        // 当前节点不可用时无论容差都切换
+        group.auto_select(delays(&[("a", 10)]), Duration::from_secs(1));
+        assert_eq!(group.get(), "a");
+    }
+}
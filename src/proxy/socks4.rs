@@ -0,0 +1,79 @@
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use crate::proxy::dispatcher::Dispatcher;
+use crate::rule::Metadata;
+
+const REPLY_GRANTED: u8 = 0x5A;
+const REPLY_REJECTED: u8 = 0x5B;
+
+// SOCKS4 / SOCKS4a 只支持 CONNECT，目前只由 mixed 端口接入
+pub async fn handle_client(
+    client: TcpStream,
+    peer_addr: SocketAddr,
+    dispatcher: Arc<Dispatcher>,
+) -> std::io::Result<()> {
+    let mut reader = BufReader::new(client);
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf).await?;
+    let command = buf[1];
+    let port = u16::from_be_bytes([buf[2], buf[3]]);
+    let ip = Ipv4Addr::new(buf[4], buf[5], buf[6], buf[7]);
+
+    // USERID 以 0 结尾，未做校验
+    read_null_terminated(&mut reader).await?;
+
+    // 0.0.0.x (x != 0) 表示 SOCKS4a，域名跟在 USERID 后面
+    let octets = ip.octets();
+    let addr = if octets[..3] == [0, 0, 0] && octets[3] != 0 {
+        read_null_terminated(&mut reader).await?
+    } else {
+        ip.to_string()
+    };
+
+    let mut client = reader.into_inner();
+    // SOCKS4 没有密码字段，配置了认证时一律拒绝
+    if dispatcher.authenticator().enabled() {
+        client.write_all(&reply(REPLY_REJECTED)).await?;
+        return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "SOCKS4 not allowed with authentication"));
+    }
+    if command != 0x01 {
+        client.write_all(&reply(REPLY_REJECTED)).await?;
+        return Err(std::io::Error::other("Only CONNECT supported"));
+    }
+
+    println!("[SOCKS4] Received request to connect to {}:{}", addr, port);
+    let metadata = Metadata::new(&addr, port, Some(peer_addr));
+    let mut remote = match dispatcher.connect(&metadata).await {
+        Ok(remote) => remote,
+        Err(e) => {
+            client.write_all(&reply(REPLY_REJECTED)).await?;
+            return Err(e);
+        }
+    };
+
+    client.write_all(&reply(REPLY_GRANTED)).await?;
+
+    let (n1, n2) = tokio::io::copy_bidirectional(&mut client, &mut remote).await?;
+    println!("[SOCKS4] Relay complete: client → remote = {} bytes, remote → client = {} bytes", n1, n2);
+
+    Ok(())
+}
+
+async fn read_null_terminated(reader: &mut BufReader<TcpStream>) -> std::io::Result<String> {
+    // USERID / 域名最多 255 字节加结尾的 NUL，读满还没有 NUL 就拒绝
+    let mut field = Vec::new();
+    let n = (&mut *reader).take(256).read_until(0, &mut field).await?;
+    if field.pop() != Some(0) {
+        if n == 256 {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "SOCKS4 field too long"));
+        }
+        return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "unterminated SOCKS4 field"));
+    }
+    Ok(String::from_utf8_lossy(&field).to_string())
+}
+
+fn reply(code: u8) -> [u8; 8] {
+    [0x00, code, 0, 0, 0, 0, 0, 0]
+}
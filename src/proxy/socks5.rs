@@ -1,79 +1,477 @@
 
-use std::sync::Arc;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::io::{AsyncReadExt, AsyncWriteExt, copy_bidirectional};
-use crate::proxy::proxy_manager::ProxyManager;
-use crate::proxy::runtime::ProxyRuntime;
-use crate::proxy::outbound::OutboundHandler;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use lru::LruCache;
+use crate::proxy::dispatcher::{Dispatcher, UdpRoute};
+use crate::proxy::outbound::AnyDatagram;
+use crate::proxy::socks_addr;
+use crate::rule::Metadata;
+
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_USER_PASS: u8 = 0x02;
+const METHOD_NO_ACCEPTABLE: u8 = 0xFF;
+const AUTH_VERSION: u8 = 0x01;
+
+const CMD_CONNECT: u8 = 0x01;
+const CMD_BIND: u8 = 0x02;
+const CMD_UDP_ASSOCIATE: u8 = 0x03;
+
+const REP_SUCCEEDED: u8 = 0x00;
+const REP_GENERAL_FAILURE: u8 = 0x01;
+const REP_NOT_ALLOWED: u8 = 0x02;
+const REP_NETWORK_UNREACHABLE: u8 = 0x03;
+const REP_HOST_UNREACHABLE: u8 = 0x04;
+const REP_CONNECTION_REFUSED: u8 = 0x05;
+const REP_TTL_EXPIRED: u8 = 0x06;
+const REP_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const REP_ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+
+const BIND_TIMEOUT: Duration = Duration::from_secs(60);
+// 每个 UDP 关联缓存的目标路由数
+const MAX_UDP_ROUTES: usize = 1024;
+// 等待规则匹配或出站绑定时，每个目标 / 出站最多排队的包
+const MAX_QUEUED_PACKETS: usize = 32;
 
 pub async fn start_socks5_server(
     addr: &str,
-    manager: Arc<ProxyManager>,
-    runtime: Arc<ProxyRuntime>,
+    dispatcher: Arc<Dispatcher>,
 ) -> std::io::Result<()> {
     let listener = TcpListener::bind(addr).await?;
     println!("[SOCKS5] Listening on {}", addr);
 
     loop {
         let (stream, peer_addr) = listener.accept().await?;
-        let manager = manager.clone();
-        let runtime = runtime.clone();
+        if !dispatcher.permits(peer_addr.ip()) {
+            println!("[SOCKS5] Rejected connection from {}", peer_addr);
+            continue;
+        }
+        let dispatcher = dispatcher.clone();
 
         tokio::spawn(async move {
-            if let Err(e) = handle_client(stream, manager, runtime).await {
+            if let Err(e) = handle_client(stream, peer_addr, dispatcher).await {
                 eprintln!("[SOCKS5] Error from {}: {:?}", peer_addr, e);
             }
         });
     }
 }
 
-async fn handle_client(
+pub async fn handle_client(
     mut client: TcpStream,
-    manager: Arc<ProxyManager>,
-    runtime: Arc<ProxyRuntime>,
+    peer_addr: SocketAddr,
+    dispatcher: Arc<Dispatcher>,
 ) -> std::io::Result<()> {
     let mut buf = [0u8; 262];
     client.read_exact(&mut buf[..2]).await?;
     let nmethods = buf[1] as usize;
     client.read_exact(&mut buf[..nmethods]).await?;
-    client.write_all(&[0x05, 0x00]).await?;
+    let methods = &buf[..nmethods];
 
-    client.read_exact(&mut buf[..4]).await?;
-    if buf[1] != 0x01 {
-        return Err(std::io::Error::new(std::io::ErrorKind::Other, "Only CONNECT supported"));
-    }
-
-    let addr = match buf[3] {
-        0x01 => {
-            client.read_exact(&mut buf[..4]).await?;
-            std::net::Ipv4Addr::from(<[u8; 4]>::try_from(&buf[..4]).unwrap()).to_string()
+    let user = if dispatcher.authenticator().enabled() {
+        if !methods.contains(&METHOD_USER_PASS) {
+            client.write_all(&[0x05, METHOD_NO_ACCEPTABLE]).await?;
+            return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "client offered no username/password auth"));
         }
-        0x03 => {
-            client.read_exact(&mut buf[..1]).await?;
-            let len = buf[0] as usize;
-            client.read_exact(&mut buf[..len]).await?;
-            String::from_utf8_lossy(&buf[..len]).to_string()
+        client.write_all(&[0x05, METHOD_USER_PASS]).await?;
+        Some(authenticate(&mut client, &dispatcher).await?)
+    } else {
+        if !methods.contains(&METHOD_NO_AUTH) {
+            client.write_all(&[0x05, METHOD_NO_ACCEPTABLE]).await?;
+            return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "client offered no acceptable auth method"));
         }
-        _ => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Address type not supported")),
+        client.write_all(&[0x05, METHOD_NO_AUTH]).await?;
+        None
     };
 
-    client.read_exact(&mut buf[..2]).await?;
-    let port = u16::from_be_bytes(buf[..2].try_into().unwrap());
+    client.read_exact(&mut buf[..4]).await?;
+    let (cmd, atyp) = (buf[1], buf[3]);
+    if cmd != CMD_CONNECT && cmd != CMD_BIND && cmd != CMD_UDP_ASSOCIATE {
+        reply(&mut client, REP_COMMAND_NOT_SUPPORTED, unspecified()).await?;
+        return Err(std::io::Error::other("Command not supported"));
+    }
+    if !matches!(atyp, socks_addr::ATYP_IPV4 | socks_addr::ATYP_DOMAIN | socks_addr::ATYP_IPV6) {
+        reply(&mut client, REP_ADDRESS_NOT_SUPPORTED, unspecified()).await?;
+        return Err(std::io::Error::other("Address type not supported"));
+    }
+    let (addr, port) = socks_addr::read_with_type(&mut client, atyp).await?;
+
+    if cmd == CMD_UDP_ASSOCIATE {
+        return handle_udp_associate(client, peer_addr, dispatcher, user).await;
+    }
+
+    let metadata = Metadata::new(&addr, port, Some(peer_addr)).with_user(user);
+    if cmd == CMD_BIND {
+        return handle_bind(client, peer_addr, dispatcher, metadata).await;
+    }
 
-    let current_group = runtime
-        .get_group("🔰国外流量")
-        .expect("[socks5] runtime missing Proxy group");
     println!("[SOCKS5] Received request to connect to {}:{}", addr, port);
-    let current_name = current_group.get();
-    println!("[SOCKS5] 当前使用节点：{}", current_name);
-    let handler = manager.get(&current_name)
-        .unwrap_or_else(|| panic!("[SOCKS5] No handler found for proxy: {}", current_name));
-    let mut remote = handler.connect(&addr, port).await?;
+    let mut remote = match dispatcher.connect(&metadata).await {
+        Ok(remote) => remote,
+        Err(e) => {
+            reply(&mut client, reply_code(&e), unspecified()).await?;
+            return Err(e);
+        }
+    };
 
-    client.write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).await?;
+    // 出站流拿不到本地地址，回复客户端连入的本机地址
+    let bound = client.local_addr()?;
+    reply(&mut client, REP_SUCCEEDED, bound).await?;
 
     let (n1, n2) = tokio::io::copy_bidirectional(&mut client, &mut remote).await?;
     println!("[SOCKS5] Relay complete: client → remote = {} bytes, remote → client = {} bytes", n1, n2);
 
     Ok(())
 }
+
+// BIND 用于 FTP 主动模式等由目标主机反向连入的协议，只能在本机监听
+async fn handle_bind(
+    mut client: TcpStream,
+    peer_addr: SocketAddr,
+    dispatcher: Arc<Dispatcher>,
+    metadata: Metadata,
+) -> std::io::Result<()> {
+    if !dispatcher.routes_direct(&metadata).await {
+        reply(&mut client, REP_NOT_ALLOWED, unspecified()).await?;
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            format!("BIND for {}:{} is not routed to DIRECT", metadata.host, metadata.dst_port),
+        ));
+    }
+
+    let listener = TcpListener::bind(SocketAddr::new(client.local_addr()?.ip(), 0)).await?;
+    let bound = listener.local_addr()?;
+    reply(&mut client, REP_SUCCEEDED, bound).await?;
+    println!("[SOCKS5] BIND for {} on {}, expecting {}", peer_addr, bound, metadata.host);
+
+    let (mut remote, remote_addr) = match tokio::time::timeout(BIND_TIMEOUT, listener.accept()).await {
+        Ok(Ok(accepted)) => accepted,
+        Ok(Err(e)) => {
+            reply(&mut client, REP_GENERAL_FAILURE, unspecified()).await?;
+            return Err(e);
+        }
+        Err(_) => {
+            reply(&mut client, REP_TTL_EXPIRED, unspecified()).await?;
+            return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "BIND timed out"));
+        }
+    };
+    drop(listener);
+
+    // DST 是 IP 时只接受该主机连入
+    if let Some(expected) = metadata.dst_ip
+        && !expected.is_unspecified()
+        && expected.to_canonical() != remote_addr.ip().to_canonical()
+    {
+        reply(&mut client, REP_NOT_ALLOWED, unspecified()).await?;
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            format!("BIND got connection from {}, expected {}", remote_addr, expected),
+        ));
+    }
+
+    reply(&mut client, REP_SUCCEEDED, remote_addr).await?;
+    println!("[SOCKS5] BIND for {} accepted {}", peer_addr, remote_addr);
+
+    let (n1, n2) = tokio::io::copy_bidirectional(&mut client, &mut remote).await?;
+    println!("[SOCKS5] BIND relay complete: client → remote = {} bytes, remote → client = {} bytes", n1, n2);
+
+    Ok(())
+}
+
+// RFC 1929: VER ULEN UNAME PLEN PASSWD，返回认证通过的用户名
+async fn authenticate(client: &mut TcpStream, dispatcher: &Dispatcher) -> std::io::Result<String> {
+    let mut buf = [0u8; 255];
+    client.read_exact(&mut buf[..2]).await?;
+    if buf[0] != AUTH_VERSION {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "bad username/password auth version"));
+    }
+    let ulen = buf[1] as usize;
+    client.read_exact(&mut buf[..ulen]).await?;
+    let user = String::from_utf8_lossy(&buf[..ulen]).to_string();
+    let plen = client.read_u8().await? as usize;
+    client.read_exact(&mut buf[..plen]).await?;
+    let pass = String::from_utf8_lossy(&buf[..plen]).to_string();
+
+    if !dispatcher.authenticator().verify(&user, &pass) {
+        client.write_all(&[AUTH_VERSION, 0x01]).await?;
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            format!("authentication failed for user {}", user),
+        ));
+    }
+    client.write_all(&[AUTH_VERSION, 0x00]).await?;
+    println!("[SOCKS5] User {} authenticated", user);
+    Ok(user)
+}
+
+async fn reply(client: &mut TcpStream, rep: u8, bound: SocketAddr) -> std::io::Result<()> {
+    let mut packet = vec![0x05, rep, 0x00];
+    socks_addr::encode(&bound.ip().to_canonical().to_string(), bound.port(), &mut packet);
+    client.write_all(&packet).await
+}
+
+// 出站错误映射到 RFC 1928 的 REP，REJECT 规则返回的是 PermissionDenied
+fn reply_code(e: &std::io::Error) -> u8 {
+    use std::io::ErrorKind;
+    match e.kind() {
+        ErrorKind::PermissionDenied => REP_NOT_ALLOWED,
+        ErrorKind::NetworkUnreachable => REP_NETWORK_UNREACHABLE,
+        ErrorKind::HostUnreachable => REP_HOST_UNREACHABLE,
+        ErrorKind::ConnectionRefused => REP_CONNECTION_REFUSED,
+        ErrorKind::TimedOut => REP_TTL_EXPIRED,
+        _ => REP_GENERAL_FAILURE,
+    }
+}
+
+fn unspecified() -> SocketAddr {
+    SocketAddr::from(([0, 0, 0, 0], 0))
+}
+
+async fn handle_udp_associate(
+    mut client: TcpStream,
+    peer_addr: SocketAddr,
+    dispatcher: Arc<Dispatcher>,
+    user: Option<String>,
+) -> std::io::Result<()> {
+    // 在接受控制连接的同一地址上开 UDP 端口，客户端才能连得到
+    let socket = Arc::new(UdpSocket::bind(SocketAddr::new(client.local_addr()?.ip(), 0)).await?);
+    let bound = socket.local_addr()?;
+
+    reply(&mut client, REP_SUCCEEDED, bound).await?;
+    println!("[SOCKS5] UDP associate for {} on {}", peer_addr, bound);
+
+    let (events, events_rx) = mpsc::unbounded_channel();
+    let mut session = UdpSession {
+        socket,
+        peer_ip: peer_addr.ip().to_canonical(),
+        peer_addr,
+        user,
+        client_addr: Arc::new(Mutex::new(None)),
+        dispatcher,
+        routes: LruCache::new(NonZeroUsize::new(MAX_UDP_ROUTES).unwrap()),
+        routing: HashMap::new(),
+        outbounds: HashMap::new(),
+        binding: HashMap::new(),
+        events,
+    };
+
+    // 控制连接关闭时关联随之结束
+    let mut probe = [0u8; 64];
+    let closed = async {
+        loop {
+            match client.read(&mut probe).await {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {}
+            }
+        }
+    };
+
+    tokio::select! {
+        result = session.run(events_rx) => result,
+        _ = closed => {
+            println!("[SOCKS5] UDP associate for {} closed", peer_addr);
+            Ok(())
+        }
+    }
+}
+
+type Target = (String, u16);
+
+// 后台任务（规则匹配、绑定出站、回程转发）通知会话
+enum UdpEvent {
+    Routed(Target, UdpRoute),
+    Bound(String, std::io::Result<AnyDatagram>),
+    Closed(String, AnyDatagram),
+}
+
+struct Outbound {
+    datagram: AnyDatagram,
+    relay: JoinHandle<()>,
+}
+
+impl Drop for Outbound {
+    fn drop(&mut self) {
+        self.relay.abort();
+    }
+}
+
+struct UdpSession {
+    socket: Arc<UdpSocket>,
+    peer_ip: IpAddr,
+    peer_addr: SocketAddr,
+    user: Option<String>,
+    client_addr: Arc<Mutex<Option<SocketAddr>>>,
+    dispatcher: Arc<Dispatcher>,
+    // 目标 -> 规则匹配结果，避免每个包都走一遍规则；只保留最近的目标
+    routes: LruCache<Target, UdpRoute>,
+    // 正在匹配规则 / 正在绑定出站时到达的包先排队，匹配可能要查 DNS，不能卡住收包
+    routing: HashMap<Target, Vec<Vec<u8>>>,
+    outbounds: HashMap<String, Outbound>,
+    binding: HashMap<String, Vec<(Target, Vec<u8>)>>,
+    events: mpsc::UnboundedSender<UdpEvent>,
+}
+
+impl UdpSession {
+    async fn run(&mut self, mut events: mpsc::UnboundedReceiver<UdpEvent>) -> std::io::Result<()> {
+        let mut buf = vec![0u8; 65535];
+        loop {
+            let (n, from) = tokio::select! {
+                received = self.socket.recv_from(&mut buf) => received?,
+                Some(event) = events.recv() => {
+                    self.handle_event(event).await;
+                    continue;
+                }
+            };
+            if from.ip().to_canonical() != self.peer_ip {
+                continue;
+            }
+            *self.client_addr.lock().unwrap() = Some(from);
+
+            // RSV(2) FRAG(1) ATYP DST.ADDR DST.PORT DATA，不支持分片
+            if n < 4 || buf[2] != 0x00 {
+                continue;
+            }
+            let (host, port, used) = match socks_addr::decode(&buf[3..n]) {
+                Ok(addr) => addr,
+                Err(e) => {
+                    eprintln!("[SOCKS5] Bad UDP packet from {}: {}", from, e);
+                    continue;
+                }
+            };
+            let host = self.dispatcher.real_host(&host);
+            self.handle_packet((host, port), &buf[3 + used..n]).await;
+        }
+    }
+
+    async fn handle_packet(&mut self, target: Target, payload: &[u8]) {
+        if let Some(queue) = self.routing.get_mut(&target) {
+            if queue.len() < MAX_QUEUED_PACKETS {
+                queue.push(payload.to_vec());
+            }
+            return;
+        }
+        let dispatcher = self.dispatcher.clone();
+        let route = self.routes.get(&target).filter(|route| dispatcher.is_current(route));
+        let Some(route) = route else {
+            self.routing.insert(target.clone(), vec![payload.to_vec()]);
+            let metadata = Metadata::new(&target.0, target.1, Some(self.peer_addr)).with_user(self.user.clone());
+            let events = self.events.clone();
+            tokio::spawn(async move {
+                let route = dispatcher.udp_route(&metadata).await;
+                let _ = events.send(UdpEvent::Routed(target, route));
+            });
+            return;
+        };
+        match self.dispatcher.udp_proxy(route) {
+            Ok(proxy) => self.forward(proxy, target, payload).await,
+            Err(e) => eprintln!("[SOCKS5] UDP {}:{} dropped: {}", target.0, target.1, e),
+        }
+    }
+
+    async fn forward(&mut self, proxy: String, target: Target, payload: &[u8]) {
+        let Some(outbound) = self.outbounds.get(&proxy) else {
+            if let Some(queue) = self.binding.get_mut(&proxy) {
+                if queue.len() < MAX_QUEUED_PACKETS {
+                    queue.push((target, payload.to_vec()));
+                }
+                return;
+            }
+            println!("[SOCKS5] UDP {}:{} via {}", target.0, target.1, proxy);
+            self.binding.insert(proxy.clone(), vec![(target, payload.to_vec())]);
+            let dispatcher = self.dispatcher.clone();
+            let events = self.events.clone();
+            tokio::spawn(async move {
+                let outbound = dispatcher.bind_udp(&proxy).await;
+                let _ = events.send(UdpEvent::Bound(proxy, outbound));
+            });
+            return;
+        };
+        // 发送失败说明出站已失效（服务器断开等），丢掉它，下一个包重新绑定
+        if let Err(e) = outbound.datagram.send_to(payload, &target.0, target.1).await {
+            eprintln!("[SOCKS5] UDP send to {}:{} via {} failed: {}", target.0, target.1, proxy, e);
+            self.outbounds.remove(&proxy);
+        }
+    }
+
+    async fn handle_event(&mut self, event: UdpEvent) {
+        match event {
+            UdpEvent::Routed(target, route) => {
+                let queued = self.routing.remove(&target).unwrap_or_default();
+                let proxy = self.dispatcher.udp_proxy(&route);
+                self.routes.put(target.clone(), route);
+                match proxy {
+                    Ok(proxy) => {
+                        for payload in queued {
+                            self.forward(proxy.clone(), target.clone(), &payload).await;
+                        }
+                    }
+                    Err(e) => eprintln!("[SOCKS5] UDP {}:{} dropped: {}", target.0, target.1, e),
+                }
+            }
+            UdpEvent::Bound(proxy, result) => {
+                let queued = self.binding.remove(&proxy).unwrap_or_default();
+                let datagram = match result {
+                    Ok(datagram) => datagram,
+                    Err(e) => {
+                        eprintln!("[SOCKS5] UDP bind via {} failed: {}", proxy, e);
+                        return;
+                    }
+                };
+                let relay = tokio::spawn(relay_back(
+                    proxy.clone(),
+                    datagram.clone(),
+                    self.socket.clone(),
+                    self.client_addr.clone(),
+                    self.events.clone(),
+                ));
+                self.outbounds.insert(proxy.clone(), Outbound { datagram, relay });
+                for (target, payload) in queued {
+                    self.forward(proxy.clone(), target, &payload).await;
+                }
+            }
+            // 回程读失败，出站已失效；只移除同一个出站，期间可能已经换了新的
+            UdpEvent::Closed(proxy, datagram) => {
+                if self.outbounds.get(&proxy).is_some_and(|outbound| Arc::ptr_eq(&outbound.datagram, &datagram)) {
+                    self.outbounds.remove(&proxy);
+                }
+            }
+        }
+    }
+}
+
+// 把出站收到的包加上 SOCKS5 UDP 头发回客户端
+async fn relay_back(
+    proxy: String,
+    outbound: AnyDatagram,
+    socket: Arc<UdpSocket>,
+    client_addr: Arc<Mutex<Option<SocketAddr>>>,
+    events: mpsc::UnboundedSender<UdpEvent>,
+) {
+    let mut buf = vec![0u8; 65535];
+    loop {
+        let (n, host, port) = match outbound.recv_from(&mut buf).await {
+            Ok(packet) => packet,
+            Err(e) => {
+                eprintln!("[SOCKS5] UDP outbound {} closed: {}", proxy, e);
+                let _ = events.send(UdpEvent::Closed(proxy, outbound));
+                break;
+            }
+        };
+        let Some(target) = *client_addr.lock().unwrap() else {
+            continue;
+        };
+
+        let mut packet = Vec::with_capacity(n + 32);
+        packet.extend_from_slice(&[0x00, 0x00, 0x00]);
+        socks_addr::encode(&host, port, &mut packet);
+        packet.extend_from_slice(&buf[..n]);
+        if let Err(e) = socket.send_to(&packet, target).await {
+            eprintln!("[SOCKS5] UDP reply to {} failed: {}", target, e);
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+pub const ATYP_IPV4: u8 = 0x01;
+pub const ATYP_DOMAIN: u8 = 0x03;
+pub const ATYP_IPV6: u8 = 0x04;
+
+// SOCKS5 风格的地址编码：ATYP + ADDR + PORT，Trojan 也使用同样的格式
+pub fn encode(host: &str, port: u16, out: &mut Vec<u8>) {
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            out.push(ATYP_IPV4);
+            out.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            out.push(ATYP_IPV6);
+            out.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            let host = &host.as_bytes()[..host.len().min(255)];
+            out.push(ATYP_DOMAIN);
+            out.push(host.len() as u8);
+            out.extend_from_slice(host);
+        }
+    }
+    out.extend_from_slice(&port.to_be_bytes());
+}
+
+// 返回 (host, port, 占用的字节数)
+pub fn decode(buf: &[u8]) -> io::Result<(String, u16, usize)> {
+    let short = || io::Error::new(io::ErrorKind::UnexpectedEof, "truncated address");
+    let atyp = *buf.first().ok_or_else(short)?;
+    let (host, offset) = match atyp {
+        ATYP_IPV4 => {
+            let octets: [u8; 4] = buf.get(1..5).ok_or_else(short)?.try_into().unwrap();
+            (Ipv4Addr::from(octets).to_string(), 5)
+        }
+        ATYP_IPV6 => {
+            let octets: [u8; 16] = buf.get(1..17).ok_or_else(short)?.try_into().unwrap();
+            (Ipv6Addr::from(octets).to_string(), 17)
+        }
+        ATYP_DOMAIN => {
+            let len = *buf.get(1).ok_or_else(short)? as usize;
+            let domain = buf.get(2..2 + len).ok_or_else(short)?;
+            (String::from_utf8_lossy(domain).to_string(), 2 + len)
+        }
+        _ => return Err(io::Error::other("Address type not supported")),
+    };
+    let port = buf.get(offset..offset + 2).ok_or_else(short)?;
+    Ok((host, u16::from_be_bytes([port[0], port[1]]), offset + 2))
+}
+
+pub async fn read<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<(String, u16)> {
+    let atyp = reader.read_u8().await?;
+    read_with_type(reader, atyp).await
+}
+
+pub async fn read_with_type<R: AsyncRead + Unpin>(reader: &mut R, atyp: u8) -> io::Result<(String, u16)> {
+    let host = match atyp {
+        ATYP_IPV4 => {
+            let mut octets = [0u8; 4];
+            reader.read_exact(&mut octets).await?;
+            Ipv4Addr::from(octets).to_string()
+        }
+        ATYP_IPV6 => {
+            let mut octets = [0u8; 16];
+            reader.read_exact(&mut octets).await?;
+            Ipv6Addr::from(octets).to_string()
+        }
+        ATYP_DOMAIN => {
+            let len = reader.read_u8().await? as usize;
+            let mut domain = vec![0u8; len];
+            reader.read_exact(&mut domain).await?;
+            String::from_utf8_lossy(&domain).to_string()
+        }
+        _ => return Err(io::Error::other("Address type not supported")),
+    };
+    let port = reader.read_u16().await?;
+    Ok((host, port))
+}
@@ -0,0 +1,70 @@
+use std::io;
+use std::sync::{Arc, OnceLock};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::{
+    client::TlsStream,
+    rustls::{self, OwnedTrustAnchor, RootCertStore, ServerName},
+    TlsConnector,
+};
+use webpki_roots::TLS_SERVER_ROOTS;
+
+static CONNECTOR: OnceLock<TlsConnector> = OnceLock::new();
+
+pub fn connector() -> TlsConnector {
+    CONNECTOR
+        .get_or_init(|| {
+            let mut root_cert_store = RootCertStore::empty();
+            root_cert_store.add_trust_anchors(
+                TLS_SERVER_ROOTS.iter().map(|ta| {
+                    OwnedTrustAnchor::from_subject_spki_name_constraints(
+                        ta.subject,
+                        ta.spki,
+                        ta.name_constraints,
+                    )
+                }),
+            );
+            #[cfg(test)]
+            root_cert_store
+                .add(&rustls::Certificate(test_certificate().0.clone()))
+                .unwrap();
+
+            let config = rustls::ClientConfig::builder()
+                .with_safe_defaults()
+                .with_root_certificates(root_cert_store)
+                .with_no_client_auth();
+
+            TlsConnector::from(Arc::new(config))
+        })
+        .clone()
+}
+
+pub async fn connect<S>(stream: S, server_name: &str) -> io::Result<TlsStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let server_name = ServerName::try_from(server_name)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid SNI"))?;
+    connector().connect(server_name, stream).await
+}
+
+// 测试用自签证书 (DER 证书, DER 私钥)，覆盖 localhost、127.0.0.1 和 dns.test；只在测试构建里加入信任列表
+#[cfg(test)]
+pub fn test_certificate() -> &'static (Vec<u8>, Vec<u8>) {
+    static CERT: OnceLock<(Vec<u8>, Vec<u8>)> = OnceLock::new();
+    CERT.get_or_init(|| {
+        let names = vec!["localhost".to_string(), "127.0.0.1".to_string(), "dns.test".to_string()];
+        let cert = rcgen::generate_simple_self_signed(names).unwrap();
+        (cert.serialize_der().unwrap(), cert.serialize_private_key_der())
+    })
+}
+
+#[cfg(test)]
+pub fn test_acceptor() -> tokio_rustls::TlsAcceptor {
+    let (cert, key) = test_certificate();
+    let config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(vec![rustls::Certificate(cert.clone())], rustls::PrivateKey(key.clone()))
+        .unwrap();
+    tokio_rustls::TlsAcceptor::from(Arc::new(config))
+}
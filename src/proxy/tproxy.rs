@@ -0,0 +1,389 @@
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::fd::AsRawFd;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use tokio::io::Interest;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+use crate::proxy::dispatcher::{Dispatcher, UdpRoute};
+use crate::proxy::outbound::AnyDatagram;
+use crate::rule::Metadata;
+
+// 一个客户端地址在这段时间内没有收发包就回收其会话
+const UDP_SESSION_TIMEOUT: Duration = Duration::from_secs(60);
+
+// iptables -t mangle ... -j TPROXY 过来的流量，TCP 的本地地址就是原始目标
+pub async fn start_tproxy_server(
+    addr: &str,
+    dispatcher: Arc<Dispatcher>,
+) -> io::Result<()> {
+    let addr: SocketAddr = addr.parse().map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let listener = tcp_listener(addr)?;
+    let udp = Arc::new(udp_socket(addr)?);
+    println!("[TProxy] Listening on {} (tcp+udp)", addr);
+
+    tokio::spawn(serve_udp(udp, addr, dispatcher.clone()));
+
+    loop {
+        let (stream, peer_addr) = listener.accept().await?;
+        if !dispatcher.permits(peer_addr.ip()) {
+            println!("[TProxy] Rejected connection from {}", peer_addr);
+            continue;
+        }
+        let dispatcher = dispatcher.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = handle_tcp(stream, peer_addr, addr, dispatcher).await {
+                eprintln!("[TProxy] Error from {}: {:?}", peer_addr, e);
+            }
+        });
+    }
+}
+
+async fn handle_tcp(
+    mut client: TcpStream,
+    peer_addr: SocketAddr,
+    listen: SocketAddr,
+    dispatcher: Arc<Dispatcher>,
+) -> io::Result<()> {
+    let peer_addr = canonical(peer_addr);
+    let target = canonical(client.local_addr()?);
+    // 直接连到监听地址的连接，转发出去会连回自己
+    if is_listen_addr(target, listen) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "connection was not redirected"));
+    }
+
+    println!("[TProxy] {} -> {}", peer_addr, target);
+    let metadata = Metadata::new(&target.ip().to_string(), target.port(), Some(peer_addr));
+    let mut remote = dispatcher.connect(&metadata).await?;
+
+    let (n1, n2) = tokio::io::copy_bidirectional(&mut client, &mut remote).await?;
+    println!("[TProxy] Relay complete: client → remote = {} bytes, remote → client = {} bytes", n1, n2);
+
+    Ok(())
+}
+
+type Sessions = Arc<Mutex<HashMap<SocketAddr, mpsc::Sender<(SocketAddr, Vec<u8>)>>>>;
+
+async fn serve_udp(socket: Arc<UdpSocket>, listen: SocketAddr, dispatcher: Arc<Dispatcher>) {
+    let sessions: Sessions = Arc::new(Mutex::new(HashMap::new()));
+    let mut buf = vec![0u8; 65535];
+
+    loop {
+        let (n, src, dst) = match socket
+            .async_io(Interest::READABLE, || recv_orig_dst(&socket, &mut buf))
+            .await
+        {
+            Ok(packet) => packet,
+            Err(e) => {
+                eprintln!("[TProxy] UDP receive failed: {}", e);
+                continue;
+            }
+        };
+        let (src, dst) = (canonical(src), canonical(dst));
+        if !dispatcher.permits(src.ip()) {
+            continue;
+        }
+        if is_listen_addr(dst, listen) {
+            eprintln!("[TProxy] UDP from {} was not redirected, dropped", src);
+            continue;
+        }
+
+        let existing = sessions.lock().unwrap().get(&src).cloned();
+        let tx = match existing {
+            Some(tx) if !tx.is_closed() => tx,
+            _ => {
+                let (tx, rx) = mpsc::channel(256);
+                sessions.lock().unwrap().insert(src, tx.clone());
+                tokio::spawn(udp_session(src, rx, dispatcher.clone(), sessions.clone()));
+                tx
+            }
+        };
+        // 会话处理不过来时直接丢包，UDP 本身允许丢
+        let _ = tx.try_send((dst, buf[..n].to_vec()));
+    }
+}
+
+// 每个客户端地址一个会话：按目标选出站，回包用绑定在原始目标地址上的透明 socket 发回
+// 目标是 fake-ip 时按域名发出，并给这个目标单开出站，回包才能以 fake-ip 的身份发回
+async fn udp_session(
+    client: SocketAddr,
+    mut rx: mpsc::Receiver<(SocketAddr, Vec<u8>)>,
+    dispatcher: Arc<Dispatcher>,
+    sessions: Sessions,
+) {
+    let mut routes: HashMap<SocketAddr, UdpRoute> = HashMap::new();
+    let mut outbounds: HashMap<String, AnyDatagram> = HashMap::new();
+    let (reply_tx, mut reply_rx) = mpsc::channel::<(Vec<u8>, SocketAddr)>(256);
+    let mut tasks = Vec::new();
+    let mut reply_sockets: HashMap<SocketAddr, UdpSocket> = HashMap::new();
+
+    loop {
+        tokio::select! {
+            packet = tokio::time::timeout(UDP_SESSION_TIMEOUT, rx.recv()) => {
+                let Ok(Some((dst, payload))) = packet else { break };
+                if !routes.get(&dst).is_some_and(|route| dispatcher.is_current(route)) {
+                    let metadata = Metadata::new(&dst.ip().to_string(), dst.port(), Some(client));
+                    routes.insert(dst, dispatcher.udp_route(&metadata).await);
+                }
+                let proxy = match dispatcher.udp_proxy(&routes[&dst]) {
+                    Ok(proxy) => proxy,
+                    Err(e) => {
+                        eprintln!("[TProxy] UDP {} dropped: {}", dst, e);
+                        continue;
+                    }
+                };
+
+                let ip = dst.ip().to_string();
+                let host = dispatcher.real_host(&ip);
+                let (key, origin) = if host == ip {
+                    (proxy.clone(), None)
+                } else {
+                    (format!("{}|{}", proxy, dst), Some(dst))
+                };
+                if !outbounds.contains_key(&key) {
+                    println!("[TProxy] UDP {} -> {}:{} via {}", client, host, dst.port(), proxy);
+                    let outbound = match dispatcher.bind_udp(&proxy).await {
+                        Ok(outbound) => outbound,
+                        Err(e) => {
+                            eprintln!("[TProxy] UDP {} dropped: {}", dst, e);
+                            continue;
+                        }
+                    };
+                    tasks.push(tokio::spawn(forward_replies(outbound.clone(), origin, reply_tx.clone())));
+                    outbounds.insert(key.clone(), outbound);
+                }
+
+                if let Err(e) = outbounds[&key].send_to(&payload, &host, dst.port()).await {
+                    eprintln!("[TProxy] UDP send to {} failed: {}", dst, e);
+                }
+            }
+            Some((payload, from)) = reply_rx.recv() => {
+                let socket = match reply_sockets.entry(from) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => match reply_socket(from) {
+                        Ok(socket) => entry.insert(socket),
+                        Err(e) => {
+                            eprintln!("[TProxy] Cannot bind reply socket on {}: {}", from, e);
+                            continue;
+                        }
+                    },
+                };
+                if let Err(e) = socket.send_to(&payload, client).await {
+                    eprintln!("[TProxy] UDP reply to {} failed: {}", client, e);
+                }
+            }
+        }
+    }
+
+    sessions.lock().unwrap().remove(&client);
+    for task in tasks {
+        task.abort();
+    }
+}
+
+// origin 非空时回包一律以它为来源，否则用出站报告的来源地址
+async fn forward_replies(outbound: AnyDatagram, origin: Option<SocketAddr>, tx: mpsc::Sender<(Vec<u8>, SocketAddr)>) {
+    let mut buf = vec![0u8; 65535];
+    while let Ok((n, host, port)) = outbound.recv_from(&mut buf).await {
+        let from = match (origin, host.parse()) {
+            (Some(origin), _) => origin,
+            (None, Ok(ip)) => canonical(SocketAddr::new(ip, port)),
+            (None, Err(_)) => continue,
+        };
+        if tx.send((buf[..n].to_vec(), from)).await.is_err() {
+            break;
+        }
+    }
+}
+
+fn tcp_listener(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_ip_transparent(true)?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+fn udp_socket(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_ip_transparent(true)?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    set_recv_orig_dst(&socket, addr.is_ipv6())?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+// 回包必须以原始目标的身份发出，所以在非本机地址上透明绑定
+fn reply_socket(from: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(from), Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_ip_transparent(true)?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&from.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+fn set_recv_orig_dst(socket: &Socket, ipv6: bool) -> io::Result<()> {
+    let enable: libc::c_int = 1;
+    let set = |level, name| {
+        let ret = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                level,
+                name,
+                &enable as *const _ as *const libc::c_void,
+                mem::size_of_val(&enable) as libc::socklen_t,
+            )
+        };
+        if ret == 0 { Ok(()) } else { Err(io::Error::last_os_error()) }
+    };
+    set(libc::SOL_IP, libc::IP_RECVORIGDSTADDR)?;
+    if ipv6 {
+        set(libc::SOL_IPV6, libc::IPV6_RECVORIGDSTADDR)?;
+    }
+    Ok(())
+}
+
+// recvmsg 并从控制消息里取出 IP_ORIGDSTADDR / IPV6_ORIGDSTADDR，返回 (长度, 来源, 原始目标)
+fn recv_orig_dst(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, SocketAddr)> {
+    unsafe {
+        let mut src: libc::sockaddr_storage = mem::zeroed();
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        // 控制消息缓冲区按 cmsghdr 对齐
+        let mut control: [libc::cmsghdr; 8] = mem::zeroed();
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_name = &mut src as *mut _ as *mut libc::c_void;
+        msg.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = mem::size_of_val(&control) as _;
+
+        let n = libc::recvmsg(socket.as_raw_fd(), &mut msg, 0);
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut dst = None;
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            let header = std::ptr::read_unaligned(cmsg);
+            let is_orig_dst = (header.cmsg_level == libc::SOL_IP && header.cmsg_type == libc::IP_ORIGDSTADDR)
+                || (header.cmsg_level == libc::SOL_IPV6 && header.cmsg_type == libc::IPV6_ORIGDSTADDR);
+            if is_orig_dst {
+                let len = header.cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                let mut storage: libc::sockaddr_storage = mem::zeroed();
+                let len = len.min(mem::size_of::<libc::sockaddr_storage>());
+                std::ptr::copy_nonoverlapping(libc::CMSG_DATA(cmsg), &mut storage as *mut _ as *mut u8, len);
+                dst = SockAddr::new(storage, len as libc::socklen_t).as_socket();
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+
+        let src = SockAddr::new(src, msg.msg_namelen).as_socket();
+        match (src, dst) {
+            (Some(src), Some(dst)) => Ok((n as usize, src, dst)),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "missing original destination")),
+        }
+    }
+}
+
+// 目标是监听地址本身（没有经过 TPROXY）；监听在通配地址时，本机任一地址上的该端口都算
+fn is_listen_addr(target: SocketAddr, listen: SocketAddr) -> bool {
+    if target.port() != listen.port() {
+        return false;
+    }
+    let listen_ip = listen.ip().to_canonical();
+    if !listen_ip.is_unspecified() {
+        return target.ip() == listen_ip;
+    }
+    target.ip().is_loopback() || local_ips().is_ok_and(|ips| ips.contains(&target.ip()))
+}
+
+// 本机各网卡上的地址
+fn local_ips() -> io::Result<Vec<IpAddr>> {
+    unsafe {
+        let mut addrs: *mut libc::ifaddrs = std::ptr::null_mut();
+        if libc::getifaddrs(&mut addrs) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut ips = Vec::new();
+        let mut current = addrs;
+        while !current.is_null() {
+            let ifa = &*current;
+            if !ifa.ifa_addr.is_null() {
+                match (*ifa.ifa_addr).sa_family as libc::c_int {
+                    libc::AF_INET => {
+                        let addr = std::ptr::read_unaligned(ifa.ifa_addr as *const libc::sockaddr_in);
+                        ips.push(IpAddr::V4(Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr))));
+                    }
+                    libc::AF_INET6 => {
+                        let addr = std::ptr::read_unaligned(ifa.ifa_addr as *const libc::sockaddr_in6);
+                        ips.push(IpAddr::V6(Ipv6Addr::from(addr.sin6_addr.s6_addr)).to_canonical());
+                    }
+                    _ => {}
+                }
+            }
+            current = ifa.ifa_next;
+        }
+        libc::freeifaddrs(addrs);
+        Ok(ips)
+    }
+}
+
+fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_listen_address_counts_as_not_redirected() {
+        let listen: SocketAddr = "127.0.0.1:7893".parse().unwrap();
+        assert!(is_listen_addr("127.0.0.1:7893".parse().unwrap(), listen));
+        // 远端服务恰好用了同一个端口号
+        assert!(!is_listen_addr("203.0.113.7:7893".parse().unwrap(), listen));
+        assert!(!is_listen_addr("127.0.0.1:7894".parse().unwrap(), listen));
+
+        let any: SocketAddr = "0.0.0.0:7893".parse().unwrap();
+        assert!(is_listen_addr("127.0.0.1:7893".parse().unwrap(), any));
+        assert!(!is_listen_addr("203.0.113.7:7893".parse().unwrap(), any));
+    }
+
+    // 没有经过 TPROXY 的包，原始目标就是 socket 自己的地址
+    #[tokio::test]
+    async fn recv_orig_dst_reads_the_control_message() {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).unwrap();
+        socket.set_nonblocking(true).unwrap();
+        set_recv_orig_dst(&socket, false).unwrap();
+        socket.bind(&SocketAddr::from(([127, 0, 0, 1], 0)).into()).unwrap();
+        let socket = UdpSocket::from_std(socket.into()).unwrap();
+        let local = socket.local_addr().unwrap();
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(b"ping", local).await.unwrap();
+
+        let mut buf = [0u8; 16];
+        let (n, src, dst) = socket
+            .async_io(Interest::READABLE, || recv_orig_dst(&socket, &mut buf))
+            .await
+            .unwrap();
+        assert_eq!(&buf[..n], b"ping");
+        assert_eq!(src, client.local_addr().unwrap());
+        assert_eq!(dst, local);
+    }
+}
@@ -1,15 +1,16 @@
 use std::io;
 use std::sync::Arc;
 use tokio::net::TcpStream;
-use tokio_rustls::{
-    rustls::{self, OwnedTrustAnchor, RootCertStore, ServerName},
-    TlsConnector,
-};
-use webpki_roots::TLS_SERVER_ROOTS;
-use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, AsyncReadExt};
+use tokio::io::{AsyncWriteExt, AsyncReadExt, ReadHalf, WriteHalf};
+use tokio::sync::Mutex;
 use async_trait::async_trait;
+use sha2::{Digest, Sha224};
 
-use crate::proxy::outbound::{OutboundHandler, AnyStream};
+use crate::proxy::outbound::{OutboundHandler, OutboundDatagram, AnyDatagram, AnyStream};
+use crate::proxy::socks_addr;
+use crate::proxy::tls;
+
+const CMD_UDP_ASSOCIATE: u8 = 0x03;
 
 pub struct TrojanProxy {
     pub name: String,
@@ -35,37 +36,9 @@ impl TrojanProxy {
             sni,
         }
     }
-}
-
-#[async_trait]
-impl OutboundHandler for TrojanProxy {
-    async fn connect(&self, address: &str, port: u16) -> io::Result<AnyStream> {
-        println!("[Trojan] Connecting to {}:{} via Trojan", address, port);
-
-        let stream = TcpStream::connect(format!("{}:{}", self.server, self.port)).await?;
-        println!("[Trojan] Connected to Trojan server {}:{}", self.server, self.port);
-
-        let mut root_cert_store = RootCertStore::empty();
-        root_cert_store.add_trust_anchors(
-            TLS_SERVER_ROOTS.iter().map(|ta| {
-                OwnedTrustAnchor::from_subject_spki_name_constraints(
-                    ta.subject,
-                    ta.spki,
-                    ta.name_constraints,
-                )
-            }),
-        );
 
-        let config = rustls::ClientConfig::builder()
-            .with_safe_defaults()
-            .with_root_certificates(root_cert_store)
-            .with_no_client_auth();
-
-        let connector = TlsConnector::from(Arc::new(config));
-        let server_name = ServerName::try_from(self.sni.as_deref().unwrap_or(&self.server))
-            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid SNI"))?;
-
-        let mut tls_stream = connector.connect(server_name, stream).await?;
+    async fn handshake(&self, stream: AnyStream, address: &str, port: u16) -> io::Result<AnyStream> {
+        let mut tls_stream = tls::connect(stream, self.sni.as_deref().unwrap_or(&self.server)).await?;
         println!("[Trojan] TLS handshake successful");
 
         let connect_header = format!(
@@ -94,8 +67,82 @@ impl OutboundHandler for TrojanProxy {
 
         Ok(Box::new(tls_stream))
     }
+}
+
+#[async_trait]
+impl OutboundHandler for TrojanProxy {
+    async fn connect(&self, address: &str, port: u16) -> io::Result<AnyStream> {
+        println!("[Trojan] Connecting to {}:{} via {}", address, port, self.name);
+
+        let stream = TcpStream::connect(format!("{}:{}", self.server, self.port)).await?;
+        println!("[Trojan] Connected to Trojan server {}:{}", self.server, self.port);
+
+        self.handshake(Box::new(stream), address, port).await
+    }
+
+    async fn connect_over(&self, stream: AnyStream, address: &str, port: u16) -> io::Result<AnyStream> {
+        println!("[Trojan] Connecting to {}:{} via {} over relay", address, port, self.name);
+        self.handshake(stream, address, port).await
+    }
+
+    async fn bind_udp(&self) -> io::Result<AnyDatagram> {
+        println!("[Trojan] UDP associate via {}", self.name);
+        let stream = TcpStream::connect(format!("{}:{}", self.server, self.port)).await?;
+        let mut tls_stream: AnyStream =
+            Box::new(tls::connect(stream, self.sni.as_deref().unwrap_or(&self.server)).await?);
+
+        // hex(SHA224(password)) CRLF CMD ATYP DST.ADDR DST.PORT CRLF
+        let mut header = hex::encode(Sha224::digest(self.password.as_bytes())).into_bytes();
+        header.extend_from_slice(b"\r\n");
+        header.push(CMD_UDP_ASSOCIATE);
+        socks_addr::encode("0.0.0.0", 0, &mut header);
+        header.extend_from_slice(b"\r\n");
+        tls_stream.write_all(&header).await?;
+        tls_stream.flush().await?;
+
+        let (reader, writer) = tokio::io::split(tls_stream);
+        Ok(Arc::new(TrojanDatagram {
+            reader: Mutex::new(reader),
+            writer: Mutex::new(writer),
+        }))
+    }
+
+    fn server_addr(&self) -> Option<(String, u16)> {
+        Some((self.server.clone(), self.port))
+    }
+}
+
+// 每个 UDP 包：ATYP DST.ADDR DST.PORT LENGTH CRLF PAYLOAD
+pub struct TrojanDatagram {
+    reader: Mutex<ReadHalf<AnyStream>>,
+    writer: Mutex<WriteHalf<AnyStream>>,
+}
+
+#[async_trait]
+impl OutboundDatagram for TrojanDatagram {
+    async fn send_to(&self, data: &[u8], address: &str, port: u16) -> io::Result<()> {
+        let mut packet = Vec::with_capacity(data.len() + 32);
+        socks_addr::encode(address, port, &mut packet);
+        packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        packet.extend_from_slice(b"\r\n");
+        packet.extend_from_slice(data);
+
+        let mut writer = self.writer.lock().await;
+        writer.write_all(&packet).await?;
+        writer.flush().await
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, String, u16)> {
+        let mut reader = self.reader.lock().await;
+        let (host, port) = socks_addr::read(&mut *reader).await?;
+        let len = reader.read_u16().await? as usize;
+        let mut crlf = [0u8; 2];
+        reader.read_exact(&mut crlf).await?;
 
-    fn as_any(&self) -> &dyn std::any::Any {
-        self
+        let mut payload = vec![0u8; len];
+        reader.read_exact(&mut payload).await?;
+        let n = len.min(buf.len());
+        buf[..n].copy_from_slice(&payload[..n]);
+        Ok((n, host, port))
     }
 }
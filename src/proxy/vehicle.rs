@@ -0,0 +1,497 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use hyper::body::HttpBody;
+use hyper::header::{ETAG, HOST, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, LOCATION, USER_AGENT};
+use hyper::{Body, Request, Response, StatusCode};
+use url::Url;
+
+use crate::proxy::outbound::AnyStream;
+use crate::proxy::proxy_manager::ProxyManager;
+use crate::proxy::tls;
+use crate::rule::Metadata;
+
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_REDIRECTS: usize = 5;
+// 订阅和规则集的大小上限，防止异常的服务器把内存撑爆
+const MAX_BODY_SIZE: u64 = 32 * 1024 * 1024;
+
+/// rule-providers / proxy-providers 的内容来源：本地文件，或者 HTTP 下载。
+/// HTTP 下载的最近一份有效内容保存在 path，下载失败时使用它。
+pub enum Vehicle {
+    File {
+        path: PathBuf,
+        modified: Mutex<Option<SystemTime>>,
+    },
+    Http(HttpVehicle),
+}
+
+pub struct HttpVehicle {
+    url: Url,
+    proxy: Option<String>,
+    path: PathBuf,
+    manager: Arc<ProxyManager>,
+    validators: Mutex<Validators>,
+}
+
+// 上次下载时服务器返回的 ETag / Last-Modified，下次请求时带上做条件请求
+#[derive(Debug, Clone, Default)]
+struct Validators {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+struct Download {
+    content: String,
+    validators: Validators,
+}
+
+impl Vehicle {
+    pub fn new(
+        kind: &str,
+        path: &str,
+        url: Option<&str>,
+        proxy: Option<&str>,
+        manager: &Arc<ProxyManager>,
+    ) -> Result<Self, String> {
+        match kind {
+            "file" => Ok(Vehicle::File {
+                path: PathBuf::from(path),
+                modified: Mutex::new(None),
+            }),
+            "http" => {
+                let url = url.ok_or("http provider requires url")?;
+                let url = Url::parse(url).map_err(|e| format!("invalid url {}: {}", url, e))?;
+                if !matches!(url.scheme(), "http" | "https") || url.host_str().is_none() {
+                    return Err(format!("unsupported url {}", url));
+                }
+                let path = PathBuf::from(path);
+                let validators = Validators::load(&headers_path(&path));
+                Ok(Vehicle::Http(HttpVehicle {
+                    url,
+                    proxy: proxy.map(String::from),
+                    path,
+                    manager: manager.clone(),
+                    validators: Mutex::new(validators),
+                }))
+            }
+            other => Err(format!("unsupported provider type: {}", other)),
+        }
+    }
+
+    pub fn is_file(&self) -> bool {
+        matches!(self, Vehicle::File { .. })
+    }
+
+    // 启动时加载，返回 (内容, 是否需要尽快在后台更新)。
+    // HTTP 有可用的缓存时直接使用，不等下载；没有缓存才在这里下载
+    pub async fn load<T>(&self, parse: impl Fn(&str) -> Result<T, String>) -> Result<(T, bool), String> {
+        let http = match self {
+            Vehicle::File { path, modified } => {
+                *modified.lock().unwrap() = modified_time(path);
+                return parse(&read(path)?).map(|value| (value, false));
+            }
+            Vehicle::Http(http) => http,
+        };
+
+        if http.path.exists() {
+            match read(&http.path).and_then(|content| parse(&content)) {
+                Ok(value) => {
+                    println!("[Provider] Using cached {}, updating in background", http.path.display());
+                    return Ok((value, true));
+                }
+                Err(e) => eprintln!("[Provider] Cache {} is unusable, downloading: {}", http.path.display(), e),
+            }
+        }
+
+        let download = http
+            .download(false)
+            .await
+            .map_err(|e| format!("download {} failed: {}", http.url, e))?
+            .ok_or_else(|| format!("{} returned 304 for an unconditional request", http.url))?;
+        let value = parse(&download.content)?;
+        http.save(&download);
+        Ok((value, false))
+    }
+
+    // 运行中刷新，内容没有变化时返回 None。
+    // 文件在修改时间变化或 force 时重新读取；HTTP 每次调用都会发条件请求
+    pub async fn update<T>(&self, force: bool, parse: impl Fn(&str) -> Result<T, String>) -> Result<Option<T>, String> {
+        match self {
+            Vehicle::File { path, modified } => {
+                let current = modified_time(path);
+                {
+                    let mut last = modified.lock().unwrap();
+                    if *last == current && !force {
+                        return Ok(None);
+                    }
+                    // 不论成功与否都记下，避免坏文件每次检查都重新报错
+                    *last = current;
+                }
+                parse(&read(path)?).map(Some)
+            }
+            Vehicle::Http(http) => {
+                let Some(download) = http
+                    .download(true)
+                    .await
+                    .map_err(|e| format!("download {} failed: {}", http.url, e))?
+                else {
+                    return Ok(None);
+                };
+                let value = parse(&download.content)?;
+                http.save(&download);
+                Ok(Some(value))
+            }
+        }
+    }
+}
+
+impl HttpVehicle {
+    // conditional 时带上 ETag / Last-Modified，304 时返回 None
+    async fn download(&self, conditional: bool) -> io::Result<Option<Download>> {
+        tokio::time::timeout(DOWNLOAD_TIMEOUT, self.fetch(conditional))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "download timed out"))?
+    }
+
+    async fn fetch(&self, conditional: bool) -> io::Result<Option<Download>> {
+        // 缓存文件不在时不能接受 304，不带条件重新下载
+        let validators = if conditional && self.path.exists() {
+            self.validators.lock().unwrap().clone()
+        } else {
+            Validators::default()
+        };
+
+        let mut url = self.url.clone();
+        for _ in 0..=MAX_REDIRECTS {
+            let response = self.get(&url, &validators).await?;
+            let status = response.status();
+            if status == StatusCode::NOT_MODIFIED {
+                return Ok(None);
+            }
+            if status.is_redirection()
+                && let Some(location) = response.headers().get(LOCATION)
+            {
+                let location = location.to_str().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                url = redirect(&url, location)?;
+                continue;
+            }
+            if !status.is_success() {
+                return Err(io::Error::other(format!("HTTP {}", status)));
+            }
+
+            let header = |name| {
+                response
+                    .headers()
+                    .get(name)
+                    .and_then(|value| value.to_str().ok())
+                    .map(String::from)
+            };
+            let validators = Validators {
+                etag: header(ETAG),
+                last_modified: header(LAST_MODIFIED),
+            };
+            let body = read_body(response.into_body()).await?;
+            let content = String::from_utf8(body)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "content is not UTF-8"))?;
+            return Ok(Some(Download { content, validators }));
+        }
+        Err(io::Error::other(format!("too many redirects from {}", self.url)))
+    }
+
+    async fn get(&self, url: &Url, validators: &Validators) -> io::Result<Response<Body>> {
+        let host = url
+            .host_str()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "url has no host"))?
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string();
+        let port = url.port_or_known_default().unwrap_or(80);
+
+        let proxy = self.proxy.as_deref().unwrap_or("DIRECT");
+        let stream = self.manager.connect(proxy, &Metadata::new(&host, port, None)).await?;
+        let stream: AnyStream = match url.scheme() {
+            "https" => Box::new(tls::connect(stream, &host).await?),
+            _ => stream,
+        };
+        let (mut sender, connection) = hyper::client::conn::handshake(stream)
+            .await
+            .map_err(io::Error::other)?;
+        tokio::spawn(async move {
+            let _ = connection.await;
+        });
+
+        let mut request = Request::get(&url[url::Position::BeforePath..url::Position::AfterQuery])
+            .header(HOST, &url[url::Position::BeforeHost..url::Position::AfterPort])
+            .header(USER_AGENT, "clash-rs");
+        if let Some(etag) = &validators.etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &validators.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified);
+        }
+        let request = request.body(Body::empty()).map_err(io::Error::other)?;
+        sender.send_request(request).await.map_err(io::Error::other)
+    }
+
+    // 内容确认有效后才写入缓存，保证磁盘上始终是最近一份好的
+    fn save(&self, download: &Download) {
+        let result = write_atomic(&self.path, download.content.as_bytes())
+            .and_then(|_| write_atomic(&headers_path(&self.path), download.validators.to_string().as_bytes()));
+        match result {
+            Ok(()) => *self.validators.lock().unwrap() = download.validators.clone(),
+            Err(e) => eprintln!("[Provider] Cannot write cache {}: {}", self.path.display(), e),
+        }
+    }
+}
+
+impl Validators {
+    fn load(path: &Path) -> Self {
+        let mut validators = Self::default();
+        let Ok(content) = fs::read_to_string(path) else {
+            return validators;
+        };
+        for line in content.lines() {
+            match line.split_once(": ") {
+                Some(("ETag", value)) => validators.etag = Some(value.to_string()),
+                Some(("Last-Modified", value)) => validators.last_modified = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        validators
+    }
+}
+
+impl std::fmt::Display for Validators {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(etag) = &self.etag {
+            writeln!(f, "ETag: {}", etag)?;
+        }
+        if let Some(last_modified) = &self.last_modified {
+            writeln!(f, "Last-Modified: {}", last_modified)?;
+        }
+        Ok(())
+    }
+}
+
+// 缓存文件旁边的 <path>.headers 保存 ETag / Last-Modified
+fn headers_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".headers");
+    PathBuf::from(name)
+}
+
+fn write_atomic(path: &Path, content: &[u8]) -> io::Result<()> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, content)?;
+    fs::rename(&tmp, path)
+}
+
+fn read(path: &Path) -> Result<String, String> {
+    fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path.display(), e))
+}
+
+// 跟随重定向，https 不能被降级成明文 http
+fn redirect(from: &Url, location: &str) -> io::Result<Url> {
+    let to = from.join(location).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    match (from.scheme(), to.scheme()) {
+        ("https", "https") | ("http", "http" | "https") => Ok(to),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("refusing redirect from {} to {}", from, to),
+        )),
+    }
+}
+
+async fn read_body(mut body: Body) -> io::Result<Vec<u8>> {
+    let too_large = || io::Error::new(io::ErrorKind::InvalidData, format!("content larger than {} bytes", MAX_BODY_SIZE));
+    if body.size_hint().lower() > MAX_BODY_SIZE {
+        return Err(too_large());
+    }
+    let mut content = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(io::Error::other)?;
+        if content.len() as u64 + chunk.len() as u64 > MAX_BODY_SIZE {
+            return Err(too_large());
+        }
+        content.extend_from_slice(&chunk);
+    }
+    Ok(content)
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+
+    use hyper::Server;
+    use hyper::service::{make_service_fn, service_fn};
+
+    use super::*;
+    use crate::config::Config;
+    use crate::dns::Resolver;
+    use crate::proxy::runtime::{Mode, ProxyRuntime};
+
+    // 本地订阅服务：按 status 返回 body 和 ETag，If-None-Match 命中时返回 304
+    struct Served {
+        status: u16,
+        body: String,
+        etag: String,
+        requests: usize,
+        if_none_match: Option<String>,
+    }
+
+    async fn serve(status: u16, body: &str, etag: &str) -> (SocketAddr, Arc<Mutex<Served>>) {
+        let state = Arc::new(Mutex::new(Served {
+            status,
+            body: body.to_string(),
+            etag: etag.to_string(),
+            requests: 0,
+            if_none_match: None,
+        }));
+        let served = state.clone();
+        let make = make_service_fn(move |_| {
+            let state = state.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let state = state.clone();
+                    async move {
+                        let mut served = state.lock().unwrap();
+                        served.requests += 1;
+                        served.if_none_match = req
+                            .headers()
+                            .get(IF_NONE_MATCH)
+                            .map(|v| v.to_str().unwrap().to_string());
+                        let response = if served.status == 200 && served.if_none_match.as_ref() == Some(&served.etag) {
+                            Response::builder().status(304).body(Body::empty())
+                        } else {
+                            Response::builder()
+                                .status(served.status)
+                                .header(ETAG, &served.etag)
+                                .body(Body::from(served.body.clone()))
+                        };
+                        Ok::<_, Infallible>(response.unwrap())
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        (addr, served)
+    }
+
+    fn vehicle(addr: SocketAddr, path: &Path) -> Vehicle {
+        let config: Config = serde_yaml::from_str("proxies: []\nproxy-groups: []\nrules: []\n").unwrap();
+        let resolver = Arc::new(Resolver::new(None, &serde_yaml::Mapping::new()).unwrap());
+        let manager = Arc::new(ProxyManager::new(&config, Arc::new(ProxyRuntime::new(Mode::Rule)), resolver));
+        let url = format!("http://{}/sub.yaml", addr);
+        Vehicle::new("http", path.to_str().unwrap(), Some(&url), None, &manager).unwrap()
+    }
+
+    fn cache_path(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("clash-rs-vehicle-{}-{}", std::process::id(), test));
+        let _ = fs::remove_dir_all(&dir);
+        dir.join("sub.yaml")
+    }
+
+    fn parse(content: &str) -> Result<String, String> {
+        match content.starts_with("payload") {
+            true => Ok(content.to_string()),
+            false => Err("not a payload".to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn download_writes_cache_and_headers() {
+        let (addr, served) = serve(200, "payload: v1", "\"v1\"").await;
+        let path = cache_path("download");
+
+        let (value, stale) = vehicle(addr, &path).load(parse).await.unwrap();
+        assert_eq!(value, "payload: v1");
+        assert!(!stale);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "payload: v1");
+        assert_eq!(fs::read_to_string(headers_path(&path)).unwrap(), "ETag: \"v1\"\n");
+        assert_eq!(served.lock().unwrap().if_none_match, None);
+    }
+
+    #[tokio::test]
+    async fn update_sends_etag_and_handles_not_modified() {
+        let (addr, served) = serve(200, "payload: v1", "\"v1\"").await;
+        let path = cache_path("conditional");
+        let vehicle = vehicle(addr, &path);
+        vehicle.load(parse).await.unwrap();
+
+        assert_eq!(vehicle.update(false, parse).await.unwrap(), None);
+        assert_eq!(served.lock().unwrap().if_none_match.as_deref(), Some("\"v1\""));
+
+        {
+            let mut served = served.lock().unwrap();
+            served.body = "payload: v2".to_string();
+            served.etag = "\"v2\"".to_string();
+        }
+        assert_eq!(vehicle.update(false, parse).await.unwrap().as_deref(), Some("payload: v2"));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "payload: v2");
+        assert_eq!(fs::read_to_string(headers_path(&path)).unwrap(), "ETag: \"v2\"\n");
+    }
+
+    #[tokio::test]
+    async fn failures_fall_back_to_cache() {
+        let (addr, served) = serve(200, "payload: v1", "\"v1\"").await;
+        let path = cache_path("fallback");
+        let vehicle = vehicle(addr, &path);
+        vehicle.load(parse).await.unwrap();
+
+        served.lock().unwrap().status = 503;
+        assert!(vehicle.update(false, parse).await.is_err());
+
+        {
+            let mut served = served.lock().unwrap();
+            served.status = 200;
+            served.body = "<html>login</html>".to_string();
+            served.etag = "\"v3\"".to_string();
+        }
+        assert!(vehicle.update(false, parse).await.is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "payload: v1");
+        assert_eq!(fs::read_to_string(headers_path(&path)).unwrap(), "ETag: \"v1\"\n");
+
+        // 重启时直接用缓存，不等下载
+        served.lock().unwrap().status = 503;
+        let requests = served.lock().unwrap().requests;
+        let (value, stale) = super::tests::vehicle(addr, &path).load(parse).await.unwrap();
+        assert_eq!(value, "payload: v1");
+        assert!(stale);
+        assert_eq!(served.lock().unwrap().requests, requests);
+    }
+
+    #[tokio::test]
+    async fn oversize_content_is_rejected() {
+        let body = format!("payload: {}", "a".repeat(MAX_BODY_SIZE as usize));
+        let (addr, _) = serve(200, &body, "\"big\"").await;
+        let path = cache_path("oversize");
+
+        assert!(vehicle(addr, &path).load(parse).await.is_err());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn https_redirect_cannot_downgrade() {
+        let https = Url::parse("https://example.com/sub.yaml").unwrap();
+        let http = Url::parse("http://example.com/sub.yaml").unwrap();
+        assert_eq!(redirect(&https, "/v2.yaml").unwrap().as_str(), "https://example.com/v2.yaml");
+        assert!(redirect(&https, "http://example.com/v2.yaml").is_err());
+        assert_eq!(redirect(&http, "https://example.com/v2.yaml").unwrap().as_str(), "https://example.com/v2.yaml");
+        assert!(redirect(&http, "file:///etc/passwd").is_err());
+    }
+}
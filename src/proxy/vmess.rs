@@ -1,12 +1,13 @@
 use std::collections::HashMap;
 use std::io;
-use std::net::Ipv4Addr;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
 use uuid::Uuid;
 use async_trait::async_trait;
 use rand::{RngCore, thread_rng};
 use sha2::{Sha256, Digest};
 use chrono::Utc;
-use md5::{Md5, Digest as Md5Digest};
+use md5::Md5;
 use futures_util::SinkExt;
 use futures_util::StreamExt;
 use bytes::{BufMut, BytesMut};
@@ -15,13 +16,19 @@ use aes_gcm::{Aes128Gcm, KeyInit, Nonce};
 use aes_gcm::aead::{Aead, Payload};
 
 use tokio::net::TcpStream;
-use tokio::io::{AsyncWriteExt};
-use tokio_tungstenite::{connect_async, tungstenite::Message, WebSocketStream, MaybeTlsStream};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, WriteHalf};
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::{client_async, tungstenite::Message, WebSocketStream};
 use tokio_tungstenite::tungstenite::client::IntoClientRequest;
 
-use crate::proxy::outbound::{OutboundHandler, AnyStream};
+use crate::proxy::outbound::{OutboundHandler, OutboundDatagram, AnyDatagram, AnyStream};
 use crate::proxy::ws_wrapper::WsStreamWrapper;
 
+const CMD_TCP: u8 = 0x01;
+const CMD_UDP: u8 = 0x02;
+
+#[derive(Clone)]
 pub struct VmessProxy {
     pub name: String,
     pub server: String,
@@ -31,9 +38,11 @@ pub struct VmessProxy {
     pub network: Option<String>,
     pub ws_path: Option<String>,
     pub ws_host: Option<String>,
+    pub udp: bool,
 }
 
 impl VmessProxy {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         name: String,
         server: String,
@@ -43,6 +52,7 @@ impl VmessProxy {
         network: Option<String>,
         ws_path: Option<String>,
         ws_headers: Option<HashMap<String, String>>,
+        udp: bool,
     ) -> Self {
         let ws_host = ws_headers.as_ref().and_then(|h| h.get("Host").cloned());
         Self {
@@ -54,17 +64,24 @@ impl VmessProxy {
             network,
             ws_path,
             ws_host,
+            udp,
         }
     }
 
-    async fn connect_ws(&self, address: &str, port: u16) -> io::Result<AnyStream> {
+    async fn connect_ws(&self, address: &str, port: u16, command: u8) -> io::Result<AnyStream> {
         println!("[VMess] connect_ws_stream called: {} -> {}:{}", self.name, address, port);
 
+        let ws_server = self.ws_host.as_deref().unwrap_or(&self.server);
+        let stream = TcpStream::connect(format!("{}:{}", ws_server, self.port)).await?;
+        self.handshake_ws(Box::new(stream), address, port, command).await
+    }
+
+    async fn handshake_ws(&self, stream: AnyStream, address: &str, port: u16, command: u8) -> io::Result<AnyStream> {
         let raw_url = format!(
             "ws://{}:{}{}",
             self.ws_host.as_deref().unwrap_or(&self.server),
             self.port,
-            self.ws_path.as_deref().unwrap_or(&"/".to_string())
+            self.ws_path.as_deref().unwrap_or("/")
         );
 
         let req_url = url::Url::parse(&raw_url).unwrap();
@@ -73,14 +90,14 @@ impl VmessProxy {
             request.headers_mut().insert("Host", host.parse().unwrap());
         }
 
-        let (mut ws_stream, _) = connect_async(request).await
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("WebSocket connect failed: {}", e)))?;
+        let (mut ws_stream, _) = client_async(request, stream).await
+            .map_err(|e| io::Error::other(format!("WebSocket connect failed: {}", e)))?;
 
         if self.alter_id == 0 {
-            send_vmess_aead_handshake(&mut ws_stream, &self.uuid, address, port).await?;
+            send_vmess_aead_handshake(&mut ws_stream, &self.uuid, address, port, command).await?;
         } else {
             let mut raw_stream = WsStreamWrapper::new(ws_stream);
-            send_vmess_legacy_handshake(&mut raw_stream, &self.uuid, address, port).await?;
+            send_vmess_legacy_handshake(&mut raw_stream, &self.uuid, address, port, command).await?;
             return Ok(Box::new(raw_stream));
         }
 
@@ -97,26 +114,191 @@ impl VmessProxy {
 impl OutboundHandler for VmessProxy {
     async fn connect(&self, address: &str, port: u16) -> io::Result<AnyStream> {
         match self.network.as_deref() {
-            Some("ws") => self.connect_ws(address, port).await,
-            _ => Err(io::Error::new(io::ErrorKind::Other, "Only ws supported in this impl")),
+            Some("ws") => self.connect_ws(address, port, CMD_TCP).await,
+            _ => Err(io::Error::other("Only ws supported in this impl")),
         }
     }
 
-    fn as_any(&self) -> &dyn std::any::Any {
-        self
+    async fn connect_over(&self, stream: AnyStream, address: &str, port: u16) -> io::Result<AnyStream> {
+        println!("[VMess] {} -> {}:{} over relay", self.name, address, port);
+        match self.network.as_deref() {
+            Some("ws") => self.handshake_ws(stream, address, port, CMD_TCP).await,
+            _ => Err(io::Error::other("Only ws supported in this impl")),
+        }
+    }
+
+    async fn bind_udp(&self) -> io::Result<AnyDatagram> {
+        if !self.udp {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("UDP is disabled for {}", self.name),
+            ));
+        }
+        if self.network.as_deref() != Some("ws") {
+            return Err(io::Error::other("Only ws supported in this impl"));
+        }
+        Ok(Arc::new(VmessDatagram::new(self.clone())))
+    }
+
+    fn server_addr(&self) -> Option<(String, u16)> {
+        let ws_server = self.ws_host.as_deref().unwrap_or(&self.server);
+        Some((ws_server.to_string(), self.port))
+    }
+}
+
+type UdpPacket = (Vec<u8>, String, u16);
+
+// 目标超过这么久没有收发就关闭对应连接
+const UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+const UDP_SWEEP_INTERVAL: Duration = Duration::from_secs(15);
+
+// 到单个目标的连接，读任务随会话一起结束
+struct UdpSession {
+    writer: Mutex<WriteHalf<AnyStream>>,
+    reader: JoinHandle<()>,
+    last_active: Arc<std::sync::Mutex<Instant>>,
+}
+
+impl UdpSession {
+    fn touch(&self) {
+        *self.last_active.lock().unwrap() = Instant::now();
+    }
+
+    fn is_idle(&self) -> bool {
+        self.reader.is_finished() || self.last_active.lock().unwrap().elapsed() >= UDP_IDLE_TIMEOUT
+    }
+}
+
+impl Drop for UdpSession {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+type UdpSessions = std::sync::Mutex<HashMap<(String, u16), Arc<UdpSession>>>;
+
+// VMess 的 UDP 命令只携带一个目标地址，因此每个目标单独建立一条连接，
+// 载荷按 2 字节长度前缀分帧
+pub struct VmessDatagram {
+    proxy: VmessProxy,
+    sessions: Arc<UdpSessions>,
+    sweeper: JoinHandle<()>,
+    tx: mpsc::Sender<UdpPacket>,
+    rx: Mutex<mpsc::Receiver<UdpPacket>>,
+}
+
+impl VmessDatagram {
+    fn new(proxy: VmessProxy) -> Self {
+        let (tx, rx) = mpsc::channel(256);
+        let sessions: Arc<UdpSessions> = Arc::default();
+        let sweeper = tokio::spawn(sweep_idle(proxy.name.clone(), Arc::downgrade(&sessions)));
+        Self {
+            proxy,
+            sessions,
+            sweeper,
+            tx,
+            rx: Mutex::new(rx),
+        }
+    }
+
+    // 建连期间不持有 sessions 锁，其他目标的包照常发送
+    async fn session(&self, key: &(String, u16)) -> io::Result<Arc<UdpSession>> {
+        if let Some(session) = self.sessions.lock().unwrap().get(key) {
+            return Ok(session.clone());
+        }
+
+        let (address, port) = key;
+        println!("[VMess] {} UDP -> {}:{}", self.proxy.name, address, port);
+        let stream = self.proxy.connect_ws(address, *port, CMD_UDP).await?;
+        let (mut reader, writer) = tokio::io::split(stream);
+        let last_active = Arc::new(std::sync::Mutex::new(Instant::now()));
+        let tx = self.tx.clone();
+        let (host, from_port) = key.clone();
+        let active = last_active.clone();
+        let reader = tokio::spawn(async move {
+            while let Ok(len) = reader.read_u16().await {
+                let mut payload = vec![0u8; len as usize];
+                if reader.read_exact(&mut payload).await.is_err() {
+                    break;
+                }
+                *active.lock().unwrap() = Instant::now();
+                if tx.send((payload, host.clone(), from_port)).await.is_err() {
+                    break;
+                }
+            }
+        });
+        let session = Arc::new(UdpSession {
+            writer: Mutex::new(writer),
+            reader,
+            last_active,
+        });
+
+        // 同一目标并发建连时保留先插入的那条
+        Ok(self.sessions.lock().unwrap().entry(key.clone()).or_insert(session).clone())
+    }
+}
+
+async fn sweep_idle(name: String, sessions: Weak<UdpSessions>) {
+    let mut ticker = tokio::time::interval(UDP_SWEEP_INTERVAL);
+    loop {
+        ticker.tick().await;
+        let Some(sessions) = sessions.upgrade() else { return };
+        sessions.lock().unwrap().retain(|(address, port), session| {
+            let idle = session.is_idle();
+            if idle {
+                println!("[VMess] {} UDP -> {}:{} closed (idle)", name, address, port);
+            }
+            !idle
+        });
+    }
+}
+
+#[async_trait]
+impl OutboundDatagram for VmessDatagram {
+    async fn send_to(&self, data: &[u8], address: &str, port: u16) -> io::Result<()> {
+        let key = (address.to_string(), port);
+        let session = self.session(&key).await?;
+        session.touch();
+
+        let mut frame = Vec::with_capacity(data.len() + 2);
+        frame.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        frame.extend_from_slice(data);
+        if let Err(e) = session.writer.lock().await.write_all(&frame).await {
+            let mut sessions = self.sessions.lock().unwrap();
+            if sessions.get(&key).is_some_and(|current| Arc::ptr_eq(current, &session)) {
+                sessions.remove(&key);
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, String, u16)> {
+        let (payload, host, port) = self.rx.lock().await.recv().await
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "VMess UDP closed"))?;
+        let n = payload.len().min(buf.len());
+        buf[..n].copy_from_slice(&payload[..n]);
+        Ok((n, host, port))
+    }
+}
+
+impl Drop for VmessDatagram {
+    fn drop(&mut self) {
+        self.sweeper.abort();
     }
 }
 
 async fn send_vmess_aead_handshake(
-    stream: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
+    stream: &mut WebSocketStream<AnyStream>,
     uuid: &Uuid,
     target_host: &str,
     target_port: u16,
+    command: u8,
 ) -> io::Result<()> {
-    let payload = build_vmess_aead_request(uuid, target_host, target_port)?;
+    let payload = build_vmess_aead_request(uuid, target_host, target_port, command)?;
     println!("[VMess AEAD] payload len = {}", payload.len());
     stream.send(Message::Binary(payload)).await
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("WS send failed: {}", e)))
+        .map_err(|e| io::Error::other(format!("WS send failed: {}", e)))
 }
 
 async fn send_vmess_legacy_handshake<S: tokio::io::AsyncWrite + Unpin + Send>(
@@ -124,24 +306,21 @@ async fn send_vmess_legacy_handshake<S: tokio::io::AsyncWrite + Unpin + Send>(
     uuid: &Uuid,
     target_host: &str,
     target_port: u16,
+    command: u8,
 ) -> io::Result<()> {
-    let payload = build_vmess_legacy_request(uuid, target_host, target_port)?;
+    let payload = build_vmess_legacy_request(uuid, target_host, target_port, command)?;
     println!("[VMess Legacy] payload len = {}", payload.len());
     stream.write_all(&payload).await?;
     Ok(())
 }
 
-fn build_vmess_aead_request(uuid: &Uuid, target_host: &str, target_port: u16) -> io::Result<Vec<u8>> {
+fn build_vmess_aead_request(uuid: &Uuid, target_host: &str, target_port: u16, command: u8) -> io::Result<Vec<u8>> {
     let timestamp = Utc::now().timestamp() as u32;
     let mut hash_input = uuid.as_bytes().to_vec();
     hash_input.extend_from_slice(&timestamp.to_be_bytes());
     let id = &Sha256::digest(&hash_input)[..16];
 
-    let mut body = Vec::new();
-    body.push(0x01);
-    body.push(0x01);
-    body.push(0x00);
-    body.push(0x03);
+    let mut body = vec![0x01, command, 0x00, 0x03];
     body.push(target_host.len() as u8);
     body.extend_from_slice(target_host.as_bytes());
     body.extend_from_slice(&target_port.to_be_bytes());
@@ -164,7 +343,7 @@ fn build_vmess_aead_request(uuid: &Uuid, target_host: &str, target_port: u16) ->
     let cipher = Aes128Gcm::new_from_slice(&key).unwrap();
     let nonce = Nonce::from_slice(&iv);
     let encrypted = cipher.encrypt(nonce, Payload { msg: &body, aad: &[] })
-        .map_err(|_| io::Error::new(io::ErrorKind::Other, "encrypt failed"))?;
+        .map_err(|_| io::Error::other("encrypt failed"))?;
 
     println!("[VMess AEAD] encrypted len: {}", encrypted.len());
 
@@ -177,7 +356,7 @@ fn build_vmess_aead_request(uuid: &Uuid, target_host: &str, target_port: u16) ->
     Ok(out)
 }
 
-fn build_vmess_legacy_request(uuid: &Uuid, target_host: &str, target_port: u16) -> io::Result<Vec<u8>> {
+fn build_vmess_legacy_request(uuid: &Uuid, target_host: &str, target_port: u16, command: u8) -> io::Result<Vec<u8>> {
     let id = generate_legacy_id(uuid);
 
     println!("[VMess Legacy] id: {:x?}", id);
@@ -185,7 +364,7 @@ fn build_vmess_legacy_request(uuid: &Uuid, target_host: &str, target_port: u16)
     let mut buf = BytesMut::with_capacity(512);
     buf.put_slice(&id);
     buf.put_u8(0x01);
-    buf.put_u8(0x01);
+    buf.put_u8(command);
     buf.put_u8(0x00);
     buf.put_u8(0x00);
 
@@ -204,7 +383,7 @@ fn build_vmess_legacy_request(uuid: &Uuid, target_host: &str, target_port: u16)
     buf.put_u8(0x01); // chunk_stream_mask
 
     println!("[VMess Legacy] full payload = {:?}", buf.to_vec());
-    println!("[VMess Legacy] hex payload = {}", hex::encode(buf.to_vec()));
+    println!("[VMess Legacy] hex payload = {}", hex::encode(&buf));
 
     Ok(buf.to_vec())
 }
@@ -213,7 +392,7 @@ fn generate_legacy_id(uuid: &Uuid) -> [u8; 16] {
     let timestamp = (Utc::now().timestamp() / 60) as u64;
     let mut hasher = Md5::new();
     hasher.update(uuid.as_bytes());
-    hasher.update(&timestamp.to_be_bytes());
+    hasher.update(timestamp.to_be_bytes());
     let result = hasher.finalize();
     let mut id = [0u8; 16];
     id.copy_from_slice(&result[..16]);
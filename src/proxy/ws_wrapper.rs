@@ -1,21 +1,22 @@
 
-use tokio_tungstenite::{WebSocketStream, MaybeTlsStream};
-use tokio::net::TcpStream;
+use tokio_tungstenite::WebSocketStream;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use std::pin::Pin;
 use std::task::{Context, Poll};
-use futures_util::{StreamExt, Sink, SinkExt};
+use futures_util::{Sink, SinkExt};
 use tungstenite::Message;
 use std::io;
 use futures_util::Stream;
 
+use crate::proxy::outbound::AnyStream;
+
 pub struct WsStreamWrapper {
-    ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    ws: WebSocketStream<AnyStream>,
     read_buf: Vec<u8>,
 }
 
 impl WsStreamWrapper {
-    pub fn new(ws: WebSocketStream<MaybeTlsStream<TcpStream>>) -> Self {
+    pub fn new(ws: WebSocketStream<AnyStream>) -> Self {
         Self { ws, read_buf: Vec::new() }
     }
 }
@@ -48,7 +49,7 @@ impl AsyncRead for WsStreamWrapper {
             }
             Poll::Ready(Some(Err(e))) => {
                 println!("[WsWrapper] Error reading from WebSocket: {}", e);
-                Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, e)))
+                Poll::Ready(Err(io::Error::other(e)))
             }
             Poll::Ready(None) => {
                 println!("[WsWrapper] Remote closed connection");
@@ -62,7 +63,7 @@ impl AsyncRead for WsStreamWrapper {
 impl AsyncWrite for WsStreamWrapper {
     fn poll_write(
         mut self: Pin<&mut Self>,
-        cx: &mut Context<'_>,
+        _cx: &mut Context<'_>,
         data: &[u8],
     ) -> Poll<io::Result<usize>> {
         let len = data.len();
@@ -75,7 +76,7 @@ impl AsyncWrite for WsStreamWrapper {
             }
             Err(e) => {
                 println!("[WsWrapper] Error sending to remote: {}", e);
-                Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, e)))
+                Poll::Ready(Err(io::Error::other(e)))
             }
         }
     }
@@ -86,7 +87,7 @@ impl AsyncWrite for WsStreamWrapper {
     ) -> Poll<io::Result<()>> {
         Pin::new(&mut self.ws)
             .poll_flush(cx)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+            .map_err(io::Error::other)
     }
 
     fn poll_shutdown(
@@ -95,6 +96,6 @@ impl AsyncWrite for WsStreamWrapper {
     ) -> Poll<io::Result<()>> {
         Pin::new(&mut self.ws)
             .poll_close(cx)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+            .map_err(io::Error::other)
     }
 }
@@ -0,0 +1,188 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use super::earliest;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn new(addr: IpAddr, prefix: u8) -> Result<Self, String> {
+        let max = bit_len(&addr);
+        if prefix > max {
+            return Err(format!("prefix /{} too long for {}", prefix, addr));
+        }
+        Ok(Self { addr, prefix })
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    // ::ffff:a.b.c.d/96 以上的网段按 IPv4 存，和查询时 to_canonical 后的地址对上
+    fn canonical(&self) -> Self {
+        match self.addr {
+            IpAddr::V6(v6) if self.prefix >= 96 => match v6.to_ipv4_mapped() {
+                Some(v4) => Self {
+                    addr: IpAddr::V4(v4),
+                    prefix: self.prefix - 96,
+                },
+                None => *self,
+            },
+            _ => *self,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid IP address in CIDR: {}", s))?;
+        let prefix = match prefix {
+            Some(p) => p
+                .trim()
+                .parse::<u8>()
+                .map_err(|_| format!("invalid prefix length in CIDR: {}", s))?,
+            None => bit_len(&addr),
+        };
+        Cidr::new(addr, prefix)
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+fn bit_len(addr: &IpAddr) -> u8 {
+    if addr.is_ipv4() { 32 } else { 128 }
+}
+
+// 地址左对齐到 128 位，v4/v6 共用同一套位运算
+fn to_bits(addr: &IpAddr) -> u128 {
+    match addr {
+        IpAddr::V4(v4) => (u32::from(*v4) as u128) << 96,
+        IpAddr::V6(v6) => u128::from(*v6),
+    }
+}
+
+#[derive(Default)]
+struct Node {
+    children: [Option<Box<Node>>; 2],
+    value: Option<usize>,
+}
+
+/// 按位前缀树，查找时返回所有命中网段中最小的 value（即最靠前的规则序号）。
+#[derive(Default)]
+pub struct CidrTable {
+    v4: Node,
+    v6: Node,
+    len: usize,
+}
+
+impl CidrTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, cidr: &Cidr, value: usize) {
+        let cidr = cidr.canonical();
+        let bits = to_bits(&cidr.addr);
+        let mut node = if cidr.addr.is_ipv4() { &mut self.v4 } else { &mut self.v6 };
+        for i in 0..cidr.prefix as u32 {
+            let bit = ((bits >> (127 - i)) & 1) as usize;
+            node = node.children[bit].get_or_insert_with(Box::default);
+        }
+        node.value = Some(node.value.map_or(value, |v| v.min(value)));
+        self.len += 1;
+    }
+
+    pub fn lookup(&self, ip: &IpAddr) -> Option<usize> {
+        let ip = ip.to_canonical();
+        let bits = to_bits(&ip);
+        let (mut node, len) = if ip.is_ipv4() { (&self.v4, 32) } else { (&self.v6, 128) };
+        let mut best = node.value;
+        for i in 0..len {
+            let bit = ((bits >> (127 - i)) & 1) as usize;
+            match &node.children[bit] {
+                Some(child) => node = child,
+                None => break,
+            }
+            best = earliest(best, node.value);
+        }
+        best
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(cidrs: &[&str]) -> CidrTable {
+        let mut table = CidrTable::new();
+        for (index, cidr) in cidrs.iter().enumerate() {
+            table.insert(&cidr.parse().unwrap(), index);
+        }
+        table
+    }
+
+    fn lookup(table: &CidrTable, ip: &str) -> Option<usize> {
+        table.lookup(&ip.parse().unwrap())
+    }
+
+    #[test]
+    fn earliest_matching_prefix_wins() {
+        let table = table(&["10.1.0.0/16", "10.0.0.0/8", "10.1.2.0/24", "0.0.0.0/0"]);
+        assert_eq!(lookup(&table, "10.1.2.3"), Some(0));
+        assert_eq!(lookup(&table, "10.2.0.1"), Some(1));
+        assert_eq!(lookup(&table, "192.168.1.1"), Some(3));
+        assert_eq!(lookup(&table, "2001:db8::1"), None);
+    }
+
+    #[test]
+    fn v4_and_v6_are_separate() {
+        let table = table(&["2001:db8::/32", "192.168.0.0/16"]);
+        assert_eq!(lookup(&table, "2001:db8:1::1"), Some(0));
+        assert_eq!(lookup(&table, "2001:db9::1"), None);
+        assert_eq!(lookup(&table, "192.168.3.4"), Some(1));
+        // 查询时 v4-mapped 地址按 IPv4 匹配
+        assert_eq!(lookup(&table, "::ffff:192.168.3.4"), Some(1));
+    }
+
+    #[test]
+    fn v4_mapped_cidr_matches_ipv4() {
+        let table = table(&["::ffff:10.0.0.0/104", "::ffff:0:0/96"]);
+        assert_eq!(lookup(&table, "10.9.8.7"), Some(0));
+        assert_eq!(lookup(&table, "::ffff:10.9.8.7"), Some(0));
+        assert_eq!(lookup(&table, "11.0.0.1"), Some(1));
+    }
+
+    #[test]
+    fn invalid_cidr_is_rejected() {
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("::/129".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+        assert_eq!("10.0.0.1".parse::<Cidr>().unwrap().prefix(), 32);
+    }
+}
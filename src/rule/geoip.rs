@@ -0,0 +1,109 @@
+use std::fs;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use super::mmdb::Reader;
+
+pub const DEFAULT_PATH: &str = "Country.mmdb";
+// 每隔这么久检查一次文件是否更新
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+struct Loaded {
+    reader: Arc<Reader>,
+    modified: Option<SystemTime>,
+}
+
+/// GEOIP 规则用的国家数据库：只有规则里出现 GEOIP 时才读文件。
+/// 读文件都在后台线程里完成，查询时只读取已加载的数据。
+pub struct GeoIp {
+    path: PathBuf,
+    loaded: RwLock<Option<Loaded>>,
+    // 编译到 GEOIP 规则时置位
+    wanted: AtomicBool,
+}
+
+impl GeoIp {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: PathBuf::from(path),
+            loaded: RwLock::new(None),
+            wanted: AtomicBool::new(false),
+        }
+    }
+
+    pub fn require(&self) {
+        self.wanted.store(true, Ordering::Relaxed);
+    }
+
+    // 启动时在开始接受连接之前加载一次，之后定期检查文件更新。
+    // 运行中才出现的 GEOIP 规则（rule-provider 更新）在下次检查时加载
+    pub async fn start(self: &Arc<Self>) {
+        self.refresh().await;
+        let geoip = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(RELOAD_CHECK_INTERVAL);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                geoip.refresh().await;
+            }
+        });
+    }
+
+    // 返回大写的国家代码，查不到或数据库不可用时返回 None
+    pub fn country(&self, ip: &IpAddr) -> Option<String> {
+        let reader = self.loaded.read().unwrap().as_ref()?.reader.clone();
+        match reader.lookup(ip) {
+            Ok(record) => {
+                let record = record?;
+                // 部分数据库（如仅含 registered_country 的地址）没有 country
+                let country = record.get("country").or_else(|| record.get("registered_country"))?;
+                Some(country.get("iso_code")?.as_str()?.to_ascii_uppercase())
+            }
+            Err(e) => {
+                eprintln!("[GeoIP] Lookup {} failed: {}", ip, e);
+                None
+            }
+        }
+    }
+
+    async fn refresh(self: &Arc<Self>) {
+        if !self.wanted.load(Ordering::Relaxed) {
+            return;
+        }
+        let geoip = self.clone();
+        let _ = tokio::task::spawn_blocking(move || geoip.reload()).await;
+    }
+
+    fn reload(&self) {
+        let modified = fs::metadata(&self.path).and_then(|m| m.modified()).ok();
+        if let Some(loaded) = self.loaded.read().unwrap().as_ref()
+            && loaded.modified == modified
+        {
+            return;
+        }
+
+        let reader = fs::read(&self.path)
+            .map_err(|e| e.to_string())
+            .and_then(Reader::from_bytes);
+        match reader {
+            Ok(reader) => {
+                let reloaded = self.loaded.read().unwrap().is_some();
+                println!(
+                    "[GeoIP] {} {}",
+                    if reloaded { "Reloaded" } else { "Loaded" },
+                    self.path.display()
+                );
+                *self.loaded.write().unwrap() = Some(Loaded {
+                    reader: Arc::new(reader),
+                    modified,
+                });
+            }
+            // 加载失败时继续用旧数据库
+            Err(e) => eprintln!("[GeoIP] Cannot load {}: {}", self.path.display(), e),
+        }
+    }
+}
@@ -0,0 +1,392 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+pub const DEFAULT_PATH: &str = "geosite.dat";
+
+#[derive(Debug, Clone)]
+pub enum SiteDomain {
+    Suffix(String),
+    Full(String),
+    Keyword(String),
+    // 原样保存，编译规则时再构造 Regex
+    Regex(String),
+}
+
+#[derive(Debug, Clone)]
+struct SiteEntry {
+    domain: SiteDomain,
+    attrs: Vec<String>,
+}
+
+/// GEOSITE 数据来源：v2ray 的 geosite.dat，或 domain-list-community 格式的文本目录（每个分类一个文件）。
+/// 只在规则里出现 GEOSITE 时才读取。
+pub struct GeoSite {
+    path: PathBuf,
+    dat: OnceLock<Result<Dat, String>>,
+}
+
+// geosite.dat 的内容和 分类 -> 该分类消息在文件中的范围
+type Dat = (Vec<u8>, HashMap<String, (usize, usize)>);
+
+impl GeoSite {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: PathBuf::from(path),
+            dat: OnceLock::new(),
+        }
+    }
+
+    // "google" 或 "google@cn"（只取带 cn 属性的域名）
+    pub fn load(&self, category: &str) -> Result<Vec<SiteDomain>, String> {
+        let (name, attr) = match category.split_once('@') {
+            Some((name, attr)) => (name, Some(attr.to_ascii_lowercase())),
+            None => (category, None),
+        };
+        let entries = if self.path.is_dir() {
+            load_text(&self.path, &name.to_ascii_lowercase(), &mut HashSet::new())?
+        } else {
+            self.load_dat(name)?
+        };
+        Ok(entries
+            .into_iter()
+            .filter(|entry| attr.as_ref().is_none_or(|attr| entry.attrs.contains(attr)))
+            .map(|entry| entry.domain)
+            .collect())
+    }
+
+    fn load_dat(&self, name: &str) -> Result<Vec<SiteEntry>, String> {
+        let dat = self.dat.get_or_init(|| {
+            let buf = fs::read(&self.path).map_err(|e| format!("cannot read {}: {}", self.path.display(), e))?;
+            let index = index_dat(&buf)?;
+            println!("[GeoSite] Loaded {} ({} categories)", self.path.display(), index.len());
+            Ok((buf, index))
+        });
+        let (buf, index) = dat.as_ref().map_err(Clone::clone)?;
+        let &(start, end) = index
+            .get(&name.to_ascii_uppercase())
+            .ok_or_else(|| format!("geosite category {} not found in {}", name, self.path.display()))?;
+        parse_site(&buf[start..end])
+    }
+}
+
+// domain-list-community 文本格式：
+//   example.com          后缀
+//   full:www.example.com 完整域名
+//   keyword:example      关键字
+//   regexp:^ex.*\.com$   正则
+//   include:other        引入其他分类
+// 行尾可以带 @属性，# 开始的是注释
+fn load_text(dir: &Path, name: &str, visited: &mut HashSet<String>) -> Result<Vec<SiteEntry>, String> {
+    if !visited.insert(name.to_string()) {
+        return Ok(Vec::new());
+    }
+    let path = dir.join(name);
+    let content = fs::read_to_string(&path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+
+    let mut entries = Vec::new();
+    for line in content.lines() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        let mut tokens = line.split_whitespace();
+        let Some(rule) = tokens.next() else { continue };
+        let attrs: Vec<String> = tokens
+            .filter_map(|t| t.strip_prefix('@'))
+            .map(|t| t.to_ascii_lowercase())
+            .collect();
+
+        let (kind, value) = rule.split_once(':').unwrap_or(("domain", rule));
+        let domain = match kind {
+            "domain" => SiteDomain::Suffix(value.to_ascii_lowercase()),
+            "full" => SiteDomain::Full(value.to_ascii_lowercase()),
+            "keyword" => SiteDomain::Keyword(value.to_ascii_lowercase()),
+            "regexp" => SiteDomain::Regex(value.to_string()),
+            "include" => {
+                // include:other @attr 只引入带该属性的条目
+                let included = load_text(dir, &value.to_ascii_lowercase(), visited)?;
+                entries.extend(
+                    included
+                        .into_iter()
+                        .filter(|entry| attrs.iter().all(|attr| entry.attrs.contains(attr))),
+                );
+                continue;
+            }
+            other => return Err(format!("unknown entry type {} in {}", other, path.display())),
+        };
+        entries.push(SiteEntry { domain, attrs });
+    }
+    Ok(entries)
+}
+
+// geosite.dat 是 protobuf：
+//   GeoSiteList { repeated GeoSite entry = 1; }
+//   GeoSite { string country_code = 1; repeated Domain domain = 2; }
+//   Domain { Type type = 1; string value = 2; repeated Attribute attribute = 3; }
+//   Attribute { string key = 1; ... }
+fn index_dat(buf: &[u8]) -> Result<HashMap<String, (usize, usize)>, String> {
+    let mut index = HashMap::new();
+    let mut reader = Proto::new(buf);
+    while let Some((field, value)) = reader.next()? {
+        if field != 1 {
+            continue;
+        }
+        let FieldValue::Bytes(site) = value else { continue };
+        let start = site.as_ptr() as usize - buf.as_ptr() as usize;
+
+        let mut fields = Proto::new(site);
+        while let Some((field, value)) = fields.next()? {
+            if field == 1
+                && let FieldValue::Bytes(code) = value
+            {
+                let code = String::from_utf8_lossy(code).to_ascii_uppercase();
+                index.insert(code, (start, start + site.len()));
+                break;
+            }
+        }
+    }
+    Ok(index)
+}
+
+fn parse_site(site: &[u8]) -> Result<Vec<SiteEntry>, String> {
+    let mut entries = Vec::new();
+    let mut fields = Proto::new(site);
+    while let Some((field, value)) = fields.next()? {
+        let (2, FieldValue::Bytes(domain)) = (field, value) else {
+            continue;
+        };
+
+        let mut kind = 0;
+        let mut text = String::new();
+        let mut attrs = Vec::new();
+        let mut domain_fields = Proto::new(domain);
+        while let Some((field, value)) = domain_fields.next()? {
+            match (field, value) {
+                (1, FieldValue::Varint(v)) => kind = v,
+                (2, FieldValue::Bytes(v)) => text = String::from_utf8_lossy(v).into_owned(),
+                (3, FieldValue::Bytes(attr)) => {
+                    let mut attr_fields = Proto::new(attr);
+                    while let Some((field, value)) = attr_fields.next()? {
+                        if let (1, FieldValue::Bytes(key)) = (field, value) {
+                            attrs.push(String::from_utf8_lossy(key).to_ascii_lowercase());
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        // Type: Plain = 0（关键字）, Regex = 1, Domain = 2（后缀）, Full = 3
+        let domain = match kind {
+            0 => SiteDomain::Keyword(text.to_ascii_lowercase()),
+            1 => SiteDomain::Regex(text),
+            2 => SiteDomain::Suffix(text.to_ascii_lowercase()),
+            3 => SiteDomain::Full(text.to_ascii_lowercase()),
+            other => return Err(format!("unknown geosite domain type {}", other)),
+        };
+        entries.push(SiteEntry { domain, attrs });
+    }
+    Ok(entries)
+}
+
+enum FieldValue<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    Fixed,
+}
+
+// 只需要 varint 和 length-delimited 两种字段，定长字段跳过
+struct Proto<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Proto<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    fn next(&mut self) -> Result<Option<(u64, FieldValue<'a>)>, String> {
+        if self.pos >= self.buf.len() {
+            return Ok(None);
+        }
+        let key = self.varint()?;
+        let value = match key & 0x07 {
+            0 => FieldValue::Varint(self.varint()?),
+            1 => {
+                self.skip(8)?;
+                FieldValue::Fixed
+            }
+            2 => {
+                let len = self.varint()? as usize;
+                let start = self.pos;
+                self.skip(len)?;
+                FieldValue::Bytes(&self.buf[start..start + len])
+            }
+            5 => {
+                self.skip(4)?;
+                FieldValue::Fixed
+            }
+            other => return Err(format!("unsupported protobuf wire type {}", other)),
+        };
+        Ok(Some((key >> 3, value)))
+    }
+
+    fn varint(&mut self) -> Result<u64, String> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = *self.buf.get(self.pos).ok_or("truncated protobuf varint")?;
+            self.pos += 1;
+            value |= ((byte & 0x7F) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err("protobuf varint too long".to_string())
+    }
+
+    fn skip(&mut self, len: usize) -> Result<(), String> {
+        if self.buf.len() - self.pos < len {
+            return Err("truncated protobuf field".to_string());
+        }
+        self.pos += len;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(test: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("clash-rs-geosite-{}-{}", std::process::id(), test));
+        let _ = fs::remove_dir_all(&path);
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn names(domains: &[SiteDomain]) -> Vec<String> {
+        domains
+            .iter()
+            .map(|d| match d {
+                SiteDomain::Suffix(v) => format!("domain:{}", v),
+                SiteDomain::Full(v) => format!("full:{}", v),
+                SiteDomain::Keyword(v) => format!("keyword:{}", v),
+                SiteDomain::Regex(v) => format!("regexp:{}", v),
+            })
+            .collect()
+    }
+
+    fn varint(out: &mut Vec<u8>, mut v: u64) {
+        while v >= 0x80 {
+            out.push(v as u8 | 0x80);
+            v >>= 7;
+        }
+        out.push(v as u8);
+    }
+
+    fn bytes_field(out: &mut Vec<u8>, field: u64, data: &[u8]) {
+        varint(out, field << 3 | 2);
+        varint(out, data.len() as u64);
+        out.extend_from_slice(data);
+    }
+
+    fn domain(kind: u64, value: &str, attrs: &[&str]) -> Vec<u8> {
+        let mut out = Vec::new();
+        varint(&mut out, 1 << 3);
+        varint(&mut out, kind);
+        bytes_field(&mut out, 2, value.as_bytes());
+        for attr in attrs {
+            let mut attribute = Vec::new();
+            bytes_field(&mut attribute, 1, attr.as_bytes());
+            // bool_value = 2，解析时应跳过
+            varint(&mut attribute, 2 << 3);
+            varint(&mut attribute, 1);
+            bytes_field(&mut out, 3, &attribute);
+        }
+        out
+    }
+
+    fn site(code: &str, domains: &[Vec<u8>]) -> Vec<u8> {
+        let mut out = Vec::new();
+        bytes_field(&mut out, 1, code.as_bytes());
+        for domain in domains {
+            bytes_field(&mut out, 2, domain);
+        }
+        out
+    }
+
+    fn dat_fixture() -> Vec<u8> {
+        let mut out = Vec::new();
+        bytes_field(
+            &mut out,
+            1,
+            &site(
+                "example",
+                &[
+                    domain(2, "Example.com", &[]),
+                    domain(3, "www.example.net", &["cn"]),
+                    domain(0, "exam", &["ads", "cn"]),
+                    domain(1, r"^ex\d+\.org$", &[]),
+                ],
+            ),
+        );
+        bytes_field(&mut out, 1, &site("OTHER", &[domain(2, "other.com", &[])]));
+        out
+    }
+
+    #[test]
+    fn loads_categories_from_dat() {
+        let path = temp_path("dat");
+        fs::write(&path, dat_fixture()).unwrap();
+        let geosite = GeoSite::new(path.to_str().unwrap());
+
+        assert_eq!(
+            names(&geosite.load("EXAMPLE").unwrap()),
+            ["domain:example.com", "full:www.example.net", "keyword:exam", r"regexp:^ex\d+\.org$"]
+        );
+        assert_eq!(names(&geosite.load("example@CN").unwrap()), ["full:www.example.net", "keyword:exam"]);
+        assert_eq!(names(&geosite.load("other").unwrap()), ["domain:other.com"]);
+        assert!(geosite.load("missing").is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn truncated_dat_is_an_error() {
+        let path = temp_path("truncated");
+        let mut dat = dat_fixture();
+        dat.truncate(dat.len() - 3);
+        fs::write(&path, dat).unwrap();
+        assert!(GeoSite::new(path.to_str().unwrap()).load("example").is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn text_include_and_attributes() {
+        let dir = temp_path("text");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("example"),
+            "# comment\nexample.com\nfull:www.example.net @cn\nregexp:^ex\\d+\\.org$\ninclude:ads @ads\ninclude:example\n",
+        )
+        .unwrap();
+        fs::write(dir.join("ads"), "ads.example.com @ads @cn\nkeyword:tracker\n").unwrap();
+        let geosite = GeoSite::new(dir.to_str().unwrap());
+
+        // include 带属性时只引入有该属性的条目；重复 include 自身不会死循环
+        assert_eq!(
+            names(&geosite.load("Example").unwrap()),
+            [
+                "domain:example.com",
+                "full:www.example.net",
+                r"regexp:^ex\d+\.org$",
+                "domain:ads.example.com"
+            ]
+        );
+        assert_eq!(
+            names(&geosite.load("example@cn").unwrap()),
+            ["full:www.example.net", "domain:ads.example.com"]
+        );
+        assert!(geosite.load("missing").is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
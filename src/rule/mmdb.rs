@@ -0,0 +1,360 @@
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+
+// MaxMind DB 格式：二叉搜索树 + 16 字节分隔 + 数据段，文件末尾是元数据
+// https://maxmind.github.io/MaxMind-DB/
+const METADATA_MARKER: &[u8] = b"\xAB\xCD\xEFMaxMind.com";
+const DATA_SEPARATOR: usize = 16;
+// map / array 的最大嵌套层数，防止损坏的文件（如指回自身的 map）无限递归
+const MAX_DEPTH: usize = 32;
+
+// 规则只用到 map、字符串和无符号整数（元数据），其余类型校验长度后跳过
+#[derive(Debug)]
+pub enum Value {
+    String(String),
+    Uint(u64),
+    Map(BTreeMap<String, Value>),
+    Other,
+}
+
+impl Value {
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Map(map) => map.get(key),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn as_uint(&self) -> Option<u64> {
+        match self {
+            Value::Uint(n) => Some(*n),
+            _ => None,
+        }
+    }
+}
+
+pub struct Reader {
+    buf: Vec<u8>,
+    node_count: usize,
+    record_size: usize,
+    ip_version: u64,
+    data_start: usize,
+    // IPv6 树里 ::/96 对应的节点，IPv4 地址从这里开始查
+    ipv4_start: usize,
+}
+
+impl Reader {
+    pub fn from_bytes(buf: Vec<u8>) -> Result<Self, String> {
+        let marker = buf
+            .windows(METADATA_MARKER.len())
+            .rposition(|w| w == METADATA_MARKER)
+            .ok_or("mmdb metadata not found")?;
+        let metadata = Decoder {
+            buf: &buf,
+            base: marker + METADATA_MARKER.len(),
+        }
+        .decode(marker + METADATA_MARKER.len())?
+        .0;
+
+        let field = |name: &str| {
+            metadata
+                .get(name)
+                .and_then(Value::as_uint)
+                .ok_or_else(|| format!("mmdb metadata missing {}", name))
+        };
+        let node_count = field("node_count")? as usize;
+        let record_size = field("record_size")? as usize;
+        let ip_version = field("ip_version")?;
+        if !matches!(record_size, 24 | 28 | 32) {
+            return Err(format!("unsupported mmdb record size {}", record_size));
+        }
+
+        let tree_size = node_count * record_size / 4;
+        if tree_size + DATA_SEPARATOR > marker {
+            return Err("mmdb search tree exceeds file size".to_string());
+        }
+
+        let mut reader = Self {
+            buf,
+            node_count,
+            record_size,
+            ip_version,
+            data_start: tree_size + DATA_SEPARATOR,
+            ipv4_start: 0,
+        };
+        if ip_version == 6 {
+            let mut node = 0;
+            for _ in 0..96 {
+                if node >= node_count {
+                    break;
+                }
+                node = reader.record(node, 0);
+            }
+            reader.ipv4_start = node;
+        }
+        Ok(reader)
+    }
+
+    pub fn lookup(&self, ip: &IpAddr) -> Result<Option<Value>, String> {
+        let (bits, len, mut node) = match ip.to_canonical() {
+            IpAddr::V4(v4) => ((u32::from(v4) as u128) << 96, 32, self.ipv4_start),
+            IpAddr::V6(v6) if self.ip_version == 6 => (u128::from(v6), 128, 0),
+            IpAddr::V6(_) => return Ok(None),
+        };
+
+        for i in 0..len {
+            if node >= self.node_count {
+                break;
+            }
+            let bit = (bits >> (127 - i)) & 1;
+            node = self.record(node, bit as usize);
+        }
+
+        if node == self.node_count {
+            return Ok(None);
+        }
+        if node < self.node_count {
+            return Err("mmdb search tree is invalid".to_string());
+        }
+        let offset = self.data_start + (node - self.node_count) - DATA_SEPARATOR;
+        let decoder = Decoder {
+            buf: &self.buf,
+            base: self.data_start,
+        };
+        decoder.decode(offset).map(|(value, _)| Some(value))
+    }
+
+    // 节点 node 的左 (0) / 右 (1) 记录
+    fn record(&self, node: usize, side: usize) -> usize {
+        let b = &self.buf;
+        match self.record_size {
+            24 => {
+                let p = node * 6 + side * 3;
+                be(&b[p..p + 3])
+            }
+            28 => {
+                let p = node * 7;
+                if side == 0 {
+                    ((b[p + 3] as usize & 0xF0) << 20) | be(&b[p..p + 3])
+                } else {
+                    ((b[p + 3] as usize & 0x0F) << 24) | be(&b[p + 4..p + 7])
+                }
+            }
+            _ => {
+                let p = node * 8 + side * 4;
+                be(&b[p..p + 4])
+            }
+        }
+    }
+}
+
+struct Decoder<'a> {
+    buf: &'a [u8],
+    // 指针相对的起点：数据段开头，或元数据开头
+    base: usize,
+}
+
+impl Decoder<'_> {
+    // 返回 (值, 下一个字段的位置)
+    fn decode(&self, pos: usize) -> Result<(Value, usize), String> {
+        self.decode_at(pos, 0)
+    }
+
+    fn decode_at(&self, pos: usize, depth: usize) -> Result<(Value, usize), String> {
+        if depth > MAX_DEPTH {
+            return Err("mmdb data nested too deeply".to_string());
+        }
+        let ctrl = self.byte(pos)?;
+        let mut pos = pos + 1;
+        let mut kind = ctrl >> 5;
+
+        if kind == 1 {
+            let ss = (ctrl >> 3) & 0x03;
+            let vvv = (ctrl & 0x07) as usize;
+            let (target, next) = match ss {
+                0 => ((vvv << 8) | self.byte(pos)? as usize, pos + 1),
+                1 => (((vvv << 16) | be(self.slice(pos, 2)?)) + 2048, pos + 2),
+                2 => (((vvv << 24) | be(self.slice(pos, 3)?)) + 526336, pos + 3),
+                _ => (be(self.slice(pos, 4)?), pos + 4),
+            };
+            // 指针不能再指向指针，否则损坏的文件会让解码无限递归
+            if self.byte(self.base + target)? >> 5 == 1 {
+                return Err("mmdb pointer points to a pointer".to_string());
+            }
+            // 指针指向的值解码完后，从指针之后继续
+            let (value, _) = self.decode_at(self.base + target, depth + 1)?;
+            return Ok((value, next));
+        }
+
+        if kind == 0 {
+            kind = self
+                .byte(pos)?
+                .checked_add(7)
+                .ok_or("invalid mmdb extended type")?;
+            pos += 1;
+        }
+
+        let mut size = (ctrl & 0x1F) as usize;
+        match size {
+            29 => {
+                size = 29 + self.byte(pos)? as usize;
+                pos += 1;
+            }
+            30 => {
+                size = 285 + be(self.slice(pos, 2)?);
+                pos += 2;
+            }
+            31 => {
+                size = 65821 + be(self.slice(pos, 3)?);
+                pos += 3;
+            }
+            _ => {}
+        }
+
+        match kind {
+            2 => {
+                let s = String::from_utf8_lossy(self.slice(pos, size)?).into_owned();
+                Ok((Value::String(s), pos + size))
+            }
+            3 => Ok((Value::Other, pos + self.slice(pos, 8)?.len())),
+            4 => Ok((Value::Other, pos + self.slice(pos, size)?.len())),
+            5 | 6 | 9 | 10 => {
+                let bytes = self.slice(pos, size)?;
+                let n = bytes.iter().fold(0u128, |n, b| (n << 8) | *b as u128);
+                Ok((Value::Uint(n as u64), pos + size))
+            }
+            7 => {
+                let mut map = BTreeMap::new();
+                for _ in 0..size {
+                    let (key, next) = self.decode_at(pos, depth + 1)?;
+                    let (value, next) = self.decode_at(next, depth + 1)?;
+                    pos = next;
+                    if let Value::String(key) = key {
+                        map.insert(key, value);
+                    }
+                }
+                Ok((Value::Map(map), pos))
+            }
+            8 => Ok((Value::Other, pos + self.slice(pos, size)?.len())),
+            // 数组元素个数来自文件，逐个解码跳过，不按它预分配
+            11 => {
+                for _ in 0..size {
+                    pos = self.decode_at(pos, depth + 1)?.1;
+                }
+                Ok((Value::Other, pos))
+            }
+            14 => Ok((Value::Other, pos)),
+            15 => Ok((Value::Other, pos + self.slice(pos, 4)?.len())),
+            other => Err(format!("unsupported mmdb data type {}", other)),
+        }
+    }
+
+    fn byte(&self, pos: usize) -> Result<u8, String> {
+        self.buf.get(pos).copied().ok_or_else(|| "mmdb data truncated".to_string())
+    }
+
+    fn slice(&self, pos: usize, len: usize) -> Result<&[u8], String> {
+        self.buf.get(pos..pos + len).ok_or_else(|| "mmdb data truncated".to_string())
+    }
+}
+
+fn be(bytes: &[u8]) -> usize {
+    bytes.iter().fold(0, |n, b| (n << 8) | *b as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 手工拼出的 IPv4 数据库：只有一个节点，0.0.0.0/1 -> {country: {iso_code: "AU"}}，128.0.0.0/1 没有数据
+    fn fixture() -> Vec<u8> {
+        let mut buf = Vec::new();
+        // 搜索树，24 位记录：左 = node_count + 16 + 数据偏移 0，右 = node_count 表示没有数据
+        buf.extend_from_slice(&[0x00, 0x00, 0x11, 0x00, 0x00, 0x01]);
+        buf.extend_from_slice(&[0u8; DATA_SEPARATOR]);
+        // 数据段
+        buf.push(0xE1);
+        buf.push(0x47);
+        buf.extend_from_slice(b"country");
+        buf.push(0xE1);
+        buf.push(0x48);
+        buf.extend_from_slice(b"iso_code");
+        buf.push(0x42);
+        buf.extend_from_slice(b"AU");
+        // 元数据
+        buf.extend_from_slice(METADATA_MARKER);
+        buf.push(0xE3);
+        buf.push(0x4A);
+        buf.extend_from_slice(b"node_count");
+        buf.extend_from_slice(&[0xC1, 0x01]);
+        buf.push(0x4B);
+        buf.extend_from_slice(b"record_size");
+        buf.extend_from_slice(&[0xA1, 0x18]);
+        buf.push(0x4A);
+        buf.extend_from_slice(b"ip_version");
+        buf.extend_from_slice(&[0xA1, 0x04]);
+        buf
+    }
+
+    fn iso_code(reader: &Reader, ip: &str) -> Option<String> {
+        let record = reader.lookup(&ip.parse().unwrap()).unwrap()?;
+        Some(record.get("country")?.get("iso_code")?.as_str()?.to_string())
+    }
+
+    #[test]
+    fn lookup_fixture() {
+        let reader = Reader::from_bytes(fixture()).unwrap();
+        assert_eq!(iso_code(&reader, "1.2.3.4").as_deref(), Some("AU"));
+        assert_eq!(iso_code(&reader, "127.255.0.1").as_deref(), Some("AU"));
+        assert_eq!(iso_code(&reader, "200.1.1.1"), None);
+        assert_eq!(iso_code(&reader, "::1"), None);
+    }
+
+    #[test]
+    fn rejects_truncated_file() {
+        let mut buf = fixture();
+        buf.truncate(10);
+        assert!(Reader::from_bytes(buf).is_err());
+    }
+
+    #[test]
+    fn extended_type_overflow_is_an_error() {
+        let buf = [0x00, 0xFF];
+        let decoder = Decoder { buf: &buf, base: 0 };
+        assert!(decoder.decode(0).is_err());
+    }
+
+    #[test]
+    fn self_referencing_map_is_an_error() {
+        // {"a": <指向偏移 0 的指针>}，也就是指回自身
+        let buf = [0xE1, 0x41, b'a', 0x20, 0x00];
+        let decoder = Decoder { buf: &buf, base: 0 };
+        assert!(decoder.decode(0).is_err());
+    }
+
+    #[test]
+    fn huge_array_size_is_not_trusted() {
+        // 声明约 1680 万个元素的数组，后面没有数据
+        let buf = [0x1F, 0x04, 0xFF, 0xFF, 0xFF];
+        let decoder = Decoder { buf: &buf, base: 0 };
+        assert!(decoder.decode(0).is_err());
+    }
+
+    #[test]
+    fn skipped_types_advance_past_their_data() {
+        // {"d": double 1.0, "s": "x"}：跳过 double 之后还能读到后面的字符串
+        let mut buf = vec![0xE2, 0x41, b'd', 0x68];
+        buf.extend_from_slice(&1.0f64.to_be_bytes());
+        buf.extend_from_slice(&[0x41, b's', 0x41, b'x']);
+        let decoder = Decoder { buf: &buf, base: 0 };
+        let (value, _) = decoder.decode(0).unwrap();
+        assert_eq!(value.get("s").and_then(Value::as_str), Some("x"));
+    }
+}
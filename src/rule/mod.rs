@@ -0,0 +1,470 @@
+pub mod cidr;
+pub mod geoip;
+pub mod geosite;
+pub mod mmdb;
+pub mod provider;
+pub mod trie;
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+
+use regex::Regex;
+
+use cidr::{Cidr, CidrTable};
+use geoip::GeoIp;
+use geosite::{GeoSite, SiteDomain};
+use provider::RuleProvider;
+use trie::DomainTrie;
+
+#[derive(Debug, Clone)]
+pub struct Metadata {
+    pub host: String,
+    // 目标本身是 IP，或为匹配 IP 规则解析出的地址（此时 host 仍是域名）
+    pub dst_ip: Option<IpAddr>,
+    pub dst_port: u16,
+    pub src_addr: Option<SocketAddr>,
+    // 入站认证通过的用户名
+    pub user: Option<String>,
+    // host 由 redir-host 缓存反查得到，dst_ip 是连接的真实目标
+    pub sniffed: bool,
+}
+
+impl Metadata {
+    pub fn new(host: &str, dst_port: u16, src_addr: Option<SocketAddr>) -> Self {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        Self {
+            host: host.to_string(),
+            dst_ip: host.parse().ok(),
+            dst_port,
+            src_addr,
+            user: None,
+            sniffed: false,
+        }
+    }
+
+    pub fn with_user(mut self, user: Option<String>) -> Self {
+        self.user = user;
+        self
+    }
+
+    // 目标是域名时返回域名，目标本身是 IP 时返回 None
+    pub fn domain(&self) -> Option<&str> {
+        if self.host.is_empty() || self.host.parse::<IpAddr>().is_ok() {
+            None
+        } else {
+            Some(&self.host)
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Rule {
+    Domain(String),
+    DomainSuffix(String),
+    DomainKeyword(String),
+    // 分类名，可带 @属性
+    GeoSite(String),
+    // 第二个字段为 no-resolve：域名目标不为这条规则做解析
+    IpCidr(Cidr, bool),
+    // 国家代码（大写），no-resolve
+    GeoIp(String, bool),
+    SrcIpCidr(Cidr),
+    DstPort(RangeInclusive<u16>),
+    SrcPort(RangeInclusive<u16>),
+    InUser(Vec<String>),
+    // provider 名，no-resolve
+    Provider(String, bool),
+    Match,
+}
+
+#[derive(Debug, Clone)]
+pub struct RuleEntry {
+    pub rule: Rule,
+    pub policy: String,
+    pub raw: String,
+}
+
+impl RuleEntry {
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let parts: Vec<&str> = line.split(',').map(str::trim).collect();
+        let kind = parts[0].to_ascii_uppercase();
+
+        if kind == "MATCH" || kind == "FINAL" {
+            let policy = parts.get(1).ok_or_else(|| format!("missing policy: {}", line))?;
+            return Ok(Self {
+                rule: Rule::Match,
+                policy: policy.to_string(),
+                raw: line.to_string(),
+            });
+        }
+
+        if parts.len() < 3 {
+            return Err(format!("malformed rule: {}", line));
+        }
+        let payload = parts[1];
+        let policy = parts[2].to_string();
+        let no_resolve = parts[3..].iter().any(|p| p.eq_ignore_ascii_case("no-resolve"));
+
+        let rule = match kind.as_str() {
+            "DOMAIN" => Rule::Domain(trie::normalize(payload)),
+            "DOMAIN-SUFFIX" => Rule::DomainSuffix(trie::normalize(payload)),
+            "DOMAIN-KEYWORD" => Rule::DomainKeyword(payload.to_ascii_lowercase()),
+            "GEOSITE" => Rule::GeoSite(payload.to_string()),
+            "IP-CIDR" | "IP-CIDR6" => Rule::IpCidr(payload.parse()?, no_resolve),
+            "GEOIP" => Rule::GeoIp(payload.to_ascii_uppercase(), no_resolve),
+            "SRC-IP-CIDR" => Rule::SrcIpCidr(payload.parse()?),
+            "DST-PORT" => Rule::DstPort(parse_port_range(payload)?),
+            "SRC-PORT" => Rule::SrcPort(parse_port_range(payload)?),
+            "RULE-SET" => Rule::Provider(payload.to_string(), no_resolve),
+            "IN-USER" => Rule::InUser(payload.split('/').map(|u| u.trim().to_string()).collect()),
+            other => return Err(format!("unsupported rule type {}: {}", other, line)),
+        };
+
+        Ok(Self {
+            rule,
+            policy,
+            raw: line.to_string(),
+        })
+    }
+}
+
+fn parse_port_range(s: &str) -> Result<RangeInclusive<u16>, String> {
+    let parse = |p: &str| p.trim().parse::<u16>().map_err(|_| format!("invalid port: {}", s));
+    match s.split_once('-') {
+        Some((start, end)) => {
+            let (start, end) = (parse(start)?, parse(end)?);
+            if start > end {
+                return Err(format!("invalid port range: {}", s));
+            }
+            Ok(start..=end)
+        }
+        None => {
+            let port = parse(s)?;
+            Ok(port..=port)
+        }
+    }
+}
+
+// 编译规则时用到的外部数据
+#[derive(Clone)]
+pub struct RuleData {
+    pub geoip: Arc<GeoIp>,
+    pub geosite: Arc<GeoSite>,
+    pub providers: HashMap<String, Arc<RuleProvider>>,
+}
+
+/// 启动时把规则列表编译成域名树 + CIDR 表，匹配结果为最靠前命中规则的序号。
+pub struct RuleEngine {
+    entries: Vec<RuleEntry>,
+    domains: DomainTrie,
+    keywords: Vec<(String, usize)>,
+    // GEOSITE 里的 regexp 条目，按序号递增
+    regexes: Vec<(Regex, usize)>,
+    dst_cidrs: CidrTable,
+    // 不带 no-resolve 的 IP 规则，用于域名解析出的地址
+    resolved_cidrs: CidrTable,
+    first_resolvable: Option<usize>,
+    // (国家代码, no-resolve, 序号)，按序号递增
+    geoip_rules: Vec<(String, bool, usize)>,
+    geoip: Arc<GeoIp>,
+    // (provider, no-resolve, 序号)，按序号递增
+    rule_sets: Vec<(Arc<RuleProvider>, bool, usize)>,
+    src_cidrs: CidrTable,
+    dst_ports: Vec<(RangeInclusive<u16>, usize)>,
+    src_ports: Vec<(RangeInclusive<u16>, usize)>,
+    users: HashMap<String, usize>,
+    final_index: Option<usize>,
+}
+
+impl RuleEngine {
+    pub fn new(lines: &[String], data: &RuleData) -> Result<Self, String> {
+        let engine = Self::compile(lines, data)?;
+        println!(
+            "[Rule] Loaded {} rules ({} domain, {} ip-cidr, {} geoip, {} rule-set, {} src-ip-cidr)",
+            engine.entries.len(),
+            engine.domains.len(),
+            engine.dst_cidrs.len(),
+            engine.geoip_rules.len(),
+            engine.rule_sets.len(),
+            engine.src_cidrs.len()
+        );
+        Ok(engine)
+    }
+
+    fn compile(lines: &[String], data: &RuleData) -> Result<Self, String> {
+        let mut engine = Self {
+            entries: Vec::with_capacity(lines.len()),
+            domains: DomainTrie::new(),
+            keywords: Vec::new(),
+            regexes: Vec::new(),
+            dst_cidrs: CidrTable::new(),
+            resolved_cidrs: CidrTable::new(),
+            first_resolvable: None,
+            geoip_rules: Vec::new(),
+            geoip: data.geoip.clone(),
+            rule_sets: Vec::new(),
+            src_cidrs: CidrTable::new(),
+            dst_ports: Vec::new(),
+            src_ports: Vec::new(),
+            users: HashMap::new(),
+            final_index: None,
+        };
+
+        for line in lines {
+            let entry = RuleEntry::parse(line)?;
+            let index = engine.entries.len();
+            match &entry.rule {
+                Rule::Domain(domain) => engine.domains.insert_exact(domain, index),
+                Rule::DomainSuffix(domain) => engine.domains.insert_suffix(domain, index),
+                Rule::DomainKeyword(keyword) => engine.keywords.push((keyword.clone(), index)),
+                Rule::GeoSite(category) => {
+                    let domains = data.geosite.load(category).map_err(|e| format!("{}: {}", line, e))?;
+                    engine.insert_geosite(category, &domains, index);
+                }
+                Rule::IpCidr(cidr, no_resolve) => {
+                    engine.dst_cidrs.insert(cidr, index);
+                    if !no_resolve {
+                        engine.resolved_cidrs.insert(cidr, index);
+                        engine.first_resolvable.get_or_insert(index);
+                    }
+                }
+                Rule::GeoIp(country, no_resolve) => {
+                    data.geoip.require();
+                    engine.geoip_rules.push((country.clone(), *no_resolve, index));
+                    if !no_resolve {
+                        engine.first_resolvable.get_or_insert(index);
+                    }
+                }
+                Rule::Provider(name, no_resolve) => {
+                    let provider = data
+                        .providers
+                        .get(name)
+                        .ok_or_else(|| format!("unknown rule provider {}: {}", name, line))?;
+                    engine.rule_sets.push((provider.clone(), *no_resolve, index));
+                }
+                Rule::SrcIpCidr(cidr) => engine.src_cidrs.insert(cidr, index),
+                Rule::DstPort(range) => engine.dst_ports.push((range.clone(), index)),
+                Rule::SrcPort(range) => engine.src_ports.push((range.clone(), index)),
+                Rule::InUser(users) => {
+                    for user in users {
+                        engine.users.entry(user.clone()).or_insert(index);
+                    }
+                }
+                Rule::Match => {
+                    engine.final_index.get_or_insert(index);
+                }
+            }
+            engine.entries.push(entry);
+        }
+        Ok(engine)
+    }
+
+    // GEOSITE 分类展开后和 DOMAIN / DOMAIN-SUFFIX / DOMAIN-KEYWORD 共用同一套结构，正则单独逐条匹配
+    fn insert_geosite(&mut self, category: &str, domains: &[SiteDomain], index: usize) {
+        let mut invalid = 0;
+        for domain in domains {
+            match domain {
+                SiteDomain::Suffix(suffix) => self.domains.insert_suffix(suffix, index),
+                SiteDomain::Full(full) => self.domains.insert_exact(full, index),
+                SiteDomain::Keyword(keyword) => self.keywords.push((keyword.clone(), index)),
+                SiteDomain::Regex(pattern) => match Regex::new(pattern) {
+                    Ok(regex) => self.regexes.push((regex, index)),
+                    Err(e) => {
+                        eprintln!("[Rule] GEOSITE,{}: skipping invalid regexp {}: {}", category, pattern, e);
+                        invalid += 1;
+                    }
+                },
+            }
+        }
+        println!("[Rule] GEOSITE,{}: {} domains", category, domains.len() - invalid);
+    }
+
+    pub fn policies(&self) -> impl Iterator<Item = &str> {
+        self.entries.iter().map(|e| e.policy.as_str())
+    }
+
+    pub fn match_rule(&self, meta: &Metadata) -> Option<&RuleEntry> {
+        self.match_index(meta).map(|index| &self.entries[index])
+    }
+
+    // 域名目标还没有 IP，且有需要解析的 IP 规则排在当前命中规则之前
+    pub fn needs_resolve(&self, meta: &Metadata) -> bool {
+        // rule-set 重新加载后可能增减 IP 规则，按当前内容计算
+        let first_rule_set = self
+            .rule_sets
+            .iter()
+            .find(|(provider, no_resolve, _)| !no_resolve && provider.has_ip_rules())
+            .map(|(_, _, index)| *index);
+        let Some(first) = self.first_resolvable.into_iter().chain(first_rule_set).min() else {
+            return false;
+        };
+        meta.dst_ip.is_none()
+            && meta.domain().is_some()
+            && self.match_index(meta).is_none_or(|best| first < best)
+    }
+
+    fn match_index(&self, meta: &Metadata) -> Option<usize> {
+        let mut best = self.final_index;
+
+        if let Some(domain) = meta.domain() {
+            best = earliest(best, self.domains.lookup(domain));
+            let domain = domain.to_ascii_lowercase();
+            for (keyword, index) in &self.keywords {
+                if domain.contains(keyword.as_str()) {
+                    best = earliest(best, Some(*index));
+                    break;
+                }
+            }
+            best = earliest(best, self.match_regex(&domain, best));
+        }
+
+        if let Some(ip) = &meta.dst_ip {
+            let resolved = meta.domain().is_some() && !meta.sniffed;
+            let table = if resolved { &self.resolved_cidrs } else { &self.dst_cidrs };
+            best = earliest(best, table.lookup(ip));
+            best = earliest(best, self.match_geoip(ip, resolved, best));
+        }
+
+        best = earliest(best, self.match_rule_sets(meta, best));
+
+        if let Some(src) = &meta.src_addr {
+            best = earliest(best, self.src_cidrs.lookup(&src.ip()));
+            best = earliest(best, match_port(&self.src_ports, src.port()));
+        }
+
+        best = earliest(best, match_port(&self.dst_ports, meta.dst_port));
+
+        if let Some(user) = &meta.user {
+            best = earliest(best, self.users.get(user).copied());
+        }
+
+        best
+    }
+
+    // 正则开销大，只试排在当前命中规则之前的
+    fn match_regex(&self, domain: &str, best: Option<usize>) -> Option<usize> {
+        self.regexes
+            .iter()
+            .take_while(|(_, index)| best.is_none_or(|b| *index < b))
+            .find(|(regex, _)| regex.is_match(domain))
+            .map(|(_, index)| *index)
+    }
+
+    // 同样只看排在当前命中规则之前的 RULE-SET
+    fn match_rule_sets(&self, meta: &Metadata, best: Option<usize>) -> Option<usize> {
+        let resolved = meta.dst_ip.is_some() && meta.domain().is_some() && !meta.sniffed;
+        let unresolved = resolved.then(|| Metadata {
+            dst_ip: None,
+            ..meta.clone()
+        });
+        self.rule_sets
+            .iter()
+            .take_while(|(_, _, index)| best.is_none_or(|b| *index < b))
+            .find(|(provider, no_resolve, _)| match (&unresolved, no_resolve) {
+                // no-resolve 时不拿为规则解析出的地址去匹配
+                (Some(unresolved), true) => provider.matches(unresolved),
+                _ => provider.matches(meta),
+            })
+            .map(|(_, _, index)| *index)
+    }
+
+    // 只有排在当前命中规则之前的 GEOIP 规则才值得查库
+    fn match_geoip(&self, ip: &IpAddr, resolved: bool, best: Option<usize>) -> Option<usize> {
+        let mut candidates = self
+            .geoip_rules
+            .iter()
+            .filter(|(_, no_resolve, index)| !(resolved && *no_resolve) && best.is_none_or(|b| *index < b))
+            .peekable();
+        candidates.peek()?;
+        let country = self.geoip.country(ip)?;
+        candidates.find(|(code, _, _)| *code == country).map(|(_, _, index)| *index)
+    }
+}
+
+fn match_port(ranges: &[(RangeInclusive<u16>, usize)], port: u16) -> Option<usize> {
+    ranges
+        .iter()
+        .find(|(range, _)| range.contains(&port))
+        .map(|(_, index)| *index)
+}
+
+pub fn earliest(a: Option<usize>, b: Option<usize>) -> Option<usize> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, None) => a,
+        (None, b) => b,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn engine(lines: &[&str]) -> RuleEngine {
+        let lines: Vec<String> = lines.iter().map(|l| l.to_string()).collect();
+        let data = RuleData {
+            geoip: Arc::new(GeoIp::new("")),
+            geosite: Arc::new(GeoSite::new("")),
+            providers: HashMap::new(),
+        };
+        RuleEngine::new(&lines, &data).unwrap()
+    }
+
+    fn policy(engine: &RuleEngine, host: &str, port: u16) -> Option<String> {
+        engine.match_rule(&Metadata::new(host, port, None)).map(|e| e.policy.clone())
+    }
+
+    #[test]
+    fn port_range_rejects_reversed_bounds() {
+        assert_eq!(parse_port_range("80-443").unwrap(), 80..=443);
+        assert_eq!(parse_port_range("8080").unwrap(), 8080..=8080);
+        assert!(parse_port_range("443-80").is_err());
+        assert!(RuleEntry::parse("DST-PORT,443-80,DIRECT").is_err());
+    }
+
+    #[test]
+    fn earliest_rule_wins_across_rule_types() {
+        let engine = engine(&[
+            "DST-PORT,8000-9000,A",
+            "DOMAIN-KEYWORD,tube,B",
+            "DOMAIN-SUFFIX,example.com,C",
+            "IP-CIDR,10.0.0.0/8,D",
+            "DOMAIN,www.example.com,E",
+            "MATCH,F",
+        ]);
+        assert_eq!(policy(&engine, "www.example.com", 8080).as_deref(), Some("A"));
+        assert_eq!(policy(&engine, "youtube.example.com", 443).as_deref(), Some("B"));
+        assert_eq!(policy(&engine, "www.example.com", 443).as_deref(), Some("C"));
+        assert_eq!(policy(&engine, "10.1.2.3", 443).as_deref(), Some("D"));
+        assert_eq!(policy(&engine, "other.org", 443).as_deref(), Some("F"));
+    }
+
+    #[test]
+    fn later_duplicate_does_not_override_earlier_rule() {
+        let engine = engine(&["DOMAIN-SUFFIX,a.com,FIRST", "DOMAIN-SUFFIX,a.com,SECOND", "DOMAIN,x.a.com,THIRD"]);
+        assert_eq!(policy(&engine, "x.a.com", 443).as_deref(), Some("FIRST"));
+        assert_eq!(policy(&engine, "b.com", 443), None);
+    }
+
+    #[test]
+    fn geosite_regex_entries_match() {
+        let dir = std::env::temp_dir().join(format!("clash-rs-rule-geosite-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("test"), "regexp:^ad[0-9]+\\.example\\.com$\nregexp:(unclosed\n").unwrap();
+        let data = RuleData {
+            geoip: Arc::new(GeoIp::new("")),
+            geosite: Arc::new(GeoSite::new(dir.to_str().unwrap())),
+            providers: HashMap::new(),
+        };
+        let lines = ["DOMAIN,ad2.example.com,FIRST", "GEOSITE,test,REJECT", "MATCH,DIRECT"].map(String::from);
+        let engine = RuleEngine::new(&lines, &data).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // 无效的正则跳过，不影响其他条目
+        assert_eq!(policy(&engine, "AD1.example.com", 443).as_deref(), Some("REJECT"));
+        assert_eq!(policy(&engine, "ad2.example.com", 443).as_deref(), Some("FIRST"));
+        assert_eq!(policy(&engine, "adx.example.com", 443).as_deref(), Some("DIRECT"));
+    }
+}
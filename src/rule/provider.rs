@@ -0,0 +1,301 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use super::{Metadata, RuleData, RuleEngine};
+use crate::config::RuleProviderConfig;
+use crate::proxy::proxy_manager::ProxyManager;
+use crate::proxy::vehicle::Vehicle;
+
+// 检查文件是否改动的间隔
+const FILE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Behavior {
+    Domain,
+    IpCidr,
+    Classical,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Yaml,
+    Text,
+}
+
+#[derive(Deserialize)]
+struct Payload {
+    #[serde(default)]
+    payload: Vec<String>,
+}
+
+/// RULE-SET 引用的外部规则集。内容编译成一个独立的 RuleEngine，
+/// 重新加载时整体替换，正在匹配的请求继续使用旧的那份。
+pub struct RuleProvider {
+    name: String,
+    behavior: Behavior,
+    format: Format,
+    vehicle: Vehicle,
+    interval: Option<Duration>,
+    // classical 规则集里的 GEOIP / GEOSITE 使用；不允许再嵌套 RULE-SET
+    data: RuleData,
+    rules: RwLock<Arc<RuleEngine>>,
+    // None 表示启动时用了缓存，需要尽快更新
+    last_update: Mutex<Option<Instant>>,
+}
+
+impl RuleProvider {
+    pub async fn from_config(
+        name: &str,
+        config: &RuleProviderConfig,
+        data: &RuleData,
+        manager: &Arc<ProxyManager>,
+    ) -> Result<Self, String> {
+        let vehicle = Vehicle::new(
+            &config.provider_type,
+            &config.path,
+            config.url.as_deref(),
+            config.proxy.as_deref(),
+            manager,
+        )?;
+        let behavior = match config.behavior.as_str() {
+            "domain" => Behavior::Domain,
+            "ipcidr" => Behavior::IpCidr,
+            "classical" => Behavior::Classical,
+            other => return Err(format!("unsupported behavior: {}", other)),
+        };
+        let format = match config.format.as_deref().unwrap_or("yaml") {
+            "yaml" => Format::Yaml,
+            "text" => Format::Text,
+            other => return Err(format!("unsupported format: {}", other)),
+        };
+        let data = RuleData {
+            providers: HashMap::new(),
+            ..data.clone()
+        };
+
+        let (rules, stale) = vehicle
+            .load(|content| compile(name, behavior, format, &data, content))
+            .await?;
+        println!("[RuleProvider] {}: loaded {} rules", name, rules.entries.len());
+
+        Ok(Self {
+            name: name.to_string(),
+            behavior,
+            format,
+            vehicle,
+            interval: config.interval.filter(|&secs| secs > 0).map(Duration::from_secs),
+            data,
+            rules: RwLock::new(Arc::new(rules)),
+            last_update: Mutex::new((!stale).then(Instant::now)),
+        })
+    }
+
+    // 当前规则里有需要解析的 IP 规则时，域名目标可能需要先解析
+    pub fn has_ip_rules(&self) -> bool {
+        self.rules.read().unwrap().first_resolvable.is_some()
+    }
+
+    pub fn matches(&self, meta: &Metadata) -> bool {
+        let rules = self.rules.read().unwrap().clone();
+        rules.match_index(meta).is_some()
+    }
+
+    pub fn start_refresh(self: &Arc<Self>) {
+        let provider = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(FILE_CHECK_INTERVAL);
+            loop {
+                ticker.tick().await;
+                provider.refresh().await;
+            }
+        });
+    }
+
+    async fn refresh(&self) {
+        let due = match *self.last_update.lock().unwrap() {
+            Some(last) => self.interval.is_some_and(|interval| last.elapsed() >= interval),
+            None => true,
+        };
+        // HTTP 只按 interval 重新下载，文件每次都检查修改时间
+        if !due && !self.vehicle.is_file() {
+            return;
+        }
+        if due {
+            *self.last_update.lock().unwrap() = Some(Instant::now());
+        }
+
+        let result = self
+            .vehicle
+            .update(due, |content| compile(&self.name, self.behavior, self.format, &self.data, content))
+            .await;
+        match result {
+            Ok(None) => {}
+            Ok(Some(rules)) => {
+                println!("[RuleProvider] {}: reloaded {} rules", self.name, rules.entries.len());
+                *self.rules.write().unwrap() = Arc::new(rules);
+            }
+            Err(e) => eprintln!("[RuleProvider] {}: reload failed, keeping previous rules: {}", self.name, e),
+        }
+    }
+}
+
+fn compile(name: &str, behavior: Behavior, format: Format, data: &RuleData, content: &str) -> Result<RuleEngine, String> {
+    let items: Vec<String> = match format {
+        Format::Yaml => serde_yaml::from_str::<Payload>(content)
+            .map_err(|e| format!("invalid rule provider {}: {}", name, e))?
+            .payload,
+        Format::Text => content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_string)
+            .collect(),
+    };
+
+    // 统一转换成带占位策略的规则行，再按普通规则编译
+    let lines: Vec<String> = items
+        .iter()
+        .map(|item| {
+            let item = item.trim();
+            match behavior {
+                Behavior::Domain => match item
+                    .strip_prefix("+.")
+                    .or_else(|| item.strip_prefix("*."))
+                    .or_else(|| item.strip_prefix('.'))
+                {
+                    Some(suffix) => format!("DOMAIN-SUFFIX,{},{}", suffix, name),
+                    None => format!("DOMAIN,{},{}", item, name),
+                },
+                Behavior::IpCidr => format!("IP-CIDR,{},{}", item, name),
+                // TYPE,PAYLOAD[,no-resolve] -> TYPE,PAYLOAD,策略[,no-resolve]
+                Behavior::Classical => {
+                    let mut parts: Vec<&str> = item.split(',').map(str::trim).collect();
+                    let at = parts.len().min(2);
+                    parts.insert(at, name);
+                    parts.join(",")
+                }
+            }
+        })
+        .collect();
+    RuleEngine::compile(&lines, data).map_err(|e| format!("rule provider {}: {}", name, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+    use std::time::SystemTime;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::dns::Resolver;
+    use crate::proxy::runtime::{Mode, ProxyRuntime};
+    use crate::rule::geoip::GeoIp;
+    use crate::rule::geosite::GeoSite;
+
+    fn data() -> RuleData {
+        RuleData {
+            geoip: Arc::new(GeoIp::new("")),
+            geosite: Arc::new(GeoSite::new("")),
+            providers: HashMap::new(),
+        }
+    }
+
+    fn matches(rules: &RuleEngine, host: &str) -> bool {
+        rules.match_index(&Metadata::new(host, 443, None)).is_some()
+    }
+
+    // 域名目标带着为规则解析出的地址
+    fn matches_resolved(rules: &RuleEngine, host: &str, ip: &str) -> bool {
+        let mut meta = Metadata::new(host, 443, None);
+        meta.dst_ip = Some(ip.parse().unwrap());
+        rules.match_index(&meta).is_some()
+    }
+
+    #[test]
+    fn domain_payload() {
+        let content = "payload:\n  - 'a.com'\n  - '+.b.com'\n  - '.c.com'\n";
+        let rules = compile("test", Behavior::Domain, Format::Yaml, &data(), content).unwrap();
+        assert!(matches(&rules, "a.com"));
+        assert!(!matches(&rules, "x.a.com"));
+        assert!(matches(&rules, "b.com"));
+        assert!(matches(&rules, "x.b.com"));
+        assert!(matches(&rules, "y.c.com"));
+        assert!(!matches(&rules, "d.com"));
+    }
+
+    #[test]
+    fn ipcidr_text_payload() {
+        let content = "# comment\n10.0.0.0/8\n\n2001:db8::/32\n";
+        let rules = compile("test", Behavior::IpCidr, Format::Text, &data(), content).unwrap();
+        assert_eq!(rules.entries.len(), 2);
+        assert!(matches(&rules, "10.1.2.3"));
+        assert!(matches(&rules, "2001:db8::1"));
+        assert!(!matches(&rules, "192.168.1.1"));
+        assert!(compile("test", Behavior::IpCidr, Format::Text, &data(), "10.0.0.0/33").is_err());
+    }
+
+    #[test]
+    fn classical_payload_with_no_resolve() {
+        let content = "payload:\n  - DOMAIN-KEYWORD,tracker\n  - IP-CIDR,10.0.0.0/8,no-resolve\n  - IP-CIDR,172.16.0.0/12\n  - DST-PORT,8443\n";
+        let rules = compile("test", Behavior::Classical, Format::Yaml, &data(), content).unwrap();
+        assert_eq!(rules.entries[1].raw, "IP-CIDR,10.0.0.0/8,test,no-resolve");
+        assert!(matches(&rules, "ad.tracker.net"));
+        assert!(matches(&rules, "10.1.2.3"));
+        // no-resolve 的规则不匹配域名解析出的地址
+        assert!(!matches_resolved(&rules, "example.com", "10.1.2.3"));
+        assert!(matches_resolved(&rules, "example.com", "172.16.0.1"));
+        assert!(rules.match_index(&Metadata::new("example.com", 8443, None)).is_some());
+        assert!(!matches(&rules, "example.com"));
+        assert!(compile("test", Behavior::Classical, Format::Yaml, &data(), "payload:\n  - RULE-SET,other\n").is_err());
+    }
+
+    fn manager() -> Arc<ProxyManager> {
+        let config: Config = serde_yaml::from_str("proxies: []\nproxy-groups: []\nrules: []\n").unwrap();
+        let resolver = Arc::new(Resolver::new(None, &serde_yaml::Mapping::new()).unwrap());
+        Arc::new(ProxyManager::new(&config, Arc::new(ProxyRuntime::new(Mode::Rule)), resolver))
+    }
+
+    // 显式推进修改时间，避免文件系统时间精度让改动被忽略
+    fn write(path: &PathBuf, content: &str, secs: u64) {
+        fs::write(path, content).unwrap();
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000 + secs);
+        fs::File::options().write(true).open(path).unwrap().set_modified(time).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reload_swaps_rules() {
+        let path = std::env::temp_dir().join(format!("clash-rs-rule-provider-{}.txt", std::process::id()));
+        write(&path, "payload:\n  - a.com\n", 0);
+        let config = RuleProviderConfig {
+            provider_type: "file".to_string(),
+            behavior: "domain".to_string(),
+            format: None,
+            path: path.to_str().unwrap().to_string(),
+            url: None,
+            proxy: None,
+            interval: None,
+        };
+        let provider = RuleProvider::from_config("test", &config, &data(), &manager()).await.unwrap();
+        let a = Metadata::new("a.com", 443, None);
+        let b = Metadata::new("b.com", 443, None);
+        assert!(provider.matches(&a));
+        let before = provider.rules.read().unwrap().clone();
+
+        write(&path, "payload:\n  - b.com\n", 1);
+        provider.refresh().await;
+        assert!(!provider.matches(&a));
+        assert!(provider.matches(&b));
+        // 替换前取出的那份不受影响
+        assert!(before.match_index(&a).is_some());
+
+        // 重新加载失败时保留当前规则
+        write(&path, "payload: [", 2);
+        provider.refresh().await;
+        assert!(provider.matches(&b));
+        fs::remove_file(&path).unwrap();
+    }
+}
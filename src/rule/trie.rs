@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+
+use super::earliest;
+
+#[derive(Default)]
+struct Node {
+    children: HashMap<String, Node>,
+    exact: Option<usize>,
+    suffix: Option<usize>,
+    // 只匹配子域名，不含自身
+    subdomain: Option<usize>,
+}
+
+/// 以反转后的域名标签为路径的前缀树，同时承载 DOMAIN、DOMAIN-SUFFIX 和只匹配子域名的 "*." 通配。
+#[derive(Default)]
+pub struct DomainTrie {
+    root: Node,
+    len: usize,
+}
+
+impl DomainTrie {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert_exact(&mut self, domain: &str, value: usize) {
+        let node = self.node_mut(domain);
+        node.exact = earliest(node.exact, Some(value));
+        self.len += 1;
+    }
+
+    pub fn insert_suffix(&mut self, domain: &str, value: usize) {
+        let node = self.node_mut(domain);
+        node.suffix = earliest(node.suffix, Some(value));
+        self.len += 1;
+    }
+
+    pub fn insert_subdomain(&mut self, domain: &str, value: usize) {
+        let node = self.node_mut(domain);
+        node.subdomain = earliest(node.subdomain, Some(value));
+        self.len += 1;
+    }
+
+    // 配置里的域名写法："+.a.com" 匹配自身及子域名，"*.a.com" 只匹配子域名，其余按完整域名匹配
+    pub fn insert_pattern(&mut self, pattern: &str, value: usize) {
+        if let Some(suffix) = pattern.strip_prefix("+.") {
+            self.insert_suffix(suffix, value);
+        } else if let Some(parent) = pattern.strip_prefix("*.") {
+            self.insert_subdomain(parent, value);
+        } else {
+            self.insert_exact(pattern, value);
+        }
+    }
+
+    pub fn lookup(&self, host: &str) -> Option<usize> {
+        let host = normalize(host);
+        let mut node = &self.root;
+        let mut best = None;
+        for label in host.rsplit('.') {
+            // 还有更深一级的标签，当前节点的子域名通配生效
+            best = earliest(best, node.subdomain);
+            match node.children.get(label) {
+                Some(child) => node = child,
+                None => return best,
+            }
+            best = earliest(best, node.suffix);
+        }
+        earliest(best, node.exact)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    fn node_mut(&mut self, domain: &str) -> &mut Node {
+        let domain = normalize(domain);
+        let mut node = &mut self.root;
+        for label in domain.rsplit('.') {
+            node = node.children.entry(label.to_string()).or_default();
+        }
+        node
+    }
+}
+
+pub fn normalize(domain: &str) -> String {
+    domain.trim().trim_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn star_pattern_matches_subdomains_only() {
+        let mut trie = DomainTrie::new();
+        trie.insert_pattern("*.a.com", 0);
+        assert_eq!(trie.lookup("x.a.com"), Some(0));
+        assert_eq!(trie.lookup("y.x.a.com"), Some(0));
+        assert_eq!(trie.lookup("a.com"), None);
+        assert_eq!(trie.lookup("xa.com"), None);
+    }
+
+    #[test]
+    fn plus_pattern_matches_domain_and_subdomains() {
+        let mut trie = DomainTrie::new();
+        trie.insert_pattern("+.a.com", 0);
+        trie.insert_pattern("b.com", 1);
+        assert_eq!(trie.lookup("a.com"), Some(0));
+        assert_eq!(trie.lookup("y.x.a.com"), Some(0));
+        assert_eq!(trie.lookup("b.com"), Some(1));
+        assert_eq!(trie.lookup("x.b.com"), None);
+    }
+
+    #[test]
+    fn exact_matches_only_itself() {
+        let mut trie = DomainTrie::new();
+        trie.insert_exact("a.com", 0);
+        assert_eq!(trie.lookup("a.com"), Some(0));
+        assert_eq!(trie.lookup("x.a.com"), None);
+        assert_eq!(trie.lookup("com"), None);
+    }
+
+    #[test]
+    fn lookup_is_case_and_dot_insensitive() {
+        let mut trie = DomainTrie::new();
+        trie.insert_suffix("Example.COM.", 0);
+        assert_eq!(trie.lookup("WWW.example.com"), Some(0));
+        assert_eq!(trie.lookup("www.example.com."), Some(0));
+    }
+
+    #[test]
+    fn earliest_index_wins_regardless_of_depth() {
+        let mut trie = DomainTrie::new();
+        trie.insert_exact("x.a.com", 2);
+        trie.insert_suffix("a.com", 1);
+        trie.insert_suffix("x.a.com", 3);
+        trie.insert_subdomain("a.com", 0);
+        assert_eq!(trie.lookup("x.a.com"), Some(0));
+        assert_eq!(trie.lookup("a.com"), Some(1));
+        // 同一节点重复插入保留较小序号
+        trie.insert_exact("b.com", 5);
+        trie.insert_exact("b.com", 4);
+        assert_eq!(trie.lookup("b.com"), Some(4));
+    }
+}